@@ -0,0 +1,707 @@
+//! 饥荒联机版着色器的语言服务器, 通过标准输入输出与编辑器通信.
+//!
+//! 支持 .vs/.ps 文件和被 `#include` 的共享代码, 提供诊断, 悬停显示声明, 跳转到函数, varying 和 uniform 的定义,
+//! 引擎 uniform 的补全以及文档大纲; 打开 .ksh 文件时检查其中的两个着色器并列出它们的声明.
+
+use dst_ksh_analyze_lib::check::{check_shader, check_syntax, to_diagnostic};
+use dst_ksh_analyze_lib::core::{analyze_ksh, get_ps_vs_from_dir, BuildOptions};
+use dst_ksh_analyze_lib::diagnostic::{self, Severity};
+use dst_ksh_analyze_lib::engine_uniforms::{find_engine_uniform, ENGINE_UNIFORMS};
+use dst_ksh_analyze_lib::glsl_parser::parse_glsl;
+use dst_ksh_analyze_lib::preprocessor::{resolve_includes, PreprocessOptions};
+use dst_ksh_analyze_lib::symbols::{collect_symbols, Symbol, SymbolKind};
+use dst_ksh_analyze_lib::types::ShaderStage;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+    Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, Request as _,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, Documentation, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, InitializeParams, Location, MarkupContent,
+    MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 编辑器通过 `initializationOptions` 传入的设置, 如
+/// `{ "includePaths": ["shader_lib"], "defines": ["BLOOM"], "es100": true, "validate": false }`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Settings {
+    include_paths: Vec<PathBuf>,
+    defines: Vec<String>,
+    es100: bool,
+    /// 是否用 naga 做语义验证, 默认开启
+    validate: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            include_paths: vec![],
+            defines: vec![],
+            es100: false,
+            validate: true,
+        }
+    }
+}
+
+// 文档的种类, 按扩展名区分
+enum DocumentKind {
+    Shader(ShaderStage),
+    Ksh,
+    /// 被 #include 的共享代码等
+    Other,
+}
+
+fn document_kind(path: &Path) -> DocumentKind {
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("vs") => DocumentKind::Shader(ShaderStage::Vertex),
+        Some("ps") => DocumentKind::Shader(ShaderStage::Fragment),
+        Some("ksh") => DocumentKind::Ksh,
+        _ => DocumentKind::Other,
+    }
+}
+
+// 带有位置的全局声明, 可能来自被 #include 的文件
+struct LocatedSymbol {
+    symbol: Symbol,
+    path: PathBuf,
+    /// 原始文件中的行号, 从 0 开始
+    line: u32,
+}
+
+struct Server {
+    connection: Connection,
+    options: BuildOptions,
+    /// 已打开文档的内容
+    documents: HashMap<Url, String>,
+}
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+        .target(env_logger::Target::Stderr)
+        .init();
+
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    };
+    let params: InitializeParams =
+        serde_json::from_value(connection.initialize(serde_json::to_value(capabilities)?)?)?;
+    let settings: Settings = params
+        .initialization_options
+        .map(serde_json::from_value)
+        .transpose()
+        .unwrap_or_else(|e| {
+            log::warn!("initializationOptions 无效: {}", e);
+            None
+        })
+        .unwrap_or_default();
+    let options = BuildOptions {
+        preprocess: PreprocessOptions {
+            defines: settings
+                .defines
+                .iter()
+                .map(|d| PreprocessOptions::parse_define(d))
+                .collect::<Result<_, _>>()?,
+            include_paths: settings.include_paths,
+        },
+        es100: settings.es100,
+        skip_validation: !settings.validate,
+        ..Default::default()
+    };
+
+    let mut server = Server {
+        connection,
+        options,
+        documents: HashMap::new(),
+    };
+    server.run()?;
+    io_threads.join()?;
+    Ok(())
+}
+
+impl Server {
+    fn run(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    self.handle_request(request)?;
+                }
+                Message::Notification(notification) => {
+                    let method = notification.method.clone();
+                    if let Err(e) = self.handle_notification(notification) {
+                        log::warn!("处理 {} 失败: {}", method, e);
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, request: Request) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let result = match request.method.as_str() {
+            HoverRequest::METHOD => params(request.params).map(|p: HoverParams| {
+                let position = p.text_document_position_params;
+                json!(self.hover(&position.text_document.uri, position.position))
+            }),
+            GotoDefinition::METHOD => params(request.params).map(|p: GotoDefinitionParams| {
+                let position = p.text_document_position_params;
+                json!(self.definition(&position.text_document.uri, position.position))
+            }),
+            Completion::METHOD => params(request.params).map(|p: CompletionParams| {
+                json!(self.completion(&p.text_document_position.text_document.uri))
+            }),
+            DocumentSymbolRequest::METHOD => params(request.params)
+                .map(|p: DocumentSymbolParams| json!(self.document_symbols(&p.text_document.uri))),
+            method => {
+                let response = Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("不支持的请求: {}", method),
+                );
+                self.connection.sender.send(response.into())?;
+                return Ok(());
+            }
+        };
+        let response = match result {
+            Ok(result) => Response::new_ok(request.id, result),
+            Err(e) => Response::new_err(request.id, ErrorCode::InvalidParams as i32, e.to_string()),
+        };
+        self.connection.sender.send(response.into())?;
+        Ok(())
+    }
+
+    fn handle_notification(
+        &mut self,
+        notification: Notification,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = params(notification.params)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                self.publish_diagnostics(&uri)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams = params(notification.params)?;
+                let uri = params.text_document.uri;
+                // 只支持全量同步, 最后一次修改就是完整内容
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                self.publish_diagnostics(&uri)?;
+            }
+            DidSaveTextDocument::METHOD => {
+                let params: DidSaveTextDocumentParams = params(notification.params)?;
+                self.publish_diagnostics(&params.text_document.uri)?;
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = params(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                let params = PublishDiagnosticsParams::new(params.text_document.uri, vec![], None);
+                self.notify::<PublishDiagnostics>(params)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn notify<N: lsp_types::notification::Notification>(
+        &self,
+        params: N::Params,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let notification = Notification::new(N::METHOD.to_string(), params);
+        self.connection.sender.send(notification.into())?;
+        Ok(())
+    }
+
+    // 文档所在目录作为第一个 #include 搜索路径的选项
+    fn options_for(&self, path: &Path) -> BuildOptions {
+        let mut options = self.options.clone();
+        if let Some(parent) = path.parent() {
+            options
+                .preprocess
+                .include_paths
+                .insert(0, parent.to_path_buf());
+        }
+        options
+    }
+
+    // 文档的内容, 没有打开时从磁盘读取
+    fn text(&self, path: &Path) -> Option<String> {
+        let uri = Url::from_file_path(path).ok()?;
+        match self.documents.get(&uri) {
+            Some(text) => Some(text.clone()),
+            None => fs::read_to_string(path).ok(),
+        }
+    }
+
+    // 与着色器配对的另一个阶段: 同名的 .vs/.ps 文件, 或目录中唯一的另一个着色器
+    fn other_stage(&self, path: &Path, stage: ShaderStage) -> Option<(String, String)> {
+        let extension = match stage {
+            ShaderStage::Vertex => "ps",
+            ShaderStage::Fragment => "vs",
+        };
+        let sibling = path.with_extension(extension);
+        if let Some(text) = self.text(&sibling) {
+            return Some((sibling.display().to_string(), text));
+        }
+        let (vs, ps) = get_ps_vs_from_dir(path.parent()?).ok()?;
+        let other = match stage {
+            ShaderStage::Vertex => ps,
+            ShaderStage::Fragment => vs,
+        };
+        let text = self.text(&other)?;
+        Some((other.display().to_string(), text))
+    }
+
+    fn publish_diagnostics(&self, uri: &Url) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let Ok(path) = uri.to_file_path() else {
+            return Ok(());
+        };
+        let name = path.display().to_string();
+        let options = self.options_for(&path);
+        let diagnostics = match document_kind(&path) {
+            DocumentKind::Shader(stage) => {
+                let text = self.documents.get(uri).cloned().unwrap_or_default();
+                let other = self.other_stage(&path, stage);
+                let other = other.as_ref().map(|(n, c)| (n.as_str(), c.as_str()));
+                check_shader(stage, &name, &text, other, &options)
+                    .into_iter()
+                    .map(|d| to_lsp_diagnostic(d, &name))
+                    .collect()
+            }
+            DocumentKind::Other => {
+                let text = self.documents.get(uri).cloned().unwrap_or_default();
+                check_syntax(&name, &text, &options.preprocess)
+                    .into_iter()
+                    .map(|d| to_lsp_diagnostic(d, &name))
+                    .collect()
+            }
+            // KSH 是二进制文件, 诊断都放在第一行, 信息中注明所在的着色器和行号
+            DocumentKind::Ksh => match read_ksh(&path) {
+                Ok((vs_name, vs_content, ps_name, ps_content)) => {
+                    let vs = (vs_name.as_str(), vs_content.as_str());
+                    let ps = (ps_name.as_str(), ps_content.as_str());
+                    check_shader(ShaderStage::Vertex, vs.0, vs.1, Some(ps), &options)
+                        .into_iter()
+                        .chain(check_shader(
+                            ShaderStage::Fragment,
+                            ps.0,
+                            ps.1,
+                            Some(vs),
+                            &options,
+                        ))
+                        .map(|d| to_lsp_diagnostic(d, ""))
+                        .collect()
+                }
+                Err(e) => vec![to_lsp_diagnostic(to_diagnostic(e), "")],
+            },
+        };
+        let params = PublishDiagnosticsParams::new(uri.clone(), diagnostics, None);
+        self.notify::<PublishDiagnostics>(params)
+    }
+
+    // 文档及其 #include 的文件中的全局声明
+    fn symbols(&self, path: &Path, text: &str) -> Vec<LocatedSymbol> {
+        let options = self.options_for(path).preprocess;
+        let Ok(source) = resolve_includes(text, path, &options) else {
+            return vec![];
+        };
+        let Ok(shader) = parse_glsl(&source.code, &options) else {
+            return vec![];
+        };
+        collect_symbols(&shader)
+            .into_iter()
+            .filter_map(|symbol| {
+                let (file, line) = source.locate(symbol.line)?;
+                Some(LocatedSymbol {
+                    path: file.to_path_buf(),
+                    line: line.saturating_sub(1) as u32,
+                    symbol,
+                })
+            })
+            .collect()
+    }
+
+    // 光标处的标识符和文档中的全局声明
+    fn lookup(&self, uri: &Url, position: Position) -> Option<(String, Vec<LocatedSymbol>)> {
+        let path = uri.to_file_path().ok()?;
+        let text = self.documents.get(uri)?;
+        let line = text.lines().nth(position.line as usize)?;
+        let word = word_at(line, position.character)?;
+        Some((word, self.symbols(&path, text)))
+    }
+
+    // 声明中名称所在的范围, 找不到时为整行的开头
+    fn symbol_range(&self, symbol: &LocatedSymbol) -> Range {
+        let line = self
+            .text(&symbol.path)
+            .and_then(|text| text.lines().nth(symbol.line as usize).map(str::to_string))
+            .unwrap_or_default();
+        let (start, end) = find_word(&line, &symbol.symbol.name).unwrap_or((0, 0));
+        Range::new(
+            Position::new(symbol.line, start),
+            Position::new(symbol.line, end),
+        )
+    }
+
+    fn hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        let (word, symbols) = self.lookup(uri, position)?;
+        let mut sections = vec![];
+        for located in symbols.iter().filter(|s| s.symbol.name == word) {
+            let mut section = format!("```glsl\n{}\n```", located.symbol.declaration);
+            if uri.to_file_path().ok().as_deref() != Some(located.path.as_path()) {
+                section.push_str(&format!(
+                    "\n\n{}:{}",
+                    located.path.display(),
+                    located.line + 1
+                ));
+            }
+            sections.push(section);
+        }
+        if let Some(uniform) = find_engine_uniform(&word) {
+            if sections.is_empty() {
+                sections.push(format!("```glsl\n{}\n```", uniform.declaration()));
+            }
+            sections.push(format!("引擎 uniform: {}", uniform.description));
+        }
+        if sections.is_empty() {
+            return None;
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: sections.join("\n\n---\n\n"),
+            }),
+            range: None,
+        })
+    }
+
+    fn definition(&self, uri: &Url, position: Position) -> Option<GotoDefinitionResponse> {
+        let (word, symbols) = self.lookup(uri, position)?;
+        let locations: Vec<Location> = symbols
+            .iter()
+            .filter(|s| s.symbol.name == word)
+            .filter_map(|s| {
+                let uri = Url::from_file_path(
+                    fs::canonicalize(&s.path).unwrap_or_else(|_| s.path.clone()),
+                )
+                .ok()?;
+                Some(Location::new(uri, self.symbol_range(s)))
+            })
+            .collect();
+        (!locations.is_empty()).then_some(GotoDefinitionResponse::Array(locations))
+    }
+
+    // 引擎 uniform 和文档中的全局声明
+    fn completion(&self, uri: &Url) -> CompletionResponse {
+        let mut items: Vec<CompletionItem> = ENGINE_UNIFORMS
+            .iter()
+            .map(|uniform| CompletionItem {
+                label: uniform.name.to_string(),
+                kind: Some(CompletionItemKind::VARIABLE),
+                detail: Some(uniform.declaration()),
+                documentation: Some(Documentation::String(uniform.description.to_string())),
+                ..Default::default()
+            })
+            .collect();
+        let symbols = match (uri.to_file_path(), self.documents.get(uri)) {
+            (Ok(path), Some(text)) => self.symbols(&path, text),
+            _ => vec![],
+        };
+        for located in symbols {
+            if items.iter().any(|item| item.label == located.symbol.name) {
+                continue;
+            }
+            items.push(CompletionItem {
+                label: located.symbol.name.clone(),
+                kind: Some(completion_kind(located.symbol.kind)),
+                detail: Some(located.symbol.declaration.clone()),
+                ..Default::default()
+            });
+        }
+        CompletionResponse::Array(items)
+    }
+
+    fn document_symbols(&self, uri: &Url) -> DocumentSymbolResponse {
+        let Ok(path) = uri.to_file_path() else {
+            return DocumentSymbolResponse::Nested(vec![]);
+        };
+        if let DocumentKind::Ksh = document_kind(&path) {
+            return DocumentSymbolResponse::Nested(self.ksh_symbols(&path));
+        }
+        let Some(text) = self.documents.get(uri) else {
+            return DocumentSymbolResponse::Nested(vec![]);
+        };
+        let symbols = self
+            .symbols(&path, text)
+            .into_iter()
+            .filter(|s| s.path == path)
+            .map(|s| {
+                let range = self.symbol_range(&s);
+                document_symbol(&s.symbol, range, None)
+            })
+            .collect();
+        DocumentSymbolResponse::Nested(symbols)
+    }
+
+    // KSH 中两个着色器的声明, 按着色器分组, 位置都在第一行
+    fn ksh_symbols(&self, path: &Path) -> Vec<DocumentSymbol> {
+        let Ok((vs_name, vs_content, ps_name, ps_content)) = read_ksh(path) else {
+            return vec![];
+        };
+        let range = Range::default();
+        [(vs_name, vs_content), (ps_name, ps_content)]
+            .into_iter()
+            .map(|(name, content)| {
+                let children = parse_glsl(&content, &self.options.preprocess)
+                    .map(|shader| {
+                        collect_symbols(&shader)
+                            .iter()
+                            .map(|s| document_symbol(s, range, None))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                #[allow(deprecated)]
+                let symbol = DocumentSymbol {
+                    name,
+                    detail: None,
+                    kind: lsp_types::SymbolKind::FILE,
+                    tags: None,
+                    deprecated: None,
+                    range,
+                    selection_range: range,
+                    children: Some(children),
+                };
+                symbol
+            })
+            .collect()
+    }
+}
+
+fn params<P: DeserializeOwned>(value: serde_json::Value) -> Result<P, serde_json::Error> {
+    serde_json::from_value(value)
+}
+
+// 读取 KSH 文件中的两个着色器
+fn read_ksh(path: &Path) -> Result<(String, String, String, String), Box<dyn Error>> {
+    analyze_ksh(&fs::read(path)?).map_err(|e| format!("无法解析 KSH 文件: {}", e).into())
+}
+
+// 诊断不在 `file` 中时放在第一行, 信息中注明所在文件
+fn to_lsp_diagnostic(diagnostic: diagnostic::Diagnostic, file: &str) -> lsp_types::Diagnostic {
+    let in_file = diagnostic.file.as_deref().is_none_or(|f| f == file);
+    let (range, message) = if in_file && !file.is_empty() {
+        let line = diagnostic.line.saturating_sub(1) as u32;
+        let start = diagnostic.column.map_or(0, |c| c.saturating_sub(1)) as u32;
+        let end = if diagnostic.column.is_some() {
+            start + 1
+        } else {
+            u32::MAX
+        };
+        (
+            Range::new(Position::new(line, start), Position::new(line, end)),
+            diagnostic.message,
+        )
+    } else {
+        let location = match &diagnostic.file {
+            Some(file) => format!("{}:{}", file, diagnostic.line),
+            None => format!("第 {} 行", diagnostic.line),
+        };
+        (
+            Range::default(),
+            format!("{}: {}", location, diagnostic.message),
+        )
+    };
+    lsp_types::Diagnostic {
+        range,
+        severity: Some(match diagnostic.severity {
+            Severity::Error => DiagnosticSeverity::ERROR,
+            Severity::Warning => DiagnosticSeverity::WARNING,
+        }),
+        source: Some("ksh".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+fn completion_kind(kind: SymbolKind) -> CompletionItemKind {
+    match kind {
+        SymbolKind::Function => CompletionItemKind::FUNCTION,
+        SymbolKind::Constant => CompletionItemKind::CONSTANT,
+        SymbolKind::Struct => CompletionItemKind::STRUCT,
+        _ => CompletionItemKind::VARIABLE,
+    }
+}
+
+#[allow(deprecated)]
+fn document_symbol(
+    symbol: &Symbol,
+    range: Range,
+    children: Option<Vec<DocumentSymbol>>,
+) -> DocumentSymbol {
+    let kind = match symbol.kind {
+        SymbolKind::Function => lsp_types::SymbolKind::FUNCTION,
+        SymbolKind::Constant => lsp_types::SymbolKind::CONSTANT,
+        SymbolKind::Struct => lsp_types::SymbolKind::STRUCT,
+        SymbolKind::Uniform | SymbolKind::Attribute | SymbolKind::Varying => {
+            lsp_types::SymbolKind::FIELD
+        }
+        SymbolKind::Variable => lsp_types::SymbolKind::VARIABLE,
+    };
+    DocumentSymbol {
+        name: symbol.name.clone(),
+        detail: (!symbol.declaration.is_empty()).then(|| symbol.declaration.clone()),
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range: range,
+        children,
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// 行中 UTF-16 列 `character` 处的标识符
+fn word_at(line: &str, character: u32) -> Option<String> {
+    let mut offset = 0;
+    let mut index = line.len();
+    for (i, c) in line.char_indices() {
+        if offset >= character as usize {
+            index = i;
+            break;
+        }
+        offset += c.len_utf16();
+    }
+    // 前面可能是多字节字符, 按字符而不是字节找标识符的开头
+    let start = line[..index]
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !is_identifier_char(*c))
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let end = line[index..]
+        .find(|c: char| !is_identifier_char(c))
+        .map_or(line.len(), |i| index + i);
+    let word = &line[start..end];
+    (!word.is_empty() && !word.starts_with(|c: char| c.is_ascii_digit())).then(|| word.to_string())
+}
+
+/// 行中第一个完整的标识符 `name` 的 UTF-16 范围
+fn find_word(line: &str, name: &str) -> Option<(u32, u32)> {
+    let utf16 = |s: &str| s.encode_utf16().count() as u32;
+    line.match_indices(name)
+        .find(|(i, _)| {
+            let before = line[..*i].chars().next_back();
+            let after = line[i + name.len()..].chars().next();
+            !before.is_some_and(is_identifier_char) && !after.is_some_and(is_identifier_char)
+        })
+        .map(|(i, _)| (utf16(&line[..i]), utf16(&line[..i + name.len()])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_at() {
+        assert_eq!(word_at("vec4 TINT;", 6).as_deref(), Some("TINT"));
+        assert_eq!(word_at("vec4 TINT;", 9).as_deref(), Some("TINT"));
+        assert_eq!(word_at("x = 1.0;", 4), None);
+        // 列是 UTF-16 偏移, 标识符紧跟在中文之后
+        assert_eq!(word_at("// 颜色TINT", 6).as_deref(), Some("TINT"));
+        assert_eq!(word_at("// 颜色TINT", 5).as_deref(), Some("TINT"));
+        assert_eq!(word_at("// 颜色TINT", 4), None);
+    }
+
+    #[test]
+    fn test_hover_after_cjk() {
+        let (connection, _client) = Connection::memory();
+        let mut server = Server {
+            connection,
+            options: BuildOptions::default(),
+            documents: HashMap::new(),
+        };
+        let uri = Url::from_file_path(std::env::temp_dir().join("hover.ps")).unwrap();
+        let text = "uniform vec4 TINT;\n\
+            void main() { gl_FragColor = TINT; } // 颜色TINT\n";
+        server.documents.insert(uri.clone(), text.to_string());
+
+        let column = "void main() { gl_FragColor = TINT; } // 颜色T"
+            .encode_utf16()
+            .count() as u32;
+        let hover = server.hover(&uri, Position::new(1, column)).unwrap();
+        let HoverContents::Markup(markup) = hover.contents else {
+            panic!("悬停内容应为 Markdown");
+        };
+        assert!(
+            markup.value.contains("uniform vec4 TINT;"),
+            "{}",
+            markup.value
+        );
+    }
+
+    #[test]
+    fn test_corrupt_ksh_diagnostic() {
+        use dst_ksh_analyze_lib::core::build_ksh;
+
+        let (connection, client) = Connection::memory();
+        let server = Server {
+            connection,
+            options: BuildOptions::default(),
+            documents: HashMap::new(),
+        };
+        let vs = "void main() { gl_Position = vec4(0.0); }\n";
+        let ps = "uniform vec4 TINT;\nvoid main() { gl_FragColor = TINT; }\n";
+        let ksh = build_ksh("test", "test.vs", vs, "test.ps", ps, &server.options).unwrap();
+        let dir = std::env::temp_dir().join("ksh_lsp_test_corrupt_ksh");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("corrupt.ksh");
+        fs::write(&path, &ksh[..ksh.len() / 2]).unwrap();
+
+        // 损坏的 KSH 不会让服务器退出, 而是发布一条诊断
+        server
+            .publish_diagnostics(&Url::from_file_path(&path).unwrap())
+            .unwrap();
+        assert!(server.ksh_symbols(&path).is_empty());
+        let Message::Notification(notification) = client.receiver.recv().unwrap() else {
+            panic!("应收到诊断通知");
+        };
+        let params: PublishDiagnosticsParams = serde_json::from_value(notification.params).unwrap();
+        assert_eq!(params.diagnostics.len(), 1);
+        assert!(
+            params.diagnostics[0].message.contains("无法解析 KSH 文件"),
+            "{}",
+            params.diagnostics[0].message
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! 编辑时检查单个着色器, 收集所有诊断而不是在第一个错误处停止, 供语言服务器和图形界面使用
+
+use crate::core::{check_uniform_types, merge_uniforms, BuildOptions};
+use crate::diagnostic::Diagnostic;
+use crate::engine_uniforms::check_engine_uniforms;
+use crate::es100::check_es100;
+use crate::glsl_parser::{collect_uniforms, collect_unused_uniforms, parse_glsl, ParsedShader};
+use crate::linker::link_varyings;
+#[cfg(feature = "naga")]
+use crate::naga_glsl::{validate_with_naga, InterfaceLayout};
+use crate::preprocessor::{resolve_includes, IncludedSource, PreprocessOptions};
+use crate::types::ShaderStage;
+use std::path::Path;
+
+/// 把错误转换为诊断, 不是诊断的错误指向第一行
+pub fn to_diagnostic(error: Box<dyn std::error::Error>) -> Diagnostic {
+    match error.downcast::<Diagnostic>() {
+        Ok(diagnostic) => *diagnostic,
+        Err(error) => Diagnostic::error(1, error.to_string()),
+    }
+}
+
+// 展开 #include 并解析, 错误已映射回原始文件
+fn parse_source(
+    name: &str,
+    content: &str,
+    options: &PreprocessOptions,
+) -> Result<(IncludedSource, ParsedShader), Diagnostic> {
+    let source = resolve_includes(content, Path::new(name), options).map_err(to_diagnostic)?;
+    let shader =
+        parse_glsl(&source.code, options).map_err(|e| to_diagnostic(source.map_error(e)))?;
+    Ok((source, shader))
+}
+
+/// 只检查语法, 用于被 `#include` 的共享代码等无法确定阶段的文件
+pub fn check_syntax(name: &str, content: &str, options: &PreprocessOptions) -> Vec<Diagnostic> {
+    parse_source(name, content, options)
+        .err()
+        .into_iter()
+        .collect()
+}
+
+/// 检查一个阶段的着色器, 诊断已映射回原始文件. 包括语法错误, KSH 不支持的 uniform 类型,
+/// 引擎 uniform 的类型和拼写, 以及没有使用的 uniform.
+///
+/// 提供另一个阶段的着色器 `other` (名称和内容) 时, 还会检查 varying 是否匹配, 两边的 uniform 声明是否一致,
+/// 并用 naga 做语义验证; 另一个着色器本身有错误时跳过这些检查.
+pub fn check_shader(
+    stage: ShaderStage,
+    name: &str,
+    content: &str,
+    other: Option<(&str, &str)>,
+    options: &BuildOptions,
+) -> Vec<Diagnostic> {
+    let (source, shader) = match parse_source(name, content, &options.preprocess) {
+        Ok(parsed) => parsed,
+        Err(diagnostic) => return vec![diagnostic],
+    };
+    let mut diagnostics = vec![];
+    if options.es100 {
+        diagnostics.extend(check_es100(&shader, stage));
+    }
+    let uniforms = match collect_uniforms(&shader) {
+        Ok(uniforms) => uniforms,
+        Err(e) => {
+            diagnostics.push(to_diagnostic(e));
+            return diagnostics
+                .into_iter()
+                .map(|d| source.map_diagnostic(d))
+                .collect();
+        }
+    };
+    let used: Vec<_> = uniforms.iter().collect();
+    diagnostics.extend(check_uniform_types(&used));
+    diagnostics.extend(check_engine_uniforms(&used));
+    // 没有使用的 uniform 不会写入 KSH, 在游戏中设置它们不会生效
+    if let Ok(unused) = collect_unused_uniforms(&shader) {
+        diagnostics.extend(unused.iter().map(|u| {
+            let message = format!("uniform {} 已声明但没有使用, 不会写入 KSH", u.name);
+            Diagnostic::warning(u.line.unwrap_or(1), message)
+        }));
+    }
+    let mut diagnostics: Vec<Diagnostic> = diagnostics
+        .into_iter()
+        .map(|d| source.map_diagnostic(d))
+        .collect();
+
+    let Some((other_name, other_content)) = other else {
+        return diagnostics;
+    };
+    let Ok((other_source, other_shader)) =
+        parse_source(other_name, other_content, &options.preprocess)
+    else {
+        return diagnostics;
+    };
+    let Ok(other_uniforms) = collect_uniforms(&other_shader) else {
+        return diagnostics;
+    };
+    let (vs, ps, vs_shader, ps_shader, vs_uniforms, ps_uniforms) = match stage {
+        ShaderStage::Vertex => (
+            &source,
+            &other_source,
+            &shader,
+            &other_shader,
+            &uniforms,
+            &other_uniforms,
+        ),
+        ShaderStage::Fragment => (
+            &other_source,
+            &source,
+            &other_shader,
+            &shader,
+            &other_uniforms,
+            &uniforms,
+        ),
+    };
+
+    for (diagnostic_stage, diagnostic) in link_varyings(vs_shader, ps_shader) {
+        if diagnostic_stage == stage {
+            diagnostics.push(source.map_diagnostic(diagnostic));
+        }
+    }
+    // 声明不一致的错误指向像素着色器
+    let merged = match merge_uniforms(vs_uniforms, ps_uniforms, vs, ps) {
+        Ok(merged) => merged,
+        Err(diagnostic) => {
+            if stage == ShaderStage::Fragment {
+                diagnostics.push(diagnostic);
+            }
+            return diagnostics;
+        }
+    };
+    #[cfg(feature = "naga")]
+    if !options.skip_validation {
+        if let Ok(layout) = InterfaceLayout::new(&merged, vs_shader, ps_shader) {
+            diagnostics.extend(
+                validate_with_naga(&shader, stage, &layout)
+                    .into_iter()
+                    .map(|d| source.map_diagnostic(d)),
+            );
+        }
+    }
+    #[cfg(not(feature = "naga"))]
+    let _ = merged;
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+
+    #[test]
+    fn test_check_shader() {
+        let options = BuildOptions::default();
+        let vs = "attribute vec3 POSITION;\n\
+            varying vec2 PS_TEXCOORD;\n\
+            void main() {\n\
+            PS_TEXCOORD = POSITION.xy;\n\
+            gl_Position = vec4(POSITION, 1.0);\n\
+            }\n";
+        let ps = "uniform mat4 MatrixWW;\n\
+            varying vec3 PS_TEXCOORD;\n\
+            void main() { gl_FragColor = MatrixWW * vec4(PS_TEXCOORD, 1.0); }\n";
+
+        // 单独检查时只有引擎 uniform 的拼写警告
+        let diagnostics = check_shader(ShaderStage::Fragment, "test.ps", ps, None, &options);
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert!(diagnostics[0].message.contains("MatrixWW"));
+
+        // 提供顶点着色器后还会报告 varying 类型不一致, 且只报告本阶段的
+        let diagnostics = check_shader(
+            ShaderStage::Fragment,
+            "test.ps",
+            ps,
+            Some(("test.vs", vs)),
+            &options,
+        );
+        assert!(
+            diagnostics
+                .iter()
+                .any(|d| d.line == 2 && d.message.contains("PS_TEXCOORD")),
+            "{:?}",
+            diagnostics
+        );
+        let diagnostics = check_shader(
+            ShaderStage::Vertex,
+            "test.vs",
+            vs,
+            Some(("test.ps", ps)),
+            &options,
+        );
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        // 没有使用的 uniform 是警告, KSH 不支持的类型是错误
+        let ps = "uniform vec4 TINT;\n\
+            uniform int MODE;\n\
+            uniform vec4 UNUSED;\n\
+            void main() { gl_FragColor = TINT * float(MODE); }\n";
+        let diagnostics = check_shader(ShaderStage::Fragment, "test.ps", ps, None, &options);
+        let summary: Vec<_> = diagnostics.iter().map(|d| (d.line, d.severity)).collect();
+        assert_eq!(summary, [(2, Severity::Error), (3, Severity::Warning)]);
+
+        let diagnostics = check_syntax(
+            "common.glsl",
+            "vec4 f() { return 1.0 }\n",
+            &options.preprocess,
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 1);
+    }
+}
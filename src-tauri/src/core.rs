@@ -1,9 +1,19 @@
-use crate::glsl_parser::parse_glsl_uniforms;
-use crate::types::{Variable, VariableScope};
-use clap::Error;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::engine_uniforms::{check_engine_uniforms, find_engine_uniform};
+use crate::es100::check_es100;
+use crate::glsl_parser::{collect_uniforms, parse_glsl, ParsedShader};
+use crate::linker::link_varyings;
+use crate::manifest::Manifest;
+#[cfg(feature = "naga")]
+use crate::naga_glsl::{validate_with_naga, InterfaceLayout};
+use crate::preprocessor::{resolve_includes, specialize_source, IncludedSource, PreprocessOptions};
+use crate::types::{ShaderStage, Variable, VariableScope};
 use glsl_lang::ast::TypeSpecifierNonArrayData;
+use log::{error, warn};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 
 fn read_u32(cursor: &mut std::io::Cursor<&[u8]>) -> io::Result<u32> {
@@ -12,41 +22,92 @@ fn read_u32(cursor: &mut std::io::Cursor<&[u8]>) -> io::Result<u32> {
     Ok(u32::from_le_bytes(buffer))
 }
 
-fn read_string(cursor: &mut std::io::Cursor<&[u8]>) -> io::Result<String> {
+// 长度来自文件, 先与剩余的字节数比较再分配
+fn check_remaining(
+    cursor: &std::io::Cursor<&[u8]>,
+    length: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let remaining = cursor
+        .get_ref()
+        .len()
+        .saturating_sub(cursor.position() as usize);
+    if length > remaining {
+        return Err(format!(
+            "KSH 文件不完整: 需要 {} 字节, 剩余 {} 字节",
+            length, remaining
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn read_string(cursor: &mut std::io::Cursor<&[u8]>) -> Result<String, Box<dyn std::error::Error>> {
     let length = read_u32(cursor)? as usize;
+    check_remaining(cursor, length)?;
     let mut buffer = vec![0; length];
     cursor.read_exact(&mut buffer)?;
-    Ok(String::from_utf8(buffer).expect("Invalid UTF-8 sequence"))
+    String::from_utf8(buffer).map_err(|_| "KSH 文件中的字符串不是有效的 UTF-8".into())
 }
 
-fn read_variable(cursor: &mut std::io::Cursor<&[u8]>) -> Result<Variable, Error> {
+fn read_variable(
+    cursor: &mut std::io::Cursor<&[u8]>,
+) -> Result<Variable, Box<dyn std::error::Error>> {
     let mut var = Variable::new();
     var.name = read_string(cursor)?;
-    var.set_scope(read_u32(cursor)?)?;
-    var.set_type(read_u32(cursor)?)?;
+    let scope = read_u32(cursor)?;
+    var.set_scope(scope)
+        .map_err(|_| format!("uniform {} 的作用域 {} 无效", var.name, scope))?;
+    let type_id = read_u32(cursor)?;
+    var.set_type(type_id)
+        .map_err(|_| format!("uniform {} 的类型 {} 无效", var.name, type_id))?;
     let length = read_u32(cursor)?;
     var.array_length = if length > 1 { Some(length) } else { None };
     if var.r#type != TypeSpecifierNonArrayData::Sampler2D {
-        let data_length = read_u32(cursor)?;
+        let data_length = read_u32(cursor)? as usize;
+        check_remaining(cursor, data_length.saturating_mul(4))?;
         var.default_data = (0..data_length)
-            .map(|_| read_u32(cursor).unwrap())
-            .collect();
+            .map(|_| read_u32(cursor))
+            .collect::<Result<_, _>>()?;
     }
     Ok(var)
 }
 
-/// 解析 KSH 文件内容，返回着色器内容
+// 着色器引用的 uniform 在 uniform 表中的下标
+fn read_uniform_references(
+    cursor: &mut std::io::Cursor<&[u8]>,
+    uniforms: &[Variable],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    (0..read_u32(cursor)?)
+        .map(|_| {
+            let index = read_u32(cursor)?;
+            uniforms
+                .get(index as usize)
+                .map(|u| u.name.clone())
+                .ok_or_else(|| {
+                    format!(
+                        "KSH 文件中的 uniform 下标 {} 越界, 只有 {} 个 uniform",
+                        index,
+                        uniforms.len()
+                    )
+                    .into()
+                })
+        })
+        .collect()
+}
+
+/// 解析 KSH 文件内容，返回着色器内容. 文件不完整或损坏时返回错误
 pub fn analyze_ksh(
     content: &[u8],
 ) -> Result<(String, String, String, String), Box<dyn std::error::Error>> {
     let mut cursor = std::io::Cursor::new(content);
-    let file_name = read_string(&mut cursor)?;
+    // 文件名, 分析时不需要
+    read_string(&mut cursor)?;
     let uniforms_count = read_u32(&mut cursor)?;
     log::debug!("Uniforms数量: {}", uniforms_count);
 
     let uniforms: Vec<Variable> = (0..uniforms_count)
-        .map(|_| read_variable(&mut cursor).expect("Failed to read variable"))
-        .collect();
+        .map(|_| read_variable(&mut cursor))
+        .collect::<Result<_, _>>()?;
 
     let vs_name = read_string(&mut cursor)?;
     let mut vs_content = read_string(&mut cursor)?;
@@ -57,19 +118,8 @@ pub fn analyze_ksh(
     ps_content.pop();
 
     // 读取并忽略 uniforms 引用
-    let _vs_uniforms: Result<Vec<String>, io::Error> = (0..read_u32(&mut cursor)?)
-        .map(|_| {
-            let index = read_u32(&mut cursor)?;
-            Ok(uniforms[index as usize].name.clone())
-        })
-        .collect();
-
-    let _ps_uniforms: Result<Vec<String>, io::Error> = (0..read_u32(&mut cursor)?)
-        .map(|_| {
-            let index = read_u32(&mut cursor)?;
-            Ok(uniforms[index as usize].name.clone())
-        })
-        .collect();
+    let _vs_uniforms = read_uniform_references(&mut cursor, &uniforms)?;
+    let _ps_uniforms = read_uniform_references(&mut cursor, &uniforms)?;
 
     // 忽略剩余的 uniform pointers
     let _ = (0..)
@@ -105,12 +155,84 @@ pub fn analyze_ksh_file(
     Ok(())
 }
 
+/// 构建选项: 预处理之外, 构建时的检查和 KSH 输出设置
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub preprocess: PreprocessOptions,
+    /// 按 GLSL ES 1.00 (含附录 A 的限制) 检查着色器
+    pub es100: bool,
+    /// 跳过 naga 语义验证
+    pub skip_validation: bool,
+    /// 写入 KSH 的 uniform 默认值, 按名称指定, 未指定的为 0. 数组和采样器没有默认值
+    pub uniform_defaults: HashMap<String, Vec<f32>>,
+}
+
+// 展开 #include 并解析后的两个着色器, 以及各自使用的 uniform, 错误信息指向原始文件
+struct ShaderPair {
+    vs: IncludedSource,
+    ps: IncludedSource,
+    vs_shader: ParsedShader,
+    ps_shader: ParsedShader,
+    vs_uniforms: Vec<Variable>,
+    ps_uniforms: Vec<Variable>,
+}
+
+impl ShaderPair {
+    fn load(
+        vs_name: &str,
+        vs_content: &str,
+        ps_name: &str,
+        ps_content: &str,
+        options: &BuildOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let vs = resolve_includes(vs_content, Path::new(vs_name), &options.preprocess)?;
+        let ps = resolve_includes(ps_content, Path::new(ps_name), &options.preprocess)?;
+        let vs_shader = parse_glsl(&vs.code, &options.preprocess).map_err(|e| vs.map_error(e))?;
+        let ps_shader = parse_glsl(&ps.code, &options.preprocess).map_err(|e| ps.map_error(e))?;
+        let vs_uniforms = collect_uniforms(&vs_shader).map_err(|e| vs.map_error(e))?;
+        let ps_uniforms = collect_uniforms(&ps_shader).map_err(|e| ps.map_error(e))?;
+        Ok(ShaderPair {
+            vs,
+            ps,
+            vs_shader,
+            ps_shader,
+            vs_uniforms,
+            ps_uniforms,
+        })
+    }
+
+    /// 合并后的 uniform 表, 顶点着色器的在前. KSH 无法保存的类型 (如 int, mat3) 会报错
+    fn uniforms(&self) -> Result<Vec<&Variable>, Box<dyn std::error::Error>> {
+        let uniforms = merge_uniforms(&self.vs_uniforms, &self.ps_uniforms, &self.vs, &self.ps)?;
+        let (vs_part, ps_part) = uniforms.split_at(self.vs_uniforms.len());
+        let mut type_errors = check_uniform_types(vs_part)
+            .into_iter()
+            .map(|d| self.vs.map_diagnostic(d))
+            .chain(
+                check_uniform_types(ps_part)
+                    .into_iter()
+                    .map(|d| self.ps.map_diagnostic(d)),
+            );
+        if let Some(first) = type_errors.next() {
+            for diagnostic in type_errors {
+                error!("{}", diagnostic);
+            }
+            return Err(first.into());
+        }
+        Ok(uniforms)
+    }
+}
+
+/// 构建 KSH 文件内容
+///
+/// `#include` 会被展开后写入, 其余代码原样写入; uniform 从预处理后的代码中解析.
 pub fn build_ksh(
     file_name: &str,
     vs_name: &str,
     vs_content: &str,
     ps_name: &str,
     ps_content: &str,
+    options: &BuildOptions,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut buffer = Vec::new();
 
@@ -118,16 +240,80 @@ pub fn build_ksh(
     buffer.extend_from_slice(&(file_name.len() as u32).to_le_bytes());
     buffer.extend_from_slice(file_name.as_bytes());
 
-    // 从着色器文件中解析uniforms
-    let vs_uniforms = parse_glsl_uniforms(vs_content)?;
-    let ps_uniforms = parse_glsl_uniforms(ps_content)?;
-    // v _ps 里如果有重复声明的uniform,那么只保留vs里的, ps里的删除
-    // 合并vs_uniforms和ps_uniforms, 重复的只保留vs里的
-    let mut uniforms = Vec::new();
-    uniforms.extend(&vs_uniforms);
-    for ps_uniform in &ps_uniforms {
-        if !uniforms.iter().any(|u| u.name == ps_uniform.name) {
-            uniforms.push(ps_uniform);
+    let shaders = ShaderPair::load(vs_name, vs_content, ps_name, ps_content, options)?;
+    let ShaderPair {
+        vs,
+        ps,
+        vs_shader,
+        ps_shader,
+        vs_uniforms,
+        ps_uniforms,
+    } = &shaders;
+    let vs_content = vs.code.as_str();
+    let ps_content = ps.code.as_str();
+
+    // 检查 varying 是否匹配以及 GLSL ES 1.00 规范, 警告只输出日志, 出现错误时停止构建
+    let mut checks = link_varyings(vs_shader, ps_shader);
+    if options.es100 {
+        for (stage, shader) in [
+            (ShaderStage::Vertex, vs_shader),
+            (ShaderStage::Fragment, ps_shader),
+        ] {
+            checks.extend(check_es100(shader, stage).into_iter().map(|d| (stage, d)));
+        }
+    }
+    let mut link_error = None;
+    for (stage, diagnostic) in checks {
+        let diagnostic = match stage {
+            ShaderStage::Vertex => vs.map_diagnostic(diagnostic),
+            ShaderStage::Fragment => ps.map_diagnostic(diagnostic),
+        };
+        match diagnostic.severity {
+            Severity::Warning => warn!("{}", diagnostic),
+            Severity::Error if link_error.is_none() => link_error = Some(diagnostic),
+            Severity::Error => error!("{}", diagnostic),
+        }
+    }
+    if let Some(diagnostic) = link_error {
+        return Err(diagnostic.into());
+    }
+
+    let uniforms = shaders.uniforms()?;
+    let (vs_part, ps_part) = uniforms.split_at(vs_uniforms.len());
+    // 检查引擎 uniform 的类型和疑似拼写错误, 只输出警告
+    for diagnostic in check_engine_uniforms(vs_part) {
+        warn!("{}", vs.map_diagnostic(diagnostic));
+    }
+    for diagnostic in check_engine_uniforms(ps_part) {
+        warn!("{}", ps.map_diagnostic(diagnostic));
+    }
+    // 用 naga 做语义验证 (类型检查, 未定义的标识符, 函数签名等), 出现错误时停止构建
+    #[cfg(feature = "naga")]
+    if !options.skip_validation {
+        let layout = InterfaceLayout::new(&uniforms, vs_shader, ps_shader).map_err(
+            |(stage, diagnostic)| match stage {
+                ShaderStage::Vertex => vs.map_diagnostic(diagnostic),
+                ShaderStage::Fragment => ps.map_diagnostic(diagnostic),
+            },
+        )?;
+        let diagnostics = validate_with_naga(vs_shader, ShaderStage::Vertex, &layout)
+            .into_iter()
+            .map(|d| vs.map_diagnostic(d))
+            .chain(
+                validate_with_naga(ps_shader, ShaderStage::Fragment, &layout)
+                    .into_iter()
+                    .map(|d| ps.map_diagnostic(d)),
+            );
+        let mut naga_error = None;
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                Severity::Warning => warn!("{}", diagnostic),
+                Severity::Error if naga_error.is_none() => naga_error = Some(diagnostic),
+                Severity::Error => error!("{}", diagnostic),
+            }
+        }
+        if let Some(diagnostic) = naga_error {
+            return Err(diagnostic.into());
         }
     }
     // 写入uniforms 先写长度, 再写内容
@@ -145,13 +331,13 @@ pub fn build_ksh(
         buffer.extend_from_slice(&(uniform.array_length.unwrap_or(1)).to_le_bytes());
         if uniform.r#type != TypeSpecifierNonArrayData::Sampler2D {
             if uniform.array_length.is_none() {
-                let default_data_length = uniform.default_data_length();
-                buffer.extend_from_slice(&(default_data_length as u32).to_le_bytes());
-                for _ in 0..default_data_length {
-                    buffer.extend_from_slice(&0u32.to_le_bytes());
+                let default_data = uniform_default_data(uniform, options)?;
+                buffer.extend_from_slice(&(default_data.len() as u32).to_le_bytes());
+                for value in default_data {
+                    buffer.extend_from_slice(&value.to_bits().to_le_bytes());
                 }
             } else {
-                buffer.extend_from_slice(&(0 as u32).to_le_bytes());
+                buffer.extend_from_slice(&0u32.to_le_bytes());
             }
         }
     }
@@ -174,7 +360,7 @@ pub fn build_ksh(
     buffer.push(0);
     // 写入顶点着色器的uniforms引用
     buffer.extend_from_slice(&(vs_uniforms.len() as u32).to_le_bytes());
-    for uniform in &vs_uniforms {
+    for uniform in vs_uniforms {
         if let Some(index) = uniforms.iter().position(|u| u.name == uniform.name) {
             buffer.extend_from_slice(&(index as u32).to_le_bytes());
         } else {
@@ -183,7 +369,7 @@ pub fn build_ksh(
     }
     // 写入像素着色器的uniforms引用
     buffer.extend_from_slice(&(ps_uniforms.len() as u32).to_le_bytes());
-    for uniform in &ps_uniforms {
+    for uniform in ps_uniforms {
         if let Some(index) = uniforms.iter().position(|u| u.name == uniform.name) {
             buffer.extend_from_slice(&(index as u32).to_le_bytes());
         } else {
@@ -211,19 +397,253 @@ pub fn get_ps_vs_from_dir(path: &Path) -> Result<(PathBuf, PathBuf), Box<dyn std
     }
 }
 
+/// 列出着色器文件 (.vs/.ps) 或 KSH 文件中使用的 uniform, 并标注引擎 uniform 和疑似拼写错误
+pub fn inspect_file(
+    path: &Path,
+    options: &PreprocessOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut report = String::new();
+    if path.extension().and_then(|s| s.to_str()) == Some("ksh") {
+        let (vs_name, vs_content, ps_name, ps_content) = analyze_ksh(&fs::read(path)?)?;
+        inspect_shader(Path::new(&vs_name), &vs_content, options, &mut report)?;
+        report.push('\n');
+        inspect_shader(Path::new(&ps_name), &ps_content, options, &mut report)?;
+    } else {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("读取文件 {} 失败: {}", path.display(), e))?;
+        let mut options = options.clone();
+        if let Some(parent) = path.parent() {
+            options.include_paths.insert(0, parent.to_path_buf());
+        }
+        inspect_shader(path, &content, &options, &mut report)?;
+    }
+    Ok(report)
+}
+
+fn inspect_shader(
+    path: &Path,
+    content: &str,
+    options: &PreprocessOptions,
+    report: &mut String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let included = resolve_includes(content, path, options)?;
+    let shader = parse_glsl(&included.code, options).map_err(|e| included.map_error(e))?;
+    let uniforms = collect_uniforms(&shader).map_err(|e| included.map_error(e))?;
+
+    report.push_str(&format!("{}:\n", path.display()));
+    if uniforms.is_empty() {
+        report.push_str("  (没有使用 uniform)\n");
+    }
+    for uniform in &uniforms {
+        let declaration = uniform.declaration();
+        match find_engine_uniform(&uniform.name) {
+            Some(engine_uniform) => report.push_str(&format!(
+                "  {:<32} 引擎: {}\n",
+                declaration, engine_uniform.description
+            )),
+            None => report.push_str(&format!("  {}\n", declaration)),
+        }
+    }
+    let uniforms: Vec<&Variable> = uniforms.iter().collect();
+    for diagnostic in check_engine_uniforms(&uniforms) {
+        report.push_str(&format!("{}\n", included.map_diagnostic(diagnostic)));
+    }
+    Ok(())
+}
+
+/// 合并两个着色器的 uniform, 即 KSH 中的 uniform 表.
+/// v _ps 里如果有重复声明的uniform,那么只保留vs里的, ps里的删除, 两边声明不一致时报错
+pub fn merge_uniforms<'a>(
+    vs_uniforms: &'a [Variable],
+    ps_uniforms: &'a [Variable],
+    vs: &IncludedSource,
+    ps: &IncludedSource,
+) -> Result<Vec<&'a Variable>, Diagnostic> {
+    let mut uniforms: Vec<&Variable> = Vec::new();
+    uniforms.extend(vs_uniforms);
+    for ps_uniform in ps_uniforms {
+        match uniforms.iter().find(|u| u.name == ps_uniform.name) {
+            Some(vs_uniform) => check_uniform_conflict(vs_uniform, ps_uniform, vs, ps)?,
+            None => uniforms.push(ps_uniform),
+        }
+    }
+    Ok(uniforms)
+}
+
+// 同名 uniform 在两个着色器中共享同一个绑定, 类型, 数组长度必须一致.
+// 精度只在两边都显式指定时比较
+fn check_uniform_conflict(
+    vs_uniform: &Variable,
+    ps_uniform: &Variable,
+    vs: &IncludedSource,
+    ps: &IncludedSource,
+) -> Result<(), Diagnostic> {
+    let precision_conflict = matches!(
+        (&vs_uniform.precision, &ps_uniform.precision),
+        (Some(a), Some(b)) if a != b
+    );
+    if vs_uniform.r#type == ps_uniform.r#type
+        && vs_uniform.array_length == ps_uniform.array_length
+        && !precision_conflict
+    {
+        return Ok(());
+    }
+
+    let vs_location = vs_uniform
+        .line
+        .and_then(|line| vs.locate(line))
+        .map(|(path, line)| format!(" ({}:{})", path.display(), line))
+        .unwrap_or_default();
+    let message = format!(
+        "uniform {} 在顶点着色器和像素着色器中的声明不一致: 顶点着色器{}中为 {}, 像素着色器中为 {}",
+        ps_uniform.name,
+        vs_location,
+        vs_uniform.declaration(),
+        ps_uniform.declaration()
+    );
+    Err(ps.map_diagnostic(Diagnostic::error(ps_uniform.line.unwrap_or(1), message)))
+}
+
+/// 检查 uniform 的类型能否写入 KSH, KSH 只支持 float, vec2, vec3, vec4, mat4 和 sampler2D
+pub fn check_uniform_types(uniforms: &[&Variable]) -> Vec<Diagnostic> {
+    uniforms
+        .iter()
+        .filter(|u| u.variable_type().is_none())
+        .map(|u| {
+            let message = format!(
+                "uniform {} 的类型不能写入 KSH, 只支持 float, vec2, vec3, vec4, mat4 和 sampler2D",
+                u.declaration()
+            );
+            Diagnostic::error(u.line.unwrap_or(1), message)
+        })
+        .collect()
+}
+
+// 写入 KSH 的默认值, 未指定时为 0
+fn uniform_default_data(uniform: &Variable, options: &BuildOptions) -> Result<Vec<f32>, String> {
+    let length = uniform.default_data_length();
+    match options.uniform_defaults.get(&uniform.name) {
+        None => Ok(vec![0.0; length]),
+        Some(values) if values.len() == length => Ok(values.clone()),
+        Some(values) => Err(format!(
+            "uniform {} 的默认值需要 {} 个数, 实际为 {} 个",
+            uniform.name,
+            length,
+            values.len()
+        )),
+    }
+}
+
+/// `build_ksh` 写入的 uniform 表中的一项
+#[derive(Debug, Clone, Serialize)]
+pub struct UniformEntry {
+    /// 在 uniform 表中的下标, 两个着色器按下标引用 uniform
+    pub index: usize,
+    pub name: String,
+    /// 类型名, 如 `vec4`
+    #[serde(rename = "type")]
+    pub r#type: String,
+    pub array_length: Option<u32>,
+    /// 顶点着色器是否使用
+    pub vertex: bool,
+    /// 像素着色器是否使用
+    pub fragment: bool,
+    /// 写入 KSH 的默认值, 数组和采样器为空
+    pub default_value: Vec<f32>,
+    /// 是否由引擎赋值
+    pub engine: bool,
+}
+
+/// 按 `build_ksh` 的方式合并两个着色器使用的 uniform, 顺序与写入 KSH 的顺序一致
+pub fn uniform_table(
+    vs_name: &str,
+    vs_content: &str,
+    ps_name: &str,
+    ps_content: &str,
+    options: &BuildOptions,
+) -> Result<Vec<UniformEntry>, Box<dyn std::error::Error>> {
+    let shaders = ShaderPair::load(vs_name, vs_content, ps_name, ps_content, options)?;
+    let uniforms = shaders.uniforms()?;
+    let (vs_uniforms, ps_uniforms) = (&shaders.vs_uniforms, &shaders.ps_uniforms);
+
+    let mut table = vec![];
+    for (index, uniform) in uniforms.iter().enumerate() {
+        let has_default = uniform.array_length.is_none()
+            && uniform.r#type != TypeSpecifierNonArrayData::Sampler2D;
+        table.push(UniformEntry {
+            index,
+            name: uniform.name.clone(),
+            r#type: uniform
+                .variable_type()
+                .map(|t| t.name())
+                .unwrap_or_default()
+                .to_string(),
+            array_length: uniform.array_length,
+            vertex: vs_uniforms.iter().any(|u| u.name == uniform.name),
+            fragment: ps_uniforms.iter().any(|u| u.name == uniform.name),
+            default_value: if has_default {
+                uniform_default_data(uniform, options)?
+            } else {
+                vec![]
+            },
+            engine: find_engine_uniform(&uniform.name).is_some(),
+        });
+    }
+    Ok(table)
+}
+
 pub fn build_ksh_file_from_dir<'a>(
     dir_path: &'a Path,
     out_path: &'a Path,
+    options: &BuildOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let (vs_path, ps_path) = get_ps_vs_from_dir(dir_path)?;
-    build_ksh_file(&vs_path, &ps_path, out_path).map_err(|e| format!("构建着色器时出错: {}", e))?;
+    build_ksh_file(&vs_path, &ps_path, out_path, options)
+        .map_err(|e| format!("构建着色器时出错: {}", e))?;
     Ok(())
 }
 
+/// 按清单中的变体构建多个 KSH 文件, 每个变体输出到 `out_dir/<name>.ksh`
+pub fn build_ksh_variants_from_dir(
+    dir_path: &Path,
+    out_dir: &Path,
+    manifest: &Manifest,
+    options: &BuildOptions,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let (vs_path, ps_path) = get_ps_vs_from_dir(dir_path)?;
+    let mut outputs = vec![];
+    for variant in &manifest.variants {
+        let out_path = out_dir.join(format!("{}.ksh", variant.name));
+        log::info!("构建变体: {}", variant.name);
+        build_ksh_file_variant(
+            &vs_path,
+            &ps_path,
+            &out_path,
+            &variant.parse_defines()?,
+            options,
+        )
+        .map_err(|e| format!("构建变体 {} 时出错: {}", variant.name, e))?;
+        outputs.push(out_path);
+    }
+    Ok(outputs)
+}
+
 pub fn build_ksh_file(
     vs_file: &Path,
     ps_file: &Path,
     out_path: &Path,
+    options: &BuildOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    build_ksh_file_variant(vs_file, ps_file, out_path, &[], options)
+}
+
+// `defines` 会写入着色器代码, 用于生成变体
+fn build_ksh_file_variant(
+    vs_file: &Path,
+    ps_file: &Path,
+    out_path: &Path,
+    defines: &[(String, String)],
+    options: &BuildOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // 读取文件内容，附带自定义错误信息
     let read_file = |path: &Path| -> Result<String, Box<dyn std::error::Error>> {
@@ -235,8 +655,8 @@ pub fn build_ksh_file(
         Ok(content)
     };
 
-    let vs_content = read_file(vs_file)?;
-    let ps_content = read_file(ps_file)?;
+    let vs_content = specialize_source(&read_file(vs_file)?, defines);
+    let ps_content = specialize_source(&read_file(ps_file)?, defines);
 
     // 处理文件名，附带自定义错误信息
     let vs_name = vs_file
@@ -250,41 +670,60 @@ pub fn build_ksh_file(
         .to_str()
         .ok_or_else(|| format!("像素着色器文件名包含非法UTF-8字符: {}", ps_file.display()))?;
 
-    let mut file = File::create(out_path)
-        .map_err(|e| format!("创建文件 {} 失败: {}", out_path.display(), e))?;
     // 从out里解析file_name, 不要扩展名
     let file_name = out_path
         .file_stem()
         .ok_or_else(|| format!("无效的输出路径: {}", out_path.display()))?
         .to_str()
         .ok_or_else(|| format!("输出路径包含非法UTF-8字符: {}", out_path.display()))?;
-    let buffer = build_ksh(file_name, vs_name, &vs_content, ps_name, &ps_content)?;
-    file.write_all(&buffer)?;
+    // 着色器所在目录也作为 #include 的搜索路径
+    let mut options = options.clone();
+    for dir in [vs_file.parent(), ps_file.parent()].into_iter().flatten() {
+        let include_paths = &mut options.preprocess.include_paths;
+        if !include_paths.iter().any(|p| p == dir) {
+            include_paths.insert(0, dir.to_path_buf());
+        }
+    }
+    let buffer = build_ksh(
+        file_name,
+        vs_name,
+        &vs_content,
+        ps_name,
+        &ps_content,
+        &options,
+    )?;
+    // 构建成功后再写入, 失败时不会留下空文件或覆盖已有的文件
+    fs::write(out_path, buffer)
+        .map_err(|e| format!("写入文件 {} 失败: {}", out_path.display(), e))?;
 
     Ok(())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use log::warn;
     use std::env::temp_dir;
     use std::fs::{self, read_dir};
     use std::path::Path;
 
-    // 测试辅助函数
-    struct TestContext {
-        temp_dir: PathBuf,
+    // 测试辅助函数, 其他模块中写文件的测试也使用
+    pub(crate) struct TestContext {
+        pub(crate) temp_dir: PathBuf,
     }
 
     impl TestContext {
-        fn new() -> Self {
-            let temp_dir = temp_dir().join("ksh_test");
+        // 每个测试使用以测试名命名的目录, 并行运行时不会删除其他测试的文件
+        pub(crate) fn new(name: &str) -> Self {
+            let temp_dir = temp_dir().join("ksh_test").join(name);
+            if temp_dir.exists() {
+                fs::remove_dir_all(&temp_dir).expect("Failed to remove temp directory");
+            }
             fs::create_dir_all(&temp_dir).expect("Failed to create temp directory");
             Self { temp_dir }
         }
 
-        fn cleanup(&self) {
+        pub(crate) fn cleanup(&self) {
             if self.temp_dir.exists() {
                 fs::remove_dir_all(&self.temp_dir).expect("Failed to remove temp directory");
             }
@@ -298,7 +737,7 @@ mod tests {
                 let min_len = content1.len().min(content2.len());
                 for i in 0..min_len {
                     if content1[i] != content2[i] {
-                        let start = if i >= 10 { i - 10 } else { 0 };
+                        let start = i.saturating_sub(10);
                         let end = (i + 10).min(min_len);
                         warn!("Difference found at byte {}:", i);
                         warn!("File 1: {:?}", &content1[start..end]);
@@ -326,7 +765,8 @@ mod tests {
         #[test]
         fn test_parse_glsl() {
             let file_content = fs::read_to_string("C:\\Saved Games\\Steam\\steamapps\\common\\Don't Starve Together\\data\\databundles\\shaders\\out\\anim.ps").unwrap();
-            let uniforms = parse_glsl_uniforms(&file_content).unwrap();
+            let uniforms =
+                parse_glsl_uniforms(&file_content, &PreprocessOptions::default()).unwrap();
             println!("uniforms: {:?}", uniforms);
         }
     }
@@ -337,7 +777,7 @@ mod tests {
 
         #[test]
         fn test_build_ksh_file() {
-            let ctx = TestContext::new();
+            let ctx = TestContext::new("test_build_ksh_file");
             let vs_file = Path::new(
                 "C:\\Users\\Tohsa\\AppData\\Local\\Temp\\ksh_test\\anim_bloom_haunted\\anim.vs",
             );
@@ -354,15 +794,16 @@ mod tests {
                     .to_str()
                     .expect("Invalid UTF-8 sequence")
             ));
-            build_ksh_file(vs_file, ps_file, &out_path).expect("Failed to build ksh file");
-            ctx.assert_files_equal(&ksh_file, &out_path);
+            build_ksh_file(vs_file, ps_file, &out_path, &BuildOptions::default())
+                .expect("Failed to build ksh file");
+            ctx.assert_files_equal(ksh_file, &out_path);
 
             ctx.cleanup();
         }
 
         #[test]
         fn test_build_ksh_file_from_dir() {
-            let ctx = TestContext::new();
+            let ctx = TestContext::new("test_build_ksh_file_from_dir");
             let test_dir = ctx.temp_dir.join("test_shader");
             fs::create_dir_all(&test_dir).expect("Failed to create test directory");
 
@@ -373,22 +814,654 @@ mod tests {
             fs::write(test_dir.join("test.ps"), ps_content).expect("Failed to write ps file");
 
             let out_path = ctx.temp_dir.join("test.ksh");
-            build_ksh_file_from_dir(&test_dir, &out_path).expect("Failed to build ksh file");
+            build_ksh_file_from_dir(&test_dir, &out_path, &BuildOptions::default())
+                .expect("Failed to build ksh file");
 
             // 验证输出文件存在
             assert!(out_path.exists(), "Output file should exist");
 
             ctx.cleanup();
         }
+
+        #[test]
+        fn test_build_ksh_diagnostics() {
+            let vs =
+                "attribute vec3 POSITION;\nvoid main() { gl_Position = vec4(POSITION, 1.0); }\n";
+            let options = BuildOptions::default();
+
+            // 预处理错误指向 #if 所在行
+            let ps = "void main() {}\n#ifdef BLOOM\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 2);
+            assert_eq!(diagnostic.source_line.as_deref(), Some("#ifdef BLOOM"));
+
+            // 语法错误带有列号, 并显示为代码片段
+            let ps = "void main() {\n    gl_FragColor = vec4(1.0) +;\n}\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 2);
+            assert!(diagnostic.column.is_some());
+            assert!(err.to_string().contains("^"));
+
+            // 宏在出错位置之前展开时, 列号在原始代码中没有意义, 不显示 ^
+            let ps = "#define RED vec4(1.0, 0.0, 0.0, 1.0)\n\
+                void main() {\n    gl_FragColor = RED +;\n}\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.line, 3);
+            assert_eq!(diagnostic.column, None);
+            assert!(!err.to_string().contains("^"));
+
+            // 宏在出错位置之后时列号不受影响
+            let ps = "#define RED vec4(1.0, 0.0, 0.0, 1.0)\n\
+                void main() {\n    gl_FragColor = +; vec4 c = RED;\n}\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.line, 3);
+            assert_eq!(diagnostic.column, Some(21));
+        }
+
+        #[test]
+        fn test_build_ksh_varying_link() {
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                varying vec3 PS_POS;\n\
+                void main() {\n\
+                PS_TEXCOORD = POSITION.xy;\n\
+                PS_POS = POSITION;\n\
+                gl_Position = vec4(POSITION, 1.0);\n\
+                }\n";
+            let options = BuildOptions::default();
+
+            // 顶点着色器多输出的 varying 只是警告
+            let ps = "varying vec2 PS_TEXCOORD;\n\
+                void main() { gl_FragColor = vec4(PS_TEXCOORD, 0.0, 1.0); }\n";
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+
+            // 类型不一致
+            let ps = "varying vec3 PS_TEXCOORD;\n\
+                void main() { gl_FragColor = vec4(PS_TEXCOORD, 1.0); }\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 1);
+            assert!(diagnostic.message.contains("PS_TEXCOORD"), "{}", err);
+
+            // 读取了顶点着色器没有输出的 varying
+            let ps = "varying vec4 PS_COLOUR;\n\
+                void main() { gl_FragColor = PS_COLOUR; }\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            assert!(err.to_string().contains("PS_COLOUR"), "{}", err);
+        }
+
+        #[test]
+        fn test_build_ksh_varying_not_written() {
+            let ps = "varying vec2 PS_TEXCOORD;\n\
+                void main() { gl_FragColor = vec4(PS_TEXCOORD, 0.0, 1.0); }\n";
+            let options = BuildOptions::default();
+
+            // 顶点着色器声明了 varying 但没有赋值
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() { gl_Position = vec4(POSITION, 1.0); }\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 1);
+            assert!(diagnostic.message.contains("从未赋值"), "{}", err);
+
+            // 写入分量, 或通过 out 参数写入都算赋值
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() { PS_TEXCOORD.x = POSITION.x; gl_Position = vec4(POSITION, 1.0); }\n";
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void uv(out vec2 result);\n\
+                void main() { uv(PS_TEXCOORD); gl_Position = vec4(POSITION, 1.0); }\n\
+                void uv(out vec2 result) { result = POSITION.xy; }\n";
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+        }
+
+        #[test]
+        fn test_build_ksh_uniform_conflict() {
+            let vs = "uniform vec4 TINT;\n\
+                attribute vec3 POSITION;\n\
+                void main() { gl_Position = vec4(POSITION, 1.0) * TINT; }\n";
+            let options = BuildOptions::default();
+
+            let ps = "uniform vec4 TINT;\nvoid main() { gl_FragColor = TINT; }\n";
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+
+            let ps = "precision mediump float;\n\
+                uniform vec3 TINT;\n\
+                void main() { gl_FragColor = vec4(TINT, 1.0); }\n";
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 2);
+            assert!(diagnostic.message.contains("test.vs:1"), "{}", err);
+            assert!(diagnostic.message.contains("vec3"), "{}", err);
+
+            let ps = "uniform lowp vec4 TINT;\nvoid main() { gl_FragColor = TINT; }\n";
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+            let vs = vs.replace("uniform vec4", "uniform highp vec4");
+            assert!(build_ksh("test", "test.vs", &vs, "test.ps", ps, &options).is_err());
+        }
+
+        #[test]
+        fn test_build_ksh_unsupported_uniform_type() {
+            let vs = "attribute vec3 POSITION;\n\
+                void main() { gl_Position = vec4(POSITION, 1.0); }\n";
+            let ps = "uniform vec4 TINT;\n\
+                uniform int MODE;\n\
+                void main() { gl_FragColor = TINT * float(MODE); }\n";
+            let err = build_ksh(
+                "test",
+                "test.vs",
+                vs,
+                "test.ps",
+                ps,
+                &BuildOptions::default(),
+            )
+            .unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 2);
+            assert!(diagnostic.message.contains("MODE"), "{}", err);
+        }
+
+        #[test]
+        fn test_uniform_table() {
+            let vs = "uniform mat4 MatrixP;\n\
+                uniform vec4 TINT;\n\
+                attribute vec3 POSITION;\n\
+                void main() { gl_Position = MatrixP * vec4(POSITION, 1.0) * TINT; }\n";
+            let ps = "uniform sampler2D SAMPLER[2];\n\
+                uniform vec4 TINT;\n\
+                uniform float ALPHA;\n\
+                void main() { gl_FragColor = texture2D(SAMPLER[1], vec2(0.0)) * TINT * ALPHA; }\n";
+            let mut options = BuildOptions::default();
+            options
+                .uniform_defaults
+                .insert("TINT".to_string(), vec![1.0, 0.5, 0.25, 1.0]);
+
+            let table = uniform_table("test.vs", vs, "test.ps", ps, &options).unwrap();
+            let summary: Vec<_> = table
+                .iter()
+                .map(|u| {
+                    (
+                        u.index,
+                        u.name.as_str(),
+                        u.r#type.as_str(),
+                        u.vertex,
+                        u.fragment,
+                    )
+                })
+                .collect();
+            assert_eq!(
+                summary,
+                [
+                    (0, "MatrixP", "mat4", true, false),
+                    (1, "TINT", "vec4", true, true),
+                    (2, "SAMPLER", "sampler2D", false, true),
+                    (3, "ALPHA", "float", false, true),
+                ]
+            );
+            assert!(table[0].engine && !table[1].engine);
+            assert_eq!(table[0].default_value, [0.0; 16]);
+            assert_eq!(table[1].default_value, [1.0, 0.5, 0.25, 1.0]);
+            assert_eq!(table[2].array_length, Some(2));
+            assert!(table[2].default_value.is_empty());
+
+            // 默认值写入 KSH
+            let ksh = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+            let tint: Vec<u8> = [4u32, 1.0f32.to_bits(), 0.5f32.to_bits(), 0.25f32.to_bits()]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            assert!(ksh.windows(tint.len()).any(|w| w == tint));
+
+            options
+                .uniform_defaults
+                .insert("ALPHA".to_string(), vec![1.0, 2.0]);
+            let err = build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap_err();
+            assert!(err.to_string().contains("ALPHA"), "{}", err);
+        }
+
+        #[cfg(feature = "naga")]
+        #[test]
+        fn test_build_ksh_naga_validation() {
+            let vs = "uniform mat4 MatrixP;\n\
+                attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() {\n\
+                PS_TEXCOORD = POSITION.xy;\n\
+                gl_Position = MatrixP * vec4(POSITION, 1.0);\n\
+                }\n";
+            let ps = "precision mediump float;\n\
+                uniform sampler2D SAMPLER[2];\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() {\n\
+                vec4 colour = texture2D(SAMPLER[0], PS_TEXCOORD);\n\
+                gl_FragColor = colour * texture2D(SAMPLER[1], PS_TEXCOORD);\n\
+                }\n";
+            let options = BuildOptions::default();
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+
+            // 调用未定义的函数
+            let bad_ps = ps.replace("colour * texture2D", "colour * sample2D");
+            let err = build_ksh("test", "test.vs", vs, "test.ps", &bad_ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.ps"));
+            assert_eq!(diagnostic.line, 6);
+
+            // 类型不匹配
+            let bad_vs = vs.replace("vec4(POSITION, 1.0)", "POSITION");
+            let err = build_ksh("test", "test.vs", &bad_vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.file.as_deref(), Some("test.vs"));
+            assert_eq!(diagnostic.line, 6);
+            assert!(diagnostic.column.is_some());
+
+            // 同一行中出错的表达式之前有宏展开, 不给出列号
+            let bad_vs = format!(
+                "#define SCALE 2.0\n{}",
+                vs.replace(
+                    "PS_TEXCOORD = POSITION.xy;",
+                    "PS_TEXCOORD = vec2(SCALE); PS_TEXCOORD = POSITION;"
+                )
+            );
+            let err = build_ksh("test", "test.vs", &bad_vs, "test.ps", ps, &options).unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.line, 6);
+            assert_eq!(diagnostic.column, None);
+
+            let options = BuildOptions {
+                skip_validation: true,
+                ..Default::default()
+            };
+            build_ksh("test", "test.vs", &bad_vs, "test.ps", ps, &options).unwrap();
+        }
+
+        #[cfg(feature = "naga")]
+        #[test]
+        fn test_build_ksh_vertex_without_position() {
+            use crate::check::check_shader;
+
+            // 没有写入 gl_Position 的顶点着色器可以构建, naga 的入口点错误只作为警告指向 main
+            let vs = "uniform mat4 MatrixP;\nvoid main() {}\n";
+            let ps = "void main() { gl_FragColor = vec4(1.0); }\n";
+            let options = BuildOptions::default();
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+
+            let diagnostics = check_shader(
+                ShaderStage::Vertex,
+                "test.vs",
+                vs,
+                Some(("test.ps", ps)),
+                &options,
+            );
+            let naga: Vec<_> = diagnostics
+                .iter()
+                .filter(|d| d.message.contains("Entry point"))
+                .collect();
+            assert_eq!(naga.len(), 1, "{:?}", diagnostics);
+            assert_eq!(naga[0].severity, Severity::Warning);
+            assert_eq!(naga[0].line, 2);
+
+            // 函数体中的类型错误仍然停止构建
+            let vs = "void main() { gl_Position = vec3(1.0); }\n";
+            assert!(build_ksh("test", "test.vs", vs, "test.ps", ps, &options).is_err());
+        }
+
+        #[cfg(feature = "naga")]
+        #[test]
+        fn test_build_ksh_sampler_array_index() {
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() { PS_TEXCOORD = POSITION.xy; gl_Position = vec4(POSITION, 1.0); }\n";
+            let build = |index: &str| {
+                let ps = format!(
+                    "precision mediump float;\n\
+                    uniform sampler2D SAMPLER[2];\n\
+                    varying vec2 PS_TEXCOORD;\n\
+                    const int LAST = 1;\n\
+                    void main() {{\n\
+                    gl_FragColor = texture2D(SAMPLER[{}], PS_TEXCOORD);\n\
+                    }}\n",
+                    index
+                );
+                build_ksh(
+                    "test",
+                    "test.vs",
+                    vs,
+                    "test.ps",
+                    &ps,
+                    &BuildOptions::default(),
+                )
+            };
+
+            // 常量表达式在转换前计算
+            build("1").unwrap();
+            build("LAST").unwrap();
+            build("LAST * 2 - 1").unwrap();
+            build("int(0)").unwrap();
+
+            // 动态下标无法转换, 跳过 naga 验证而不是停止构建
+            build("int(PS_TEXCOORD.x)").unwrap();
+
+            let err = build("LAST + 1").unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.line, 6);
+            assert!(diagnostic.message.contains("越界"), "{}", err);
+        }
+
+        #[cfg(feature = "naga")]
+        #[test]
+        fn test_build_ksh_sampler_function_parameter() {
+            use crate::check::check_shader;
+
+            // 把采样器传给函数是合法的 GLSL ES, naga 不支持时只警告一次并跳过该阶段的验证
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() { PS_TEXCOORD = POSITION.xy; gl_Position = vec4(POSITION, 1.0); }\n";
+            let ps = "precision mediump float;\n\
+                uniform sampler2D TEX;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                vec4 samp(sampler2D s, vec2 uv) { return texture2D(s, uv); }\n\
+                void main() { gl_FragColor = samp(TEX, PS_TEXCOORD); }\n";
+            let options = BuildOptions::default();
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+
+            let diagnostics = check_shader(
+                ShaderStage::Fragment,
+                "test.ps",
+                ps,
+                Some(("test.vs", vs)),
+                &options,
+            );
+            assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+            assert_eq!(diagnostics[0].severity, Severity::Warning);
+            assert_eq!(diagnostics[0].line, 4);
+            assert!(
+                diagnostics[0].message.contains("naga 无法验证此着色器"),
+                "{:?}",
+                diagnostics
+            );
+        }
+
+        #[cfg(feature = "naga")]
+        #[test]
+        fn test_build_ksh_sampler_loop_index() {
+            use crate::check::check_shader;
+
+            // 附录 A 允许用循环变量作为采样器下标, naga 无法验证时只警告
+            let vs = "attribute vec3 POSITION;\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() { PS_TEXCOORD = POSITION.xy; gl_Position = vec4(POSITION, 1.0); }\n";
+            let ps = "precision mediump float;\n\
+                uniform sampler2D SAMPLER[2];\n\
+                varying vec2 PS_TEXCOORD;\n\
+                void main() {\n\
+                vec4 colour = vec4(0.0);\n\
+                for (int i = 0; i < 2; i++) { colour += texture2D(SAMPLER[i], PS_TEXCOORD); }\n\
+                gl_FragColor = colour;\n\
+                }\n";
+            let options = BuildOptions {
+                es100: true,
+                ..Default::default()
+            };
+            build_ksh("test", "test.vs", vs, "test.ps", ps, &options).unwrap();
+
+            let diagnostics = check_shader(
+                ShaderStage::Fragment,
+                "test.ps",
+                ps,
+                Some(("test.vs", vs)),
+                &options,
+            );
+            assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+            assert_eq!(diagnostics[0].severity, Severity::Warning);
+            assert_eq!(diagnostics[0].line, 6);
+            assert!(
+                diagnostics[0].message.contains("SAMPLER[i]"),
+                "{:?}",
+                diagnostics
+            );
+        }
+
+        #[test]
+        fn test_build_ksh_with_include() {
+            let ctx = TestContext::new("test_build_ksh_with_include");
+            let test_dir = ctx.temp_dir.join("include_shader");
+            let lib_dir = ctx.temp_dir.join("include_lib");
+            fs::create_dir_all(&test_dir).expect("Failed to create test directory");
+            fs::create_dir_all(lib_dir.join("common")).expect("Failed to create lib directory");
+
+            fs::write(
+                lib_dir.join("common/tint.glsl"),
+                "uniform vec4 TINT;\nvec4 tint(vec4 c) { return c * TINT; }",
+            )
+            .expect("Failed to write include file");
+            let vs_content =
+                "attribute vec3 POSITION;\nvoid main() { gl_Position = vec4(POSITION, 1.0); }\n";
+            let ps_content =
+                "#include \"common/tint.glsl\"\nvoid main() { gl_FragColor = tint(vec4(1.0)); }\n";
+            fs::write(test_dir.join("test.vs"), vs_content).expect("Failed to write vs file");
+            fs::write(test_dir.join("test.ps"), ps_content).expect("Failed to write ps file");
+
+            let options = BuildOptions {
+                preprocess: PreprocessOptions {
+                    include_paths: vec![lib_dir.clone()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let out_path = ctx.temp_dir.join("include.ksh");
+            build_ksh_file_from_dir(&test_dir, &out_path, &options)
+                .expect("Failed to build ksh file");
+
+            let (_, _, _, ps) = analyze_ksh(&fs::read(&out_path).unwrap()).unwrap();
+            assert!(!ps.contains("#include"));
+            assert!(ps.contains("uniform vec4 TINT;\nvec4 tint"));
+
+            // 循环包含
+            fs::write(
+                lib_dir.join("common/tint.glsl"),
+                "#include \"common/tint.glsl\"\n",
+            )
+            .expect("Failed to write include file");
+            let err = build_ksh_file_from_dir(&test_dir, &out_path, &options).unwrap_err();
+            assert!(err.to_string().contains("循环包含"), "{}", err);
+            // 构建失败时不覆盖已有的输出, 也不创建新文件
+            assert!(analyze_ksh(&fs::read(&out_path).unwrap()).is_ok());
+            let new_path = ctx.temp_dir.join("include_failed.ksh");
+            build_ksh_file_from_dir(&test_dir, &new_path, &options).unwrap_err();
+            assert!(!new_path.exists());
+
+            ctx.cleanup();
+        }
+
+        #[test]
+        fn test_build_ksh_include_with_defines() {
+            let ctx = TestContext::new("test_build_ksh_include_with_defines");
+            fs::write(
+                ctx.temp_dir.join("bloom.glsl"),
+                "uniform vec4 BLOOM_TINT;\n",
+            )
+            .expect("Failed to write include file");
+            fs::write(ctx.temp_dir.join("plain.glsl"), "uniform vec4 TINT;\n")
+                .expect("Failed to write include file");
+            let vs = "void main() { gl_Position = vec4(0.0); }\n";
+            let ps = |other: &str| {
+                format!(
+                    "#ifdef BLOOM\n\
+                    #include \"bloom.glsl\"\n\
+                    void main() {{ gl_FragColor = BLOOM_TINT; }}\n\
+                    #else\n\
+                    #include \"{}\"\n\
+                    void main() {{ gl_FragColor = TINT; }}\n\
+                    #endif\n",
+                    other
+                )
+            };
+            let options = BuildOptions {
+                preprocess: PreprocessOptions {
+                    defines: vec![("BLOOM".to_string(), "1".to_string())],
+                    include_paths: vec![ctx.temp_dir.clone()],
+                },
+                ..Default::default()
+            };
+
+            // -D 不会写入 KSH, 游戏中启用的 #else 分支里的 #include 同样要展开
+            let ksh = build_ksh(
+                "test",
+                "test.vs",
+                vs,
+                "test.ps",
+                &ps("plain.glsl"),
+                &options,
+            )
+            .unwrap();
+            let (_, _, _, ps_code) = analyze_ksh(&ksh).unwrap();
+            assert!(!ps_code.contains("#include"), "{}", ps_code);
+            assert!(ps_code.contains("uniform vec4 TINT;"), "{}", ps_code);
+            assert!(ps_code.contains("uniform vec4 BLOOM_TINT;"), "{}", ps_code);
+
+            let err = build_ksh(
+                "test",
+                "test.vs",
+                vs,
+                "test.ps",
+                &ps("missing.glsl"),
+                &options,
+            )
+            .unwrap_err();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.line, 5);
+            assert!(diagnostic.message.contains("missing.glsl"), "{}", err);
+
+            ctx.cleanup();
+        }
+
+        #[test]
+        fn test_build_ksh_variants_from_dir() {
+            let ctx = TestContext::new("test_build_ksh_variants_from_dir");
+            let test_dir = ctx.temp_dir.join("variant_shader");
+            let out_dir = ctx.temp_dir.join("variant_output");
+            fs::create_dir_all(&test_dir).expect("Failed to create test directory");
+            fs::create_dir_all(&out_dir).expect("Failed to create output directory");
+
+            let vs_content = "uniform mat4 MatrixP;\nattribute vec3 POSITION;\n\
+                void main() { gl_Position = MatrixP * vec4(POSITION, 1.0); }\n";
+            let ps_content = "#version 100\n\
+                #ifdef BLOOM\n\
+                uniform vec4 BLOOM_PARAMS;\n\
+                #endif\n\
+                void main() {\n\
+                #ifdef BLOOM\n\
+                gl_FragColor = BLOOM_PARAMS;\n\
+                #else\n\
+                gl_FragColor = vec4(1.0);\n\
+                #endif\n\
+                }\n";
+            fs::write(test_dir.join("anim.vs"), vs_content).expect("Failed to write vs file");
+            fs::write(test_dir.join("anim.ps"), ps_content).expect("Failed to write ps file");
+            fs::write(
+                test_dir.join("ksh.json"),
+                r#"{ "variants": [{ "name": "anim" }, { "name": "anim_bloom", "defines": ["BLOOM"] }] }"#,
+            )
+            .expect("Failed to write manifest");
+
+            let manifest = Manifest::load(&test_dir)
+                .unwrap()
+                .expect("Manifest should exist");
+            let outputs = build_ksh_variants_from_dir(
+                &test_dir,
+                &out_dir,
+                &manifest,
+                &BuildOptions::default(),
+            )
+            .expect("Failed to build variants");
+            assert_eq!(
+                outputs,
+                [out_dir.join("anim.ksh"), out_dir.join("anim_bloom.ksh")]
+            );
+
+            // 文件名之后是 uniform 数量
+            let uniform_count = |path: &Path| {
+                let content = fs::read(path).unwrap();
+                let mut cursor = std::io::Cursor::new(content.as_slice());
+                read_string(&mut cursor).unwrap();
+                read_u32(&mut cursor).unwrap()
+            };
+            assert_eq!(uniform_count(&outputs[0]), 1);
+            assert_eq!(uniform_count(&outputs[1]), 2);
+
+            let (_, _, _, ps) = analyze_ksh(&fs::read(&outputs[1]).unwrap()).unwrap();
+            assert!(ps.starts_with("#version 100\n#define BLOOM 1\n#line 1\n"));
+
+            ctx.cleanup();
+        }
     }
 
     // KSH 分析测试
     mod analyze_tests {
         use super::*;
 
+        #[test]
+        fn test_analyze_ksh_corrupt() {
+            let vs = "uniform mat4 MatrixP;\nattribute vec3 POSITION;\n\
+                void main() { gl_Position = MatrixP * vec4(POSITION, 1.0); }\n";
+            let ps = "uniform vec4 TINT;\nvoid main() { gl_FragColor = TINT; }\n";
+            let ksh = build_ksh(
+                "test",
+                "test.vs",
+                vs,
+                "test.ps",
+                ps,
+                &BuildOptions::default(),
+            )
+            .unwrap();
+            assert!(analyze_ksh(&ksh).is_ok());
+
+            // 任意位置截断都返回错误而不是 panic
+            for length in 0..ksh.len() - 8 {
+                assert!(
+                    analyze_ksh(&ksh[..length]).is_err(),
+                    "截断到 {} 字节",
+                    length
+                );
+            }
+
+            // 字符串长度超过文件大小
+            let mut corrupt = ksh.clone();
+            corrupt[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let err = analyze_ksh(&corrupt).unwrap_err().to_string();
+            assert!(err.contains("KSH 文件不完整"), "{}", err);
+
+            // 像素着色器引用的 uniform 下标越界
+            let mut corrupt = ksh.clone();
+            let last = corrupt.len() - 4;
+            corrupt[last..].copy_from_slice(&7u32.to_le_bytes());
+            let err = analyze_ksh(&corrupt).unwrap_err().to_string();
+            assert!(err.contains("uniform 下标 7 越界"), "{}", err);
+
+            // 未知的类型编号
+            let mut corrupt = ksh.clone();
+            let type_offset = 4 + 4 + 4 + 4 + "MatrixP".len() + 4;
+            corrupt[type_offset..type_offset + 4].copy_from_slice(&99u32.to_le_bytes());
+            let err = analyze_ksh(&corrupt).unwrap_err().to_string();
+            assert!(err.contains("uniform MatrixP 的类型 99 无效"), "{}", err);
+        }
+
         #[test]
         fn test_analyze_ksh_file() {
-            let ctx = TestContext::new();
+            let ctx = TestContext::new("test_analyze_ksh_file");
             let ksh_file = Path::new("C:\\Saved Games\\Steam\\steamapps\\common\\Don't Starve Together\\data\\databundles\\shaders\\anim_bloom_haunted.ksh");
             let out_dir = ctx.temp_dir.join("analyze_output");
             fs::create_dir_all(&out_dir).expect("Failed to create temp directory");
@@ -416,7 +1489,7 @@ mod tests {
 
         #[test]
         fn test_full_conversion() {
-            let ctx = TestContext::new();
+            let ctx = TestContext::new("test_full_conversion");
             let folder_path = Path::new("C:\\Saved Games\\Steam\\steamapps\\common\\Don't Starve Together\\data\\databundles\\shaders");
 
             for entry in read_dir(folder_path).expect("Failed to read directory") {
@@ -435,7 +1508,7 @@ mod tests {
 
                     analyze_ksh_file(&path, &ps_vs_dir, true).expect("Failed to analyze ksh file");
                     let temp_dir = ctx.temp_dir.join(file_name);
-                    build_ksh_file_from_dir(&ps_vs_dir, &temp_dir)
+                    build_ksh_file_from_dir(&ps_vs_dir, &temp_dir, &BuildOptions::default())
                         .expect("Failed to build ksh file");
 
                     ctx.assert_files_equal(&path, &temp_dir);
@@ -0,0 +1,105 @@
+use serde::Serialize;
+use std::fmt;
+
+/// 诊断的严重程度
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "错误"),
+            Severity::Warning => write!(f, "警告"),
+        }
+    }
+}
+
+/// 指向源码位置的诊断信息, 行号和列号都从 1 开始
+///
+/// 命令行中显示为带 `^` 标记的代码片段, 图形界面中序列化为 JSON 后在编辑器里标出位置.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    /// 所在文件, 未知时为 None
+    pub file: Option<String>,
+    pub line: usize,
+    /// 所在列, 未知时为 None
+    pub column: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+    /// 出错的那一行代码, 用于显示代码片段
+    #[serde(skip)]
+    pub source_line: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            file: None,
+            line,
+            column: None,
+            severity: Severity::Error,
+            message: message.into(),
+            source_line: None,
+        }
+    }
+
+    pub fn warning(line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(line, message)
+        }
+    }
+
+    pub fn with_column(mut self, column: usize) -> Self {
+        self.column = Some(column);
+        self
+    }
+
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_source_line(mut self, source_line: &str) -> Self {
+        self.source_line = Some(source_line.trim_end().to_string());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:", self.line)?;
+        if let Some(column) = self.column {
+            write!(f, "{}:", column)?;
+        }
+        write!(f, " {}: {}", self.severity, self.message)?;
+
+        // 代码片段, 如:
+        //   12 | gl_FragColor = vec4(x, 1.0)
+        //      |                           ^
+        if let Some(source_line) = &self.source_line {
+            let number = self.line.to_string();
+            let gutter = " ".repeat(number.len());
+            write!(f, "\n {} | {}", number, source_line.trim_end())?;
+            if let Some(column) = self.column {
+                // 制表符原样保留, 保证 ^ 与代码对齐
+                let padding: String = source_line
+                    .chars()
+                    .take(column.saturating_sub(1))
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(f, "\n {} | {}^", gutter, padding)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
@@ -0,0 +1,254 @@
+use crate::diagnostic::Diagnostic;
+use crate::types::{Variable, VariableType};
+
+/// 引擎 uniform 的数组形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArrayShape {
+    /// 不是数组
+    Scalar,
+    /// 任意长度的数组, 如 `SAMPLER[n]`
+    AnyLength,
+}
+
+/// 饥荒联机版渲染器会自动赋值的 uniform
+#[derive(Debug)]
+pub struct EngineUniform {
+    pub name: &'static str,
+    /// 可接受的类型, 各着色器自行决定类型的 uniform (如 `PARAMS`) 有多个
+    pub types: &'static [VariableType],
+    pub array: ArrayShape,
+    pub description: &'static str,
+}
+
+/// 引擎 uniform 目录
+pub const ENGINE_UNIFORMS: &[EngineUniform] = &[
+    EngineUniform {
+        name: "MatrixP",
+        types: &[VariableType::Mat4],
+        array: ArrayShape::Scalar,
+        description: "投影矩阵",
+    },
+    EngineUniform {
+        name: "MatrixV",
+        types: &[VariableType::Mat4],
+        array: ArrayShape::Scalar,
+        description: "视图矩阵",
+    },
+    EngineUniform {
+        name: "MatrixW",
+        types: &[VariableType::Mat4],
+        array: ArrayShape::Scalar,
+        description: "世界矩阵",
+    },
+    EngineUniform {
+        name: "TIMEPARAMS",
+        types: &[VariableType::Vec4],
+        array: ArrayShape::Scalar,
+        description: "时间参数",
+    },
+    EngineUniform {
+        name: "FLOAT_PARAMS",
+        types: &[VariableType::Vec3],
+        array: ArrayShape::Scalar,
+        description: "通用浮点参数",
+    },
+    EngineUniform {
+        name: "PARAMS",
+        types: &[
+            VariableType::Float,
+            VariableType::Vec2,
+            VariableType::Vec3,
+            VariableType::Vec4,
+        ],
+        array: ArrayShape::Scalar,
+        description: "通用参数, 如 anim 着色器中的 alpha 测试阈值和光照覆盖",
+    },
+    EngineUniform {
+        name: "COLOUR_XFORM",
+        types: &[VariableType::Mat4],
+        array: ArrayShape::Scalar,
+        description: "颜色变换矩阵 (乘色与加色)",
+    },
+    EngineUniform {
+        name: "SAMPLER",
+        types: &[VariableType::Sampler2D],
+        array: ArrayShape::AnyLength,
+        description: "纹理采样器, 下标对应绑定的纹理单元",
+    },
+    EngineUniform {
+        name: "LIGHTMAP_WORLD_EXTENTS",
+        types: &[VariableType::Vec4],
+        array: ArrayShape::Scalar,
+        description: "光照贴图覆盖的世界范围",
+    },
+    EngineUniform {
+        name: "OCEAN_BLEND_PARAMS",
+        types: &[VariableType::Vec4],
+        array: ArrayShape::Scalar,
+        description: "海洋混合参数",
+    },
+    EngineUniform {
+        name: "OCEAN_WORLD_EXTENTS",
+        types: &[VariableType::Vec4],
+        array: ArrayShape::Scalar,
+        description: "海洋覆盖的世界范围",
+    },
+    EngineUniform {
+        name: "EROSION_PARAMS",
+        types: &[VariableType::Vec3],
+        array: ArrayShape::Scalar,
+        description: "侵蚀效果参数",
+    },
+];
+
+impl EngineUniform {
+    /// 声明形式, 如 `uniform sampler2D SAMPLER[]`, 有多个可接受类型时用 `/` 分隔
+    pub fn declaration(&self) -> String {
+        let array = match self.array {
+            ArrayShape::Scalar => "",
+            ArrayShape::AnyLength => "[]",
+        };
+        format!("uniform {} {}{}", self.type_names(), self.name, array)
+    }
+
+    /// 可接受的类型名, 如 `float/vec2/vec3/vec4`
+    pub fn type_names(&self) -> String {
+        self.types
+            .iter()
+            .map(|t| t.name())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn matches(&self, variable: &Variable) -> bool {
+        let type_matches = variable
+            .variable_type()
+            .is_some_and(|t| self.types.contains(&t));
+        let array_matches = match self.array {
+            ArrayShape::Scalar => variable.array_length.is_none(),
+            ArrayShape::AnyLength => variable.array_length.is_some(),
+        };
+        type_matches && array_matches
+    }
+}
+
+/// 按名称查找引擎 uniform
+pub fn find_engine_uniform(name: &str) -> Option<&'static EngineUniform> {
+    ENGINE_UNIFORMS.iter().find(|u| u.name == name)
+}
+
+/// 检查着色器中的 uniform: 引擎 uniform 的类型是否正确, 以及疑似引擎 uniform 拼写错误的名称.
+/// 返回的都是警告, 行号为 uniform 的声明所在行
+pub fn check_engine_uniforms(uniforms: &[&Variable]) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for uniform in uniforms {
+        let line = uniform.line.unwrap_or(1);
+        if let Some(engine_uniform) = find_engine_uniform(&uniform.name) {
+            if !engine_uniform.matches(uniform) {
+                diagnostics.push(Diagnostic::warning(
+                    line,
+                    format!(
+                        "引擎 uniform {} 的声明应为 {}, 实际为 uniform {}",
+                        uniform.name,
+                        engine_uniform.declaration(),
+                        uniform.declaration()
+                    ),
+                ));
+            }
+        } else if let Some(suggestion) = suggest_engine_uniform(&uniform.name) {
+            diagnostics.push(Diagnostic::warning(
+                line,
+                format!(
+                    "uniform {} 不会被引擎赋值, 是否应为 {}?",
+                    uniform.name, suggestion.name
+                ),
+            ));
+        }
+    }
+    diagnostics
+}
+
+// 查找与名称相近的引擎 uniform, 如 `MatrixWW` -> `MatrixW`, `timeparams` -> `TIMEPARAMS`
+fn suggest_engine_uniform(name: &str) -> Option<&'static EngineUniform> {
+    ENGINE_UNIFORMS
+        .iter()
+        .map(|u| {
+            let distance = if u.name.eq_ignore_ascii_case(name) {
+                0
+            } else {
+                edit_distance(u.name, name)
+            };
+            (u, distance)
+        })
+        .filter(|(u, distance)| *distance <= 2 && *distance * 3 < u.name.len())
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(u, _)| u)
+}
+
+// 编辑距离 (Levenshtein)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_parser::parse_glsl_uniforms;
+    use crate::preprocessor::PreprocessOptions;
+
+    #[test]
+    fn test_check_engine_uniforms() {
+        let content = "uniform mat4 MatrixP;\n\
+            uniform mat4 MatrixWW;\n\
+            uniform vec3 TIMEPARAMS;\n\
+            uniform sampler2D SAMPLER[2];\n\
+            uniform vec4 TINT;\n\
+            void main() { gl_FragColor = MatrixP * MatrixWW * vec4(TIMEPARAMS, 1.0) \
+            + texture2D(SAMPLER[1], vec2(0.0)) * TINT; }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        let uniforms: Vec<&Variable> = uniforms.iter().collect();
+        let diagnostics = check_engine_uniforms(&uniforms);
+        let summary: Vec<_> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(summary, [2, 3]);
+        assert!(diagnostics[0].message.contains("MatrixW?"));
+        assert!(diagnostics[1].message.contains("vec4"));
+    }
+
+    #[test]
+    fn test_params_accepts_vectors() {
+        for declaration in [
+            "uniform float PARAMS;",
+            "uniform vec2 PARAMS;",
+            "uniform vec4 PARAMS;",
+        ] {
+            let content = format!(
+                "{}\nvoid main() {{ gl_FragColor = vec4(PARAMS); }}\n",
+                declaration
+            );
+            let uniforms = parse_glsl_uniforms(&content, &PreprocessOptions::default()).unwrap();
+            let uniforms: Vec<&Variable> = uniforms.iter().collect();
+            assert!(
+                check_engine_uniforms(&uniforms).is_empty(),
+                "{}",
+                declaration
+            );
+        }
+
+        let content = "uniform mat4 PARAMS;\nvoid main() { gl_FragColor = PARAMS[0]; }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        let uniforms: Vec<&Variable> = uniforms.iter().collect();
+        let diagnostics = check_engine_uniforms(&uniforms);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("float/vec2/vec3/vec4"));
+    }
+}
@@ -0,0 +1,873 @@
+use crate::diagnostic::Diagnostic;
+use crate::glsl_parser::ParsedShader;
+use crate::types::{glsl_type_name, ShaderStage};
+use glsl_lang::ast;
+use std::collections::HashSet;
+
+// GLSL ES 1.00 中不存在的内置函数
+const UNSUPPORTED_FUNCTIONS: &[&str] = &[
+    "texture",
+    "textureProj",
+    "textureLod",
+    "textureOffset",
+    "textureProjOffset",
+    "textureLodOffset",
+    "textureProjLod",
+    "textureProjLodOffset",
+    "textureGrad",
+    "textureGradOffset",
+    "textureProjGrad",
+    "textureProjGradOffset",
+    "textureSize",
+    "textureGather",
+    "texelFetch",
+    "texelFetchOffset",
+    "round",
+    "roundEven",
+    "trunc",
+    "modf",
+    "isnan",
+    "isinf",
+    "sinh",
+    "cosh",
+    "tanh",
+    "asinh",
+    "acosh",
+    "atanh",
+    "fma",
+    "frexp",
+    "ldexp",
+    "transpose",
+    "determinant",
+    "inverse",
+    "outerProduct",
+    "floatBitsToInt",
+    "floatBitsToUint",
+    "intBitsToFloat",
+    "uintBitsToFloat",
+    "packSnorm2x16",
+    "unpackSnorm2x16",
+    "packUnorm2x16",
+    "unpackUnorm2x16",
+    "packHalf2x16",
+    "unpackHalf2x16",
+];
+
+// GLSL ES 1.00 中只能在顶点着色器中使用的内置函数
+const VERTEX_ONLY_FUNCTIONS: &[&str] = &["texture2DLod", "texture2DProjLod", "textureCubeLod"];
+
+/// 按 GLSL ES 1.00 及其附录 A 的限制检查着色器.
+///
+/// glsl_lang 可以解析桌面 GLSL, 这里标出 ES 1.00 中不存在的语法, 类型和内置函数,
+/// 以及附录 A 中的限制: 只允许固定形式的 for 循环, 采样器 (以及像素着色器中的 uniform 数组)
+/// 只能用常量或循环变量作为下标.
+pub fn check_es100(shader: &ParsedShader, stage: ShaderStage) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        shader,
+        stage,
+        consts: HashSet::new(),
+        samplers: HashSet::new(),
+        uniform_arrays: HashSet::new(),
+        loop_indices: vec![],
+        diagnostics: vec![],
+    };
+    for decl in &shader.declarations {
+        match &decl.content {
+            ast::ExternalDeclarationData::Preprocessor(p) => {
+                if let ast::PreprocessorData::Version(version) = &p.content {
+                    if version.version != 100 {
+                        checker.error(
+                            p.span,
+                            format!(
+                                "#version {} 不是 GLSL ES 1.00, 应为 #version 100",
+                                version.version
+                            ),
+                        );
+                    }
+                }
+            }
+            ast::ExternalDeclarationData::Declaration(decl) => {
+                checker.check_declaration(decl, true)
+            }
+            ast::ExternalDeclarationData::FunctionDefinition(f) => {
+                checker.check_function_prototype(&f.content.prototype);
+                for stmt in &f.content.statement.content.statement_list {
+                    checker.check_statement(stmt);
+                }
+            }
+        }
+    }
+    checker.diagnostics
+}
+
+struct Checker<'a> {
+    shader: &'a ParsedShader,
+    stage: ShaderStage,
+    /// const 变量, 用于判断常量表达式
+    consts: HashSet<String>,
+    /// 采样器变量
+    samplers: HashSet<String>,
+    /// uniform 数组
+    uniform_arrays: HashSet<String>,
+    /// 当前所在的 for 循环的循环变量
+    loop_indices: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, span: Option<ast::NodeSpan>, message: String) {
+        let line = self.shader.line_of(span);
+        self.diagnostics.push(Diagnostic::error(
+            line,
+            format!("GLSL ES 1.00: {}", message),
+        ));
+    }
+
+    fn check_declaration(&mut self, decl: &ast::Declaration, global: bool) {
+        match &decl.content {
+            ast::DeclarationData::InitDeclaratorList(list) => {
+                let head = &list.content.head;
+                self.check_fully_specified_type(&head.ty, global, decl.span);
+
+                let qualifiers = head.ty.qualifier.iter().flat_map(|q| &q.qualifiers);
+                let storage: Vec<&ast::StorageQualifierData> = qualifiers
+                    .filter_map(|q| match &q.content {
+                        ast::TypeQualifierSpecData::Storage(s) => Some(&s.content),
+                        _ => None,
+                    })
+                    .collect();
+                let is_const = storage.contains(&&ast::StorageQualifierData::Const);
+                let is_uniform = storage.contains(&&ast::StorageQualifierData::Uniform);
+                let is_sampler = matches!(
+                    head.ty.ty.ty.content,
+                    ast::TypeSpecifierNonArrayData::Sampler2D
+                        | ast::TypeSpecifierNonArrayData::SamplerCube
+                );
+
+                let head_declarator = head
+                    .name
+                    .as_ref()
+                    .map(|n| (n, head.array_specifier.as_ref(), head.initializer.as_ref()));
+                let tail_declarators = list.content.tail.iter().map(|d| {
+                    (
+                        &d.ident.ident,
+                        d.ident.array_spec.as_ref(),
+                        d.initializer.as_ref(),
+                    )
+                });
+                for (name, array_specifier, initializer) in
+                    head_declarator.into_iter().chain(tail_declarators)
+                {
+                    let name = name.content.as_str().to_string();
+                    if let Some(array_specifier) = array_specifier {
+                        self.check_array_specifier(array_specifier);
+                        if is_uniform {
+                            self.uniform_arrays.insert(name.clone());
+                        }
+                    }
+                    if let Some(initializer) = initializer {
+                        if global && !is_const && !self.is_initializer_constant(initializer) {
+                            self.error(
+                                decl.span,
+                                format!("全局变量 {} 的初始值必须是常量表达式", name),
+                            );
+                        }
+                        self.check_initializer(initializer);
+                    }
+                    if is_const {
+                        self.consts.insert(name.clone());
+                    }
+                    if is_sampler {
+                        self.samplers.insert(name);
+                    }
+                }
+            }
+            ast::DeclarationData::FunctionPrototype(prototype) => {
+                self.check_function_prototype(prototype)
+            }
+            ast::DeclarationData::Block(_) => {
+                self.error(decl.span, "不支持接口块".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    fn check_function_prototype(&mut self, prototype: &ast::FunctionPrototype) {
+        self.check_type_specifier(&prototype.ty.ty, prototype.span);
+        for parameter in &prototype.parameters {
+            match &parameter.content {
+                ast::FunctionParameterDeclarationData::Named(_, declarator) => {
+                    self.check_type_specifier(&declarator.ty, parameter.span);
+                    if let Some(array_specifier) = &declarator.ident.array_spec {
+                        self.check_array_specifier(array_specifier);
+                    }
+                }
+                ast::FunctionParameterDeclarationData::Unnamed(_, ty) => {
+                    self.check_type_specifier(ty, parameter.span);
+                }
+            }
+        }
+    }
+
+    fn check_fully_specified_type(
+        &mut self,
+        ty: &ast::FullySpecifiedType,
+        global: bool,
+        span: Option<ast::NodeSpan>,
+    ) {
+        for qualifier in ty.qualifier.iter().flat_map(|q| &q.qualifiers) {
+            let unsupported = match &qualifier.content {
+                ast::TypeQualifierSpecData::Storage(s) => match &s.content {
+                    ast::StorageQualifierData::Const
+                    | ast::StorageQualifierData::Uniform
+                    | ast::StorageQualifierData::Attribute
+                    | ast::StorageQualifierData::Varying => None,
+                    // 函数参数中的 in/out/inout 是合法的, 全局只能用 attribute/varying
+                    ast::StorageQualifierData::In if global => {
+                        Some("in, 应使用 attribute 或 varying")
+                    }
+                    ast::StorageQualifierData::Out if global => {
+                        Some("out, 应使用 varying 或 gl_FragColor")
+                    }
+                    ast::StorageQualifierData::In
+                    | ast::StorageQualifierData::Out
+                    | ast::StorageQualifierData::InOut => None,
+                    ast::StorageQualifierData::Centroid => Some("centroid"),
+                    ast::StorageQualifierData::Sample => Some("sample"),
+                    ast::StorageQualifierData::Patch => Some("patch"),
+                    ast::StorageQualifierData::Buffer => Some("buffer"),
+                    ast::StorageQualifierData::Shared => Some("shared"),
+                    ast::StorageQualifierData::Subroutine(_) => Some("subroutine"),
+                    _ => Some("内存限定符"),
+                },
+                ast::TypeQualifierSpecData::Layout(_) => Some("layout"),
+                ast::TypeQualifierSpecData::Interpolation(_) => Some("插值限定符"),
+                ast::TypeQualifierSpecData::Precise => Some("precise"),
+                ast::TypeQualifierSpecData::Precision(_)
+                | ast::TypeQualifierSpecData::Invariant => None,
+            };
+            if let Some(unsupported) = unsupported {
+                self.error(span, format!("不支持限定符 {}", unsupported));
+            }
+        }
+        self.check_type_specifier(&ty.ty, span);
+    }
+
+    fn check_type_specifier(&mut self, ty: &ast::TypeSpecifier, span: Option<ast::NodeSpan>) {
+        let supported = matches!(
+            &ty.ty.content,
+            ast::TypeSpecifierNonArrayData::Void
+                | ast::TypeSpecifierNonArrayData::Bool
+                | ast::TypeSpecifierNonArrayData::Int
+                | ast::TypeSpecifierNonArrayData::Float
+                | ast::TypeSpecifierNonArrayData::Vec2
+                | ast::TypeSpecifierNonArrayData::Vec3
+                | ast::TypeSpecifierNonArrayData::Vec4
+                | ast::TypeSpecifierNonArrayData::BVec2
+                | ast::TypeSpecifierNonArrayData::BVec3
+                | ast::TypeSpecifierNonArrayData::BVec4
+                | ast::TypeSpecifierNonArrayData::IVec2
+                | ast::TypeSpecifierNonArrayData::IVec3
+                | ast::TypeSpecifierNonArrayData::IVec4
+                | ast::TypeSpecifierNonArrayData::Mat2
+                | ast::TypeSpecifierNonArrayData::Mat3
+                | ast::TypeSpecifierNonArrayData::Mat4
+                | ast::TypeSpecifierNonArrayData::Sampler2D
+                | ast::TypeSpecifierNonArrayData::SamplerCube
+                | ast::TypeSpecifierNonArrayData::TypeName(_)
+        );
+        if let ast::TypeSpecifierNonArrayData::Struct(s) = &ty.ty.content {
+            for field in &s.fields {
+                self.check_type_specifier(&field.ty, field.span);
+            }
+        } else if !supported {
+            let name = glsl_type_name(&ty.ty.content);
+            self.error(span, format!("不支持类型 {}", name));
+        }
+        if ty.array_specifier.is_some() {
+            self.error(span, "数组长度必须写在变量名之后".to_string());
+        }
+    }
+
+    fn check_array_specifier(&mut self, array_specifier: &ast::ArraySpecifier) {
+        if array_specifier.dimensions.len() > 1 {
+            self.error(array_specifier.span, "不支持多维数组".to_string());
+        }
+        for dimension in &array_specifier.dimensions {
+            match &dimension.content {
+                ast::ArraySpecifierDimensionData::Unsized => {
+                    self.error(dimension.span, "数组必须指定长度".to_string())
+                }
+                ast::ArraySpecifierDimensionData::ExplicitlySized(size) => {
+                    if !self.is_constant(size) {
+                        self.error(size.span, "数组长度必须是常量表达式".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_initializer(&mut self, initializer: &ast::Initializer) {
+        match &initializer.content {
+            ast::InitializerData::Simple(expr) => self.check_expr(expr),
+            ast::InitializerData::List(_) => {
+                self.error(initializer.span, "不支持初始化列表 { ... }".to_string())
+            }
+        }
+    }
+
+    fn is_initializer_constant(&self, initializer: &ast::Initializer) -> bool {
+        match &initializer.content {
+            ast::InitializerData::Simple(expr) => self.is_constant(expr),
+            ast::InitializerData::List(list) => {
+                list.iter().all(|i| self.is_initializer_constant(i))
+            }
+        }
+    }
+
+    fn check_statement(&mut self, stmt: &ast::Statement) {
+        match &stmt.content {
+            ast::StatementData::Declaration(decl) => self.check_declaration(decl, false),
+            ast::StatementData::Expression(expr) => {
+                if let Some(expr) = &expr.0 {
+                    self.check_expr(expr);
+                }
+            }
+            ast::StatementData::Selection(selection) => {
+                self.check_expr(&selection.cond);
+                match &selection.rest.content {
+                    ast::SelectionRestStatementData::Statement(stmt) => self.check_statement(stmt),
+                    ast::SelectionRestStatementData::Else(if_stmt, else_stmt) => {
+                        self.check_statement(if_stmt);
+                        self.check_statement(else_stmt);
+                    }
+                }
+            }
+            ast::StatementData::Switch(_) => {
+                self.error(stmt.span, "不支持 switch 语句".to_string());
+            }
+            ast::StatementData::CaseLabel(_) => {}
+            ast::StatementData::Iteration(iteration) => match &iteration.content {
+                ast::IterationStatementData::While(..) => {
+                    self.error(
+                        stmt.span,
+                        "附录 A 只允许 for 循环, 不支持 while".to_string(),
+                    );
+                }
+                ast::IterationStatementData::DoWhile(..) => {
+                    self.error(
+                        stmt.span,
+                        "附录 A 只允许 for 循环, 不支持 do-while".to_string(),
+                    );
+                }
+                ast::IterationStatementData::For(init, rest, body) => {
+                    let index = self.check_for_header(init, rest, stmt.span);
+                    let pushed = index.is_some();
+                    self.loop_indices.extend(index);
+                    self.check_statement(body);
+                    if pushed {
+                        self.loop_indices.pop();
+                    }
+                }
+            },
+            ast::StatementData::Jump(jump) => {
+                if let ast::JumpStatementData::Return(Some(expr)) = &jump.content {
+                    self.check_expr(expr);
+                }
+            }
+            ast::StatementData::Compound(compound) => {
+                for stmt in &compound.statement_list {
+                    self.check_statement(stmt);
+                }
+            }
+        }
+    }
+
+    // 附录 A: for (type-specifier index = 常量; index 关系运算符 常量; index++ / index-- / index += 常量 / index -= 常量)
+    // 返回循环变量名
+    fn check_for_header(
+        &mut self,
+        init: &ast::ForInitStatement,
+        rest: &ast::ForRestStatement,
+        span: Option<ast::NodeSpan>,
+    ) -> Option<String> {
+        let index = match &init.content {
+            ast::ForInitStatementData::Declaration(decl) => match &decl.content {
+                ast::DeclarationData::InitDeclaratorList(list)
+                    if list.content.tail.is_empty()
+                        && list.content.head.array_specifier.is_none() =>
+                {
+                    let head = &list.content.head;
+                    let constant_init = match head.initializer.as_ref().map(|i| &i.content) {
+                        Some(ast::InitializerData::Simple(expr)) => self.is_constant(expr),
+                        _ => false,
+                    };
+                    head.name
+                        .as_ref()
+                        .filter(|_| constant_init)
+                        .map(|n| n.content.as_str().to_string())
+                }
+                _ => None,
+            },
+            ast::ForInitStatementData::Expression(_) => None,
+        };
+        let Some(index) = index else {
+            self.error(
+                span,
+                "for 循环必须以 `int i = 常量` 的形式声明唯一的循环变量".to_string(),
+            );
+            return None;
+        };
+
+        let is_index = |expr: &ast::Expr| match &expr.content {
+            ast::ExprData::Variable(name) => name.content.as_str() == index,
+            _ => false,
+        };
+        let condition_valid = match rest.condition.as_ref().map(|c| &c.content) {
+            Some(ast::ConditionData::Expr(expr)) => match &expr.content {
+                ast::ExprData::Binary(op, left, right) => {
+                    matches!(
+                        op.content,
+                        ast::BinaryOpData::Lt
+                            | ast::BinaryOpData::Gt
+                            | ast::BinaryOpData::Lte
+                            | ast::BinaryOpData::Gte
+                            | ast::BinaryOpData::Equal
+                            | ast::BinaryOpData::NonEqual
+                    ) && is_index(left)
+                        && self.is_constant(right)
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if !condition_valid {
+            self.error(
+                span,
+                format!("for 循环的条件必须是 `{} 关系运算符 常量表达式`", index),
+            );
+        }
+
+        let step_valid = match rest.post_expr.as_ref().map(|e| &e.content) {
+            Some(ast::ExprData::PostInc(expr) | ast::ExprData::PostDec(expr)) => is_index(expr),
+            Some(ast::ExprData::Unary(op, expr)) => {
+                matches!(op.content, ast::UnaryOpData::Inc | ast::UnaryOpData::Dec)
+                    && is_index(expr)
+            }
+            Some(ast::ExprData::Assignment(left, op, right)) => {
+                matches!(
+                    op.content,
+                    ast::AssignmentOpData::Add | ast::AssignmentOpData::Sub
+                ) && is_index(left)
+                    && self.is_constant(right)
+            }
+            _ => false,
+        };
+        if !step_valid {
+            self.error(
+                span,
+                format!(
+                    "for 循环的步进必须是 {0}++, {0}--, {0} += 常量 或 {0} -= 常量",
+                    index
+                ),
+            );
+        }
+        Some(index)
+    }
+
+    fn check_expr(&mut self, expr: &ast::Expr) {
+        match &expr.content {
+            ast::ExprData::UIntConst(_) => {
+                self.error(expr.span, "不支持无符号整数".to_string());
+            }
+            ast::ExprData::DoubleConst(_) => {
+                self.error(expr.span, "不支持 double".to_string());
+            }
+            ast::ExprData::Variable(_)
+            | ast::ExprData::IntConst(_)
+            | ast::ExprData::BoolConst(_)
+            | ast::ExprData::FloatConst(_) => {}
+            ast::ExprData::Unary(op, operand) => {
+                if op.content == ast::UnaryOpData::Complement {
+                    self.error(expr.span, "运算符 ~ 是保留的".to_string());
+                }
+                if matches!(op.content, ast::UnaryOpData::Inc | ast::UnaryOpData::Dec) {
+                    self.check_loop_index_write(operand);
+                }
+                self.check_expr(operand);
+            }
+            ast::ExprData::Binary(op, left, right) => {
+                let reserved = match op.content {
+                    ast::BinaryOpData::Mod => Some("%"),
+                    ast::BinaryOpData::BitAnd => Some("&"),
+                    ast::BinaryOpData::BitOr => Some("|"),
+                    ast::BinaryOpData::BitXor => Some("^"),
+                    ast::BinaryOpData::LShift => Some("<<"),
+                    ast::BinaryOpData::RShift => Some(">>"),
+                    _ => None,
+                };
+                if let Some(reserved) = reserved {
+                    self.error(
+                        expr.span,
+                        format!("运算符 {} 是保留的, 可以使用 mod() 等内置函数", reserved),
+                    );
+                }
+                self.check_expr(left);
+                self.check_expr(right);
+            }
+            ast::ExprData::Ternary(cond, yes, no) => {
+                self.check_expr(cond);
+                self.check_expr(yes);
+                self.check_expr(no);
+            }
+            ast::ExprData::Assignment(left, op, right) => {
+                let reserved = match op.content {
+                    ast::AssignmentOpData::Mod => Some("%="),
+                    ast::AssignmentOpData::LShift => Some("<<="),
+                    ast::AssignmentOpData::RShift => Some(">>="),
+                    ast::AssignmentOpData::And => Some("&="),
+                    ast::AssignmentOpData::Xor => Some("^="),
+                    ast::AssignmentOpData::Or => Some("|="),
+                    _ => None,
+                };
+                if let Some(reserved) = reserved {
+                    self.error(expr.span, format!("运算符 {} 是保留的", reserved));
+                }
+                self.check_loop_index_write(left);
+                self.check_expr(left);
+                self.check_expr(right);
+            }
+            ast::ExprData::Bracket(array, index) => {
+                if let ast::ExprData::Variable(name) = &array.content {
+                    let name = name.content.as_str();
+                    let restricted = self.samplers.contains(name)
+                        || (self.stage == ShaderStage::Fragment
+                            && self.uniform_arrays.contains(name));
+                    if restricted && !self.is_constant_index(index) {
+                        self.error(
+                            expr.span,
+                            format!("{} 的下标只能是常量表达式或循环变量 (附录 A)", name),
+                        );
+                    }
+                }
+                self.check_expr(array);
+                self.check_expr(index);
+            }
+            ast::ExprData::FunCall(fun, args) => {
+                match &fun.content {
+                    ast::FunIdentifierData::TypeSpecifier(ty) => {
+                        if let ast::TypeSpecifierNonArrayData::TypeName(name) = &ty.ty.content {
+                            self.check_function_name(name.as_str(), expr.span);
+                        } else {
+                            self.check_type_specifier(ty, expr.span);
+                        }
+                    }
+                    ast::FunIdentifierData::Expr(callee) => match &callee.content {
+                        ast::ExprData::Variable(name) => {
+                            self.check_function_name(name.content.as_str(), expr.span)
+                        }
+                        ast::ExprData::Dot(_, method) if method.content.as_str() == "length" => {
+                            self.error(expr.span, "不支持 .length()".to_string());
+                        }
+                        _ => self.check_expr(callee),
+                    },
+                }
+                for arg in args {
+                    self.check_expr(arg);
+                }
+            }
+            ast::ExprData::Dot(expr, _) => self.check_expr(expr),
+            ast::ExprData::PostInc(operand) | ast::ExprData::PostDec(operand) => {
+                self.check_loop_index_write(operand);
+                self.check_expr(operand);
+            }
+            ast::ExprData::Comma(left, right) => {
+                self.check_expr(left);
+                self.check_expr(right);
+            }
+        }
+    }
+
+    fn check_function_name(&mut self, name: &str, span: Option<ast::NodeSpan>) {
+        if UNSUPPORTED_FUNCTIONS.contains(&name) {
+            let hint = match name {
+                "texture" => ", 应使用 texture2D 或 textureCube",
+                "textureProj" => ", 应使用 texture2DProj",
+                _ => "",
+            };
+            self.error(span, format!("不支持内置函数 {}(){}", name, hint));
+        } else if self.stage == ShaderStage::Fragment && VERTEX_ONLY_FUNCTIONS.contains(&name) {
+            self.error(span, format!("{}() 只能在顶点着色器中使用", name));
+        }
+    }
+
+    // 附录 A: 循环体中不能修改循环变量
+    fn check_loop_index_write(&mut self, target: &ast::Expr) {
+        if let ast::ExprData::Variable(name) = &target.content {
+            if self.loop_indices.iter().any(|i| i == name.content.as_str()) {
+                self.error(
+                    target.span,
+                    format!(
+                        "不能在循环体中修改循环变量 {} (附录 A)",
+                        name.content.as_str()
+                    ),
+                );
+            }
+        }
+    }
+
+    // 常量表达式: 字面量, const 变量, 以及它们的运算和构造
+    fn is_constant(&self, expr: &ast::Expr) -> bool {
+        self.is_constant_with(expr, &|_| false)
+    }
+
+    // 常量下标表达式: 常量表达式中还可以使用循环变量
+    fn is_constant_index(&self, expr: &ast::Expr) -> bool {
+        self.is_constant_with(expr, &|name| self.loop_indices.iter().any(|i| i == name))
+    }
+
+    fn is_constant_with(&self, expr: &ast::Expr, extra: &dyn Fn(&str) -> bool) -> bool {
+        match &expr.content {
+            ast::ExprData::IntConst(_)
+            | ast::ExprData::UIntConst(_)
+            | ast::ExprData::BoolConst(_)
+            | ast::ExprData::FloatConst(_)
+            | ast::ExprData::DoubleConst(_) => true,
+            ast::ExprData::Variable(name) => {
+                let name = name.content.as_str();
+                self.consts.contains(name) || extra(name)
+            }
+            ast::ExprData::Unary(op, operand) => {
+                !matches!(op.content, ast::UnaryOpData::Inc | ast::UnaryOpData::Dec)
+                    && self.is_constant_with(operand, extra)
+            }
+            ast::ExprData::Binary(_, left, right) => {
+                self.is_constant_with(left, extra) && self.is_constant_with(right, extra)
+            }
+            ast::ExprData::Ternary(cond, yes, no) => {
+                self.is_constant_with(cond, extra)
+                    && self.is_constant_with(yes, extra)
+                    && self.is_constant_with(no, extra)
+            }
+            ast::ExprData::Bracket(array, index) => {
+                self.is_constant_with(array, extra) && self.is_constant_with(index, extra)
+            }
+            ast::ExprData::Dot(expr, _) => self.is_constant_with(expr, extra),
+            // 只有构造函数的结果是常量
+            ast::ExprData::FunCall(fun, args) => {
+                matches!(&fun.content, ast::FunIdentifierData::TypeSpecifier(ty)
+                    if !matches!(ty.ty.content, ast::TypeSpecifierNonArrayData::TypeName(_)))
+                    && args.iter().all(|arg| self.is_constant_with(arg, extra))
+            }
+            ast::ExprData::Assignment(..)
+            | ast::ExprData::PostInc(_)
+            | ast::ExprData::PostDec(_)
+            | ast::ExprData::Comma(..) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_parser::parse_glsl;
+    use crate::preprocessor::PreprocessOptions;
+
+    #[test]
+    fn test_check_es100() {
+        let check = |content: &str| {
+            let shader = parse_glsl(content, &PreprocessOptions::default()).unwrap();
+            let lines: Vec<_> = check_es100(&shader, ShaderStage::Fragment)
+                .iter()
+                .map(|d| d.line)
+                .collect();
+            lines
+        };
+
+        let valid = "precision mediump float;\n\
+            uniform sampler2D SAMPLER[2];\n\
+            varying vec2 PS_TEXCOORD;\n\
+            const int COUNT = 2;\n\
+            void main() {\n\
+            vec4 colour = vec4(0.0);\n\
+            for (int i = 0; i < COUNT; i++) { colour += texture2D(SAMPLER[i], PS_TEXCOORD); }\n\
+            gl_FragColor = colour;\n\
+            }\n";
+        assert!(check(valid).is_empty(), "{:?}", check(valid));
+
+        let invalid = "uniform sampler2D SAMPLER[2];\n\
+            in vec2 PS_TEXCOORD;\n\
+            uniform int INDEX;\n\
+            void main() {\n\
+            vec4 colour = texture(SAMPLER[0], PS_TEXCOORD);\n\
+            colour += texture2D(SAMPLER[INDEX], PS_TEXCOORD);\n\
+            int n = 0;\n\
+            while (n < 4) { n++; }\n\
+            for (int i = 0; i < n; i++) { colour *= 0.5; }\n\
+            gl_FragColor = colour;\n\
+            }\n";
+        assert_eq!(check(invalid), [2, 5, 6, 8, 9]);
+    }
+
+    // 把 `body` 放进像素或顶点着色器的 main 中检查, 返回诊断的行号和消息
+    fn check(stage: ShaderStage, body: &str) -> Vec<(usize, String)> {
+        let content = format!(
+            "precision mediump float;\n\
+            uniform sampler2D SAMPLER[2];\n\
+            uniform vec4 COLOURS[4];\n\
+            uniform int INDEX;\n\
+            const int COUNT = 2;\n\
+            void main() {{\n\
+            vec4 colour = vec4(0.0);\n\
+            {}\n\
+            gl_FragColor = colour;\n\
+            }}\n",
+            body
+        );
+        let content = match stage {
+            ShaderStage::Vertex => content.replace("gl_FragColor", "gl_Position"),
+            ShaderStage::Fragment => content,
+        };
+        let shader = parse_glsl(&content, &PreprocessOptions::default()).unwrap();
+        check_es100(&shader, stage)
+            .into_iter()
+            .map(|d| (d.line, d.message))
+            .collect()
+    }
+
+    fn assert_valid(stage: ShaderStage, body: &str) {
+        let diagnostics = check(stage, body);
+        assert!(diagnostics.is_empty(), "{}: {:?}", body, diagnostics);
+    }
+
+    // 只有一条报错, 位于 body 所在的第 8 行, 消息包含 `expected`
+    fn assert_error(stage: ShaderStage, body: &str, expected: &str) {
+        let diagnostics = check(stage, body);
+        assert_eq!(diagnostics.len(), 1, "{}: {:?}", body, diagnostics);
+        assert_eq!(diagnostics[0].0, 8, "{}", body);
+        assert!(
+            diagnostics[0].1.contains(expected),
+            "{}: {}",
+            body,
+            diagnostics[0].1
+        );
+    }
+
+    #[test]
+    fn test_es100_loop_form() {
+        use ShaderStage::Fragment;
+        assert_valid(Fragment, "for (int i = 0; i < 4; i++) { colour.x += 1.0; }");
+        assert_valid(
+            Fragment,
+            "for (int i = COUNT; i >= 0; i--) { colour.x += 1.0; }",
+        );
+        assert_valid(
+            Fragment,
+            "for (int i = 0; i != 8; i += 2) { colour.x += 1.0; }",
+        );
+        assert_valid(
+            Fragment,
+            "for (float f = 1.0; f > 0.0; f -= 0.25) { colour.x += f; }",
+        );
+
+        let header = "for 循环必须以 `int i = 常量` 的形式声明唯一的循环变量";
+        assert_error(Fragment, "int i; for (i = 0; i < 4; i++) { }", header);
+        assert_error(Fragment, "for (int i = INDEX; i < 4; i++) { }", header);
+        assert_error(
+            Fragment,
+            "for (int i = 0; i < INDEX; i++) { }",
+            "for 循环的条件必须是 `i 关系运算符 常量表达式`",
+        );
+        assert_error(
+            Fragment,
+            "for (int i = 0; 4 > i; i++) { }",
+            "for 循环的条件必须是",
+        );
+        assert_error(
+            Fragment,
+            "for (int i = 1; i < 8; i *= 2) { }",
+            "for 循环的步进必须是 i++, i--, i += 常量 或 i -= 常量",
+        );
+        assert_error(
+            Fragment,
+            "for (int i = 0; i < 4; i++) { i += 1; }",
+            "不能在循环体中修改循环变量 i",
+        );
+    }
+
+    #[test]
+    fn test_es100_sampler_index() {
+        use ShaderStage::{Fragment, Vertex};
+        assert_valid(Fragment, "colour = texture2D(SAMPLER[1], vec2(0.0));");
+        assert_valid(
+            Fragment,
+            "colour = texture2D(SAMPLER[COUNT - 1], vec2(0.0));",
+        );
+        assert_valid(
+            Fragment,
+            "for (int i = 0; i < COUNT; i++) { colour += texture2D(SAMPLER[i], vec2(0.0)); }",
+        );
+
+        let message = "SAMPLER 的下标只能是常量表达式或循环变量";
+        assert_error(
+            Fragment,
+            "colour = texture2D(SAMPLER[INDEX], vec2(0.0));",
+            message,
+        );
+        assert_error(
+            Fragment,
+            "int n = 1; colour = texture2D(SAMPLER[n], vec2(0.0));",
+            message,
+        );
+        // 顶点着色器中采样器同样受限
+        assert_error(
+            Vertex,
+            "colour = texture2DLod(SAMPLER[INDEX], vec2(0.0), 0.0);",
+            message,
+        );
+    }
+
+    #[test]
+    fn test_es100_uniform_array_index() {
+        use ShaderStage::{Fragment, Vertex};
+        assert_valid(Fragment, "colour = COLOURS[3];");
+        assert_valid(
+            Fragment,
+            "for (int i = 0; i < 4; i++) { colour += COLOURS[i]; }",
+        );
+        assert_error(
+            Fragment,
+            "colour = COLOURS[INDEX];",
+            "COLOURS 的下标只能是常量表达式或循环变量",
+        );
+        // 顶点着色器中的 uniform 数组可以用任意整数下标
+        assert_valid(Vertex, "colour = COLOURS[INDEX];");
+    }
+
+    #[test]
+    fn test_es100_builtins() {
+        use ShaderStage::{Fragment, Vertex};
+        assert_valid(
+            Fragment,
+            "colour = texture2D(SAMPLER[0], vec2(floor(0.5)));",
+        );
+        assert_valid(Vertex, "colour = texture2DLod(SAMPLER[0], vec2(0.0), 0.0);");
+
+        assert_error(
+            Fragment,
+            "colour = texture(SAMPLER[0], vec2(0.0));",
+            "不支持内置函数 texture(), 应使用 texture2D 或 textureCube",
+        );
+        assert_error(Fragment, "colour.x = round(0.5);", "不支持内置函数 round()");
+        assert_error(
+            Fragment,
+            "colour = texture2DLod(SAMPLER[0], vec2(0.0), 0.0);",
+            "texture2DLod() 只能在顶点着色器中使用",
+        );
+        assert_error(
+            Fragment,
+            "colour.x = float(COLOURS.length());",
+            "不支持 .length()",
+        );
+    }
+}
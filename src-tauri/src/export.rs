@@ -0,0 +1,467 @@
+//! 把 KSH 中的着色器经由 naga 转换为 WGSL, SPIR-V 或 HLSL, 供使用现代图形 API 的查看器使用
+
+use crate::core::{analyze_ksh, merge_uniforms};
+use crate::diagnostic::Diagnostic;
+use crate::glsl_parser::{collect_uniforms, parse_glsl, ParsedShader};
+use crate::naga_glsl::{
+    parse_module, translate, validate_module, InterfaceLayout, TranslateError, UNIFORM_BLOCK_NAME,
+};
+use crate::preprocessor::{resolve_includes, IncludedSource, PreprocessOptions};
+use crate::types::ShaderStage;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 导出的目标语言
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportTarget {
+    Wgsl,
+    SpirV,
+    Hlsl,
+}
+
+impl ExportTarget {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportTarget::Wgsl => "wgsl",
+            ExportTarget::SpirV => "spirv",
+            ExportTarget::Hlsl => "hlsl",
+        }
+    }
+
+    /// 输出文件的扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportTarget::Wgsl => "wgsl",
+            ExportTarget::SpirV => "spv",
+            ExportTarget::Hlsl => "hlsl",
+        }
+    }
+}
+
+impl TryFrom<&str> for ExportTarget {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "wgsl" => Ok(ExportTarget::Wgsl),
+            "spirv" | "spv" => Ok(ExportTarget::SpirV),
+            "hlsl" => Ok(ExportTarget::Hlsl),
+            _ => Err(format!(
+                "不支持的导出目标: {}, 可选 wgsl, spirv, hlsl",
+                value
+            )),
+        }
+    }
+}
+
+/// 导出的一个着色器
+pub struct ExportedShader {
+    pub file_name: String,
+    pub content: Vec<u8>,
+}
+
+/// 绑定表, 说明 KSH 的 uniform 表在转换后的着色器中的位置
+#[derive(Debug, Serialize)]
+pub struct BindingMap {
+    pub target: &'static str,
+    pub vertex: EntryPoint,
+    pub fragment: EntryPoint,
+    /// 非采样器 uniform 所在的 uniform 缓冲, 没有这类 uniform 时为空
+    pub uniform_buffer: Option<UniformBuffer>,
+    /// 每个采样器 (或采样器数组的每个元素) 拆分出的纹理和采样器
+    pub textures: Vec<TextureBinding>,
+    /// 顶点着色器的输入
+    pub attributes: Vec<LocationBinding>,
+    /// 顶点着色器输出到像素着色器的 varying
+    pub varyings: Vec<LocationBinding>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryPoint {
+    pub file: String,
+    pub entry_point: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UniformBuffer {
+    pub name: &'static str,
+    pub group: u32,
+    pub binding: u32,
+    /// 缓冲大小 (字节)
+    pub size: u32,
+    pub members: Vec<UniformField>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UniformField {
+    pub name: String,
+    pub r#type: &'static str,
+    pub array_length: Option<u32>,
+    /// 在缓冲中的偏移 (字节), 按 std140 布局
+    pub offset: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextureBinding {
+    /// KSH 中的 uniform 名称
+    pub uniform: String,
+    /// 采样器数组中的下标
+    pub index: Option<u32>,
+    pub group: u32,
+    pub texture_binding: u32,
+    pub sampler_binding: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LocationBinding {
+    pub name: String,
+    pub location: u32,
+}
+
+/// 转换一对着色器, 返回顶点着色器, 像素着色器和绑定表
+pub fn export_shaders(
+    vs_name: &str,
+    vs_content: &str,
+    ps_name: &str,
+    ps_content: &str,
+    target: ExportTarget,
+    options: &PreprocessOptions,
+) -> Result<(ExportedShader, ExportedShader, BindingMap), Box<dyn std::error::Error>> {
+    let vs = resolve_includes(vs_content, Path::new(vs_name), options)?;
+    let ps = resolve_includes(ps_content, Path::new(ps_name), options)?;
+    let vs_shader = parse_glsl(&vs.code, options).map_err(|e| vs.map_error(e))?;
+    let ps_shader = parse_glsl(&ps.code, options).map_err(|e| ps.map_error(e))?;
+    let vs_uniforms = collect_uniforms(&vs_shader).map_err(|e| vs.map_error(e))?;
+    let ps_uniforms = collect_uniforms(&ps_shader).map_err(|e| ps.map_error(e))?;
+    let uniforms = merge_uniforms(&vs_uniforms, &ps_uniforms, &vs, &ps)?;
+    let layout = InterfaceLayout::new(&uniforms, &vs_shader, &ps_shader).map_err(
+        |(stage, diagnostic)| match stage {
+            ShaderStage::Vertex => vs.map_diagnostic(diagnostic),
+            ShaderStage::Fragment => ps.map_diagnostic(diagnostic),
+        },
+    )?;
+
+    let (vs_module, vs_info) = compile(&vs_shader, ShaderStage::Vertex, &layout, &vs)?;
+    let (ps_module, ps_info) = compile(&ps_shader, ShaderStage::Fragment, &layout, &ps)?;
+    let (vs_output, vs_entry) = write(&vs_module, &vs_info, ShaderStage::Vertex, target)?;
+    let (ps_output, ps_entry) = write(&ps_module, &ps_info, ShaderStage::Fragment, target)?;
+    let vs_file = format!("{}.{}", vs_name, target.extension());
+    let ps_file = format!("{}.{}", ps_name, target.extension());
+
+    let binding_map = BindingMap {
+        target: target.name(),
+        vertex: EntryPoint {
+            file: vs_file.clone(),
+            entry_point: vs_entry,
+        },
+        fragment: EntryPoint {
+            file: ps_file.clone(),
+            entry_point: ps_entry,
+        },
+        uniform_buffer: uniform_buffer(&layout, &vs_module, &ps_module),
+        textures: layout
+            .samplers
+            .iter()
+            .map(|s| TextureBinding {
+                uniform: s.name.clone(),
+                index: s.index,
+                group: 0,
+                texture_binding: s.texture_binding,
+                sampler_binding: s.sampler_binding,
+            })
+            .collect(),
+        attributes: entry_inputs(&vs_module),
+        varyings: layout
+            .varyings
+            .iter()
+            .map(|(name, location)| LocationBinding {
+                name: name.clone(),
+                location: *location,
+            })
+            .collect(),
+    };
+
+    Ok((
+        ExportedShader {
+            file_name: vs_file,
+            content: vs_output,
+        },
+        ExportedShader {
+            file_name: ps_file,
+            content: ps_output,
+        },
+        binding_map,
+    ))
+}
+
+/// 转换 KSH 文件中的着色器, 和绑定表 `<名称>.bindings.json` 一起写入输出目录
+pub fn export_ksh_file(
+    file_path: &Path,
+    out_path: &Path,
+    target: ExportTarget,
+    force: bool,
+    options: &PreprocessOptions,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    log::info!("导出文件: {:?}", file_path);
+    let content = fs::read(file_path)?;
+    let (vs_name, vs_content, ps_name, ps_content) = analyze_ksh(&content)?;
+    let (vs, ps, binding_map) = export_shaders(
+        &vs_name,
+        &vs_content,
+        &ps_name,
+        &ps_content,
+        target,
+        options,
+    )?;
+
+    let stem = file_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+    let map_path = out_path.join(format!("{}.bindings.json", stem));
+    let outputs = [
+        (out_path.join(&vs.file_name), vs.content),
+        (out_path.join(&ps.file_name), ps.content),
+        (map_path, serde_json::to_vec_pretty(&binding_map)?),
+    ];
+    if !force {
+        if let Some((path, _)) = outputs.iter().find(|(path, _)| path.exists()) {
+            return Err(format!("输出文件已存在: {}", path.display()).into());
+        }
+    }
+    let mut paths = vec![];
+    for (path, content) in outputs {
+        fs::write(&path, content)
+            .map_err(|e| format!("写入文件 {} 失败: {}", path.display(), e))?;
+        paths.push(path);
+    }
+
+    log::info!("导出完成");
+    Ok(paths)
+}
+
+// 转换为 naga 模块并验证, 错误指向原始文件
+fn compile(
+    shader: &ParsedShader,
+    stage: ShaderStage,
+    layout: &InterfaceLayout,
+    included: &IncludedSource,
+) -> Result<(naga::Module, naga::valid::ModuleInfo), Diagnostic> {
+    let translation = translate(shader, stage, layout).map_err(|e| match e {
+        TranslateError::Invalid(diagnostic) => included.map_diagnostic(diagnostic),
+        TranslateError::Unsupported(diagnostic) => {
+            let message = format!("无法导出此着色器: {}", diagnostic.message);
+            included.map_diagnostic(Diagnostic::error(diagnostic.line, message))
+        }
+    })?;
+    let module = parse_module(&translation, stage).map_err(|diagnostics| {
+        let first = diagnostics.into_iter().next();
+        included.map_diagnostic(first.unwrap_or_else(|| Diagnostic::error(1, "解析失败")))
+    })?;
+    let info = validate_module(&translation, &module).map_err(|d| included.map_diagnostic(d))?;
+    Ok((module, info))
+}
+
+// 生成目标代码, 返回代码和入口函数名
+fn write(
+    module: &naga::Module,
+    info: &naga::valid::ModuleInfo,
+    stage: ShaderStage,
+    target: ExportTarget,
+) -> Result<(Vec<u8>, String), Box<dyn std::error::Error>> {
+    let shader_stage = match stage {
+        ShaderStage::Vertex => naga::ShaderStage::Vertex,
+        ShaderStage::Fragment => naga::ShaderStage::Fragment,
+    };
+    let entry_point = module
+        .entry_points
+        .iter()
+        .find(|e| e.stage == shader_stage)
+        .map(|e| e.name.clone())
+        .ok_or("着色器没有入口函数")?;
+
+    match target {
+        ExportTarget::Wgsl => {
+            let code = naga::back::wgsl::write_string(
+                module,
+                info,
+                naga::back::wgsl::WriterFlags::empty(),
+            )
+            .map_err(|e| format!("生成 WGSL 失败: {}", e))?;
+            Ok((code.into_bytes(), entry_point))
+        }
+        ExportTarget::SpirV => {
+            let pipeline_options = naga::back::spv::PipelineOptions {
+                shader_stage,
+                entry_point: entry_point.clone(),
+            };
+            let words = naga::back::spv::write_vec(
+                module,
+                info,
+                &naga::back::spv::Options::default(),
+                Some(&pipeline_options),
+            )
+            .map_err(|e| format!("生成 SPIR-V 失败: {}", e))?;
+            let bytes = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            Ok((bytes, entry_point))
+        }
+        ExportTarget::Hlsl => {
+            let mut code = String::new();
+            let options = naga::back::hlsl::Options::default();
+            let pipeline_options = naga::back::hlsl::PipelineOptions::default();
+            let reflection = naga::back::hlsl::Writer::new(&mut code, &options, &pipeline_options)
+                .write(module, info, None)
+                .map_err(|e| format!("生成 HLSL 失败: {}", e))?;
+            // HLSL 中的入口函数可能被改名
+            let entry_point = reflection
+                .entry_point_names
+                .into_iter()
+                .next()
+                .and_then(Result::ok)
+                .unwrap_or(entry_point);
+            Ok((code.into_bytes(), entry_point))
+        }
+    }
+}
+
+// 从 naga 计算的结构体布局中读取 uniform 的偏移, 两个阶段的布局相同, 优先使用顶点着色器的
+fn uniform_buffer(
+    layout: &InterfaceLayout,
+    vs_module: &naga::Module,
+    ps_module: &naga::Module,
+) -> Option<UniformBuffer> {
+    if layout.uniforms.is_empty() {
+        return None;
+    }
+    let (members, size) = [vs_module, ps_module].into_iter().find_map(|module| {
+        module.global_variables.iter().find_map(|(_, global)| {
+            let binding = global.binding.as_ref()?;
+            if global.space != naga::AddressSpace::Uniform
+                || binding.group != 0
+                || binding.binding != 0
+            {
+                return None;
+            }
+            match &module.types[global.ty].inner {
+                naga::TypeInner::Struct { members, span } => Some((members.clone(), *span)),
+                _ => None,
+            }
+        })
+    })?;
+    let fields = layout
+        .uniforms
+        .iter()
+        .map(|u| UniformField {
+            name: u.name.clone(),
+            r#type: u.r#type.name(),
+            array_length: u.array_length,
+            offset: members
+                .iter()
+                .find(|m| m.name.as_deref() == Some(u.name.as_str()))
+                .map_or(0, |m| m.offset),
+        })
+        .collect();
+    Some(UniformBuffer {
+        name: UNIFORM_BLOCK_NAME,
+        group: 0,
+        binding: 0,
+        size,
+        members: fields,
+    })
+}
+
+// 入口函数带 location 的输入
+fn entry_inputs(module: &naga::Module) -> Vec<LocationBinding> {
+    let Some(entry_point) = module.entry_points.first() else {
+        return vec![];
+    };
+    entry_point
+        .function
+        .arguments
+        .iter()
+        .filter_map(|argument| match argument.binding {
+            Some(naga::Binding::Location { location, .. }) => Some(LocationBinding {
+                name: argument.name.clone().unwrap_or_default(),
+                location,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_shaders() {
+        let vs = "uniform mat4 MatrixP;\n\
+            attribute vec3 POSITION;\n\
+            attribute vec2 TEXCOORD0;\n\
+            varying vec2 PS_TEXCOORD;\n\
+            void main() {\n\
+            PS_TEXCOORD = TEXCOORD0;\n\
+            gl_Position = MatrixP * vec4(POSITION, 1.0);\n\
+            }\n";
+        let ps = "precision mediump float;\n\
+            uniform sampler2D SAMPLER[2];\n\
+            uniform vec4 TINT;\n\
+            varying vec2 PS_TEXCOORD;\n\
+            void main() { gl_FragColor = texture2D(SAMPLER[1], PS_TEXCOORD) * TINT; }\n";
+        let options = PreprocessOptions::default();
+        let (vs_out, ps_out, map) =
+            export_shaders("anim.vs", vs, "anim.ps", ps, ExportTarget::Wgsl, &options).unwrap();
+        assert_eq!(vs_out.file_name, "anim.vs.wgsl");
+        let ps_code = String::from_utf8(ps_out.content).unwrap();
+        assert!(ps_code.contains("@group(0) @binding(3)"), "{}", ps_code);
+
+        let buffer = map.uniform_buffer.unwrap();
+        let offsets: Vec<_> = buffer
+            .members
+            .iter()
+            .map(|m| (m.name.as_str(), m.offset))
+            .collect();
+        assert_eq!(offsets, [("MatrixP", 0), ("TINT", 64)]);
+        assert_eq!(map.textures.len(), 2);
+        assert_eq!(map.textures[1].texture_binding, 3);
+        assert_eq!(map.attributes.len(), 2);
+
+        let (vs_out, ..) =
+            export_shaders("anim.vs", vs, "anim.ps", ps, ExportTarget::SpirV, &options).unwrap();
+        assert_eq!(vs_out.content[..4], 0x0723_0203u32.to_le_bytes());
+
+        // 像素着色器 varying 的错误指向像素着色器
+        let ps = "precision mediump float;\n\
+            varying vec2 PS_TEXCOORD[0];\n\
+            void main() { gl_FragColor = vec4(PS_TEXCOORD[0], 0.0, 1.0); }\n";
+        let Err(err) = export_shaders("anim.vs", vs, "anim.ps", ps, ExportTarget::Wgsl, &options)
+        else {
+            panic!("导出应当失败");
+        };
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.file.as_deref(), Some("anim.ps"));
+        assert_eq!(diagnostic.line, 2);
+
+        // 动态的采样器下标无法导出
+        let ps = "precision mediump float;\n\
+            uniform sampler2D SAMPLER[2];\n\
+            varying vec2 PS_TEXCOORD;\n\
+            void main() {\n\
+            vec4 colour = vec4(0.0);\n\
+            for (int i = 0; i < 2; i++) { colour += texture2D(SAMPLER[i], PS_TEXCOORD); }\n\
+            gl_FragColor = colour;\n\
+            }\n";
+        let Err(err) = export_shaders("anim.vs", vs, "anim.ps", ps, ExportTarget::Wgsl, &options)
+        else {
+            panic!("导出应当失败");
+        };
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 6);
+        assert!(
+            diagnostic.message.contains("不支持动态的采样器数组下标"),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,243 +1,606 @@
+use crate::diagnostic::Diagnostic;
+use crate::preprocessor::{preprocess, PreprocessOptions};
 use crate::types::{Variable, VariableScope};
-use glsl_lang::{ast, parse::Parse};
-use std::collections::HashSet;
+use glsl_lang::ast;
+use glsl_lang::parse::{DefaultLexer, Parse};
+use glsl_lang::visitor::{Host, Visit, Visitor};
+use std::collections::{HashMap, HashSet};
 
-pub fn parse_glsl_uniforms(content: &str) -> Result<Vec<Variable>, Box<dyn std::error::Error>> {
-    let ast::TranslationUnit(declarations) =
-        ast::TranslationUnit::parse::<glsl_lang::parse::DefaultLexer>(&content)?;
+/// 预处理并解析后的着色器
+pub struct ParsedShader {
+    /// 预处理后的代码, 行号与原始代码一致
+    pub source: String,
+    pub declarations: Vec<ast::ExternalDeclaration>,
+    /// 每一行开头与原始代码相同的字符数. 宏展开和去掉注释会改变之后的列号
+    pub unchanged_prefixes: Vec<usize>,
+}
+
+impl ParsedShader {
+    /// 把预处理后代码中的列号映射回原始代码, 该位置之前的代码被预处理改写过时返回 `None`
+    pub fn original_column(&self, line: usize, column: usize) -> Option<usize> {
+        let unchanged = *self.unchanged_prefixes.get(line.checked_sub(1)?)?;
+        (column.saturating_sub(1) <= unchanged).then_some(column)
+    }
+
+    /// 语法树节点所在的行号, 没有位置信息时返回 1
+    pub fn line_of(&self, span: Option<ast::NodeSpan>) -> usize {
+        let Some(span) = span else {
+            return 1;
+        };
+        let offset = usize::from(span.start().offset).min(self.source.len());
+        self.source.as_bytes()[..offset]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1
+    }
+
+    /// 语法树节点对应的代码, 没有位置信息时返回空字符串
+    pub fn text_of(&self, span: Option<ast::NodeSpan>) -> &str {
+        let Some(span) = span else {
+            return "";
+        };
+        let start = usize::from(span.start().offset).min(self.source.len());
+        let end = usize::from(span.end().offset).clamp(start, self.source.len());
+        self.source.get(start..end).unwrap_or_default()
+    }
+}
+
+/// 展开预处理指令后解析着色器
+pub fn parse_glsl(
+    content: &str,
+    options: &PreprocessOptions,
+) -> Result<ParsedShader, Box<dyn std::error::Error>> {
+    let source = preprocess(content, options)?;
+    let unchanged_prefixes = content
+        .lines()
+        .zip(source.lines())
+        .map(|(original, preprocessed)| {
+            original
+                .chars()
+                .zip(preprocessed.chars())
+                .take_while(|(a, b)| a == b)
+                .count()
+        })
+        .collect();
+    let mut shader = ParsedShader {
+        source,
+        declarations: vec![],
+        unchanged_prefixes,
+    };
+    let unit = ast::TranslationUnit::parse::<DefaultLexer>(&shader.source).map_err(|e| {
+        let line = e.line() as usize + 1;
+        let diagnostic = Diagnostic::error(line, e.inner().to_string());
+        match shader.original_column(line, e.col() as usize + 1) {
+            Some(column) => diagnostic.with_column(column),
+            None => diagnostic,
+        }
+    })?;
+    shader.declarations = unit.0;
+    Ok(shader)
+}
+
+/// 解析着色器中实际使用到的 uniform, 解析前会先展开预处理指令
+pub fn parse_glsl_uniforms(
+    content: &str,
+    options: &PreprocessOptions,
+) -> Result<Vec<Variable>, Box<dyn std::error::Error>> {
+    collect_uniforms(&parse_glsl(content, options)?)
+}
 
-    let mut uniforms = collect_uniform_declarations(&declarations)?;
-    let used_vars = collect_used_variables(&declarations);
+/// 收集已解析着色器中实际使用到的 uniform
+pub fn collect_uniforms(
+    shader: &ParsedShader,
+) -> Result<Vec<Variable>, Box<dyn std::error::Error>> {
+    let mut uniforms = collect_uniform_declarations(shader)?;
+    let used_vars = collect_used_variables(&shader.declarations);
     uniforms.retain(|u| used_vars.contains(&u.name));
 
     Ok(uniforms)
 }
 
+/// 收集已声明但没有使用的 uniform, 这些 uniform 不会写入 KSH
+pub fn collect_unused_uniforms(
+    shader: &ParsedShader,
+) -> Result<Vec<Variable>, Box<dyn std::error::Error>> {
+    let mut uniforms = collect_uniform_declarations(shader)?;
+    let used_vars = collect_used_variables(&shader.declarations);
+    uniforms.retain(|u| !used_vars.contains(&u.name));
+
+    Ok(uniforms)
+}
+
 // 收集 Uniform 声明
 fn collect_uniform_declarations(
-    declarations: &[ast::ExternalDeclaration],
+    shader: &ParsedShader,
 ) -> Result<Vec<Variable>, Box<dyn std::error::Error>> {
     let mut uniforms = vec![];
+    // 已声明的整数常量, 用于计算数组长度
+    let mut consts = HashMap::new();
 
-    for decl in declarations {
+    for decl in &shader.declarations {
         let ast::ExternalDeclarationData::Declaration(decl) = &decl.content else {
             continue;
         };
         let ast::DeclarationData::InitDeclaratorList(decl_list) = &decl.content else {
             continue;
         };
+        collect_const_ints(decl_list, &mut consts);
         let head = &decl_list.content.head;
 
-        // 检查是否是 uniform 类型
-        let is_uniform = head.ty.content.qualifier.as_ref()
-            .and_then(|q| q.qualifiers.first())
-            .map(|q| matches!(&q.content, ast::TypeQualifierSpecData::Storage(s) if s.content == ast::StorageQualifierData::Uniform))
-            .unwrap_or(false);
-
-        if !is_uniform {
+        // 检查是否是 uniform 类型, 限定符可以以任意顺序出现, 如 `highp uniform vec4 x;`
+        let Some(qualifier) = head.ty.content.qualifier.as_ref() else {
+            continue;
+        };
+        let line = shader.line_of(decl.span);
+        let head_name = head.name.as_ref().map_or("", |n| n.content.as_str());
+        let Some(uniform_qualifier) = parse_uniform_qualifier(qualifier, head_name)
+            .map_err(|e| Diagnostic::error(line, e))?
+        else {
             continue;
+        };
+        if head_name.is_empty() {
+            return Err(Diagnostic::error(line, "uniform 声明缺少名称").into());
         }
 
-        let name = head
-            .name
-            .as_ref()
-            .map(|n| n.content.as_str())
-            .ok_or("Uniform 缺少名称")?
-            .to_string();
-
-        // 提取类型和数组长度
+        // 提取类型, 类型上的数组长度 (如 `uniform vec4[2] a, b;`) 对所有声明符生效
         let type_ = &head.ty.ty.ty.content;
+        let type_array_length = array_length(
+            head.ty.ty.array_specifier.as_ref(),
+            &consts,
+            &format!("uniform {}", head_name),
+        )
+        .map_err(|e| Diagnostic::error(line, e))?;
 
-        let array_length = head
-            .array_specifier
-            .as_ref()
-            .and_then(|a| a.content.dimensions.first())
-            .and_then(|d| {
-                if let ast::ArraySpecifierDimensionData::ExplicitlySized(es) = &d.content {
-                    if let ast::ExprData::IntConst(size) = &es.content {
-                        Some(*size as u32)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            });
-        let mut variable = Variable::new();
-        variable.name = name;
-        variable.r#type = type_.clone();
-        variable.array_length = array_length;
-        variable.default_data = vec![];
-        variable.scope = VariableScope::UNIFORM;
-        uniforms.push(variable);
-    }
-
-    Ok(uniforms)
-}
+        // 按源码顺序收集 `uniform vec4 a, b[2];` 中的每个声明符
+        let declarators = std::iter::once((head_name, head.array_specifier.as_ref())).chain(
+            decl_list
+                .content
+                .tail
+                .iter()
+                .map(|d| (d.ident.ident.content.as_str(), d.ident.array_spec.as_ref())),
+        );
 
-// 收集使用的变量
-fn collect_used_variables(declarations: &[ast::ExternalDeclaration]) -> HashSet<String> {
-    let mut used_vars = HashSet::new();
-    for decl in declarations {
-        if let ast::ExternalDeclarationData::FunctionDefinition(f) = &decl.content {
-            for stmt in &f.content.statement.content.statement_list {
-                walk_statement(&stmt, &mut used_vars);
-            }
+        for (name, array_specifier) in declarators {
+            let mut variable = Variable::new();
+            variable.name = name.to_string();
+            variable.r#type = type_.clone();
+            variable.array_length =
+                array_length(array_specifier, &consts, &format!("uniform {}", name))
+                    .map_err(|e| Diagnostic::error(line, e))?
+                    .or(type_array_length);
+            variable.default_data = vec![];
+            variable.scope = VariableScope::UNIFORM;
+            variable.precision = uniform_qualifier.precision.clone();
+            variable.line = Some(line);
+            uniforms.push(variable);
         }
     }
-    used_vars
+
+    Ok(uniforms)
 }
 
-fn walk_condition(condition: &ast::Condition, vars: &mut HashSet<String>) {
-    match &condition.content {
-        ast::ConditionData::Expr(expr) => {
-            walk_expr(expr, vars);
-        }
-        ast::ConditionData::Assignment(_, _, _) => {
-            // TODO: 处理赋值表达式
-            unimplemented!()
-        }
-    }
+// uniform 声明上的限定符信息
+struct UniformQualifier {
+    precision: Option<ast::PrecisionQualifierData>,
 }
 
-fn walk_initializer(init: &ast::Initializer, used_vars: &mut HashSet<String>) {
-    match &init.content {
-        ast::InitializerData::Simple(expr) => {
-            walk_expr(expr, used_vars);
-        }
-        ast::InitializerData::List(init_list) => {
-            for item in init_list {
-                walk_initializer(item, used_vars);
-            }
-        }
+// 扫描完整的限定符列表, 不是 uniform 时返回 None, 限定符组合无效时报错
+fn parse_uniform_qualifier(
+    qualifier: &ast::TypeQualifier,
+    name: &str,
+) -> Result<Option<UniformQualifier>, String> {
+    let is_uniform = qualifier.qualifiers.iter().any(|q| {
+        matches!(&q.content, ast::TypeQualifierSpecData::Storage(s) if s.content == ast::StorageQualifierData::Uniform)
+    });
+    if !is_uniform {
+        return Ok(None);
     }
-}
 
-fn walk_statement(stmt: &ast::Statement, vars: &mut HashSet<String>) {
-    match &stmt.content {
-        ast::StatementData::Declaration(decl) => match &decl.content {
-            ast::DeclarationData::InitDeclaratorList(decl_list) => {
-                if let Some(init) = &decl_list.content.head.content.initializer {
-                    walk_initializer(init, vars);
-                }
-                for decl in &decl_list.content.tail {
-                    if let Some(init) = &decl.content.initializer {
-                        walk_initializer(init, vars);
-                    }
+    let mut precision = None;
+    let mut has_uniform = false;
+    for q in &qualifier.qualifiers {
+        match &q.content {
+            ast::TypeQualifierSpecData::Storage(s) => match &s.content {
+                ast::StorageQualifierData::Uniform if !has_uniform => has_uniform = true,
+                ast::StorageQualifierData::Uniform => {
+                    return Err(format!("uniform {} 重复使用了 uniform 限定符", name));
                 }
-            }
-            _ => {}
-        },
-        ast::StatementData::Expression(expr_stmt) => {
-            if let Some(expr) = &expr_stmt.0 {
-                walk_expr(expr, vars);
-            }
-        }
-        ast::StatementData::Selection(selection_stmt) => {
-            walk_expr(&selection_stmt.cond, vars);
-            match &selection_stmt.rest.content {
-                ast::SelectionRestStatementData::Statement(then_stmt) => {
-                    walk_statement(&then_stmt, vars);
+                // 内存限定符可以用于 image 类型的 uniform
+                ast::StorageQualifierData::Coherent
+                | ast::StorageQualifierData::Volatile
+                | ast::StorageQualifierData::Restrict
+                | ast::StorageQualifierData::ReadOnly
+                | ast::StorageQualifierData::WriteOnly => {}
+                other => {
+                    return Err(format!(
+                        "uniform {} 不能同时使用存储限定符 {}",
+                        name,
+                        storage_qualifier_name(other)
+                    ));
                 }
-                ast::SelectionRestStatementData::Else(body_stmt, next_stmt) => {
-                    walk_statement(body_stmt, vars);
-                    walk_statement(next_stmt, vars);
+            },
+            ast::TypeQualifierSpecData::Precision(p) => {
+                if precision.is_some() {
+                    return Err(format!("uniform {} 只能指定一个精度限定符", name));
                 }
+                precision = Some(p.content.clone());
             }
-        }
-        ast::StatementData::Switch(switch) => {
-            walk_expr(&switch.head, vars);
-            for stmt_node in &switch.body {
-                walk_statement(&stmt_node, vars);
-            }
-        }
-        ast::StatementData::CaseLabel(case) => {
-            if let ast::CaseLabelData::Case(expr) = &case.content {
-                walk_expr(expr, vars);
+            ast::TypeQualifierSpecData::Layout(_) => {}
+            ast::TypeQualifierSpecData::Interpolation(i) => {
+                let interpolation = match i.content {
+                    ast::InterpolationQualifierData::Smooth => "smooth",
+                    ast::InterpolationQualifierData::Flat => "flat",
+                    ast::InterpolationQualifierData::NoPerspective => "noperspective",
+                };
+                return Err(format!(
+                    "uniform {} 不能使用插值限定符 {}",
+                    name, interpolation
+                ));
             }
-        }
-        ast::StatementData::Iteration(iteration_stmt) => match &iteration_stmt.content {
-            ast::IterationStatementData::While(condition, stmt) => {
-                walk_condition(condition, vars);
-                walk_statement(stmt, vars);
+            ast::TypeQualifierSpecData::Invariant => {
+                return Err(format!("uniform {} 不能使用 invariant 限定符", name));
             }
-            ast::IterationStatementData::For(for_in_stmt, for_rest_stmt, stmt) => {
-                if let ast::ForInitStatementData::Expression(expr) = &for_in_stmt.content {
-                    if let Some(expr) = expr {
-                        walk_expr(expr, vars);
-                    }
-                }
-                if let Some(stmt) = &for_rest_stmt.condition {
-                    walk_condition(stmt, vars);
-                }
-                if let Some(expr) = &for_rest_stmt.post_expr {
-                    walk_expr(expr, vars);
-                }
-                walk_statement(stmt, vars);
-            }
-            ast::IterationStatementData::DoWhile(stmt, expr) => {
-                walk_statement(stmt, vars);
-                walk_expr(expr, vars);
-            }
-        },
-        ast::StatementData::Jump(jump) => {
-            if let ast::JumpStatementData::Return(expr) = &jump.content {
-                if let Some(expr) = expr {
-                    walk_expr(expr, vars);
-                }
-            }
-        }
-        ast::StatementData::Compound(compound_stmt) => {
-            for stmt_node in &compound_stmt.statement_list {
-                walk_statement(&stmt_node, vars);
+            ast::TypeQualifierSpecData::Precise => {
+                return Err(format!("uniform {} 不能使用 precise 限定符", name));
             }
         }
     }
+
+    Ok(Some(UniformQualifier { precision }))
+}
+
+fn storage_qualifier_name(qualifier: &ast::StorageQualifierData) -> &'static str {
+    match qualifier {
+        ast::StorageQualifierData::Const => "const",
+        ast::StorageQualifierData::InOut => "inout",
+        ast::StorageQualifierData::In => "in",
+        ast::StorageQualifierData::Out => "out",
+        ast::StorageQualifierData::Centroid => "centroid",
+        ast::StorageQualifierData::Patch => "patch",
+        ast::StorageQualifierData::Sample => "sample",
+        ast::StorageQualifierData::Uniform => "uniform",
+        ast::StorageQualifierData::Buffer => "buffer",
+        ast::StorageQualifierData::Shared => "shared",
+        ast::StorageQualifierData::Coherent => "coherent",
+        ast::StorageQualifierData::Volatile => "volatile",
+        ast::StorageQualifierData::Restrict => "restrict",
+        ast::StorageQualifierData::ReadOnly => "readonly",
+        ast::StorageQualifierData::WriteOnly => "writeonly",
+        ast::StorageQualifierData::Attribute => "attribute",
+        ast::StorageQualifierData::Varying => "varying",
+        ast::StorageQualifierData::Subroutine(_) => "subroutine",
+    }
 }
 
-fn walk_expr(expr: &ast::Expr, vars: &mut HashSet<String>) {
+// 记录 `const int N = 4;` 这类整数常量, 无法计算的常量忽略, 在被数组长度引用时再报错
+pub fn collect_const_ints(decl_list: &ast::InitDeclaratorList, consts: &mut HashMap<String, i64>) {
+    let head = &decl_list.content.head;
+    let is_const = head.ty.content.qualifier.as_ref().is_some_and(|q| {
+        q.qualifiers.iter().any(|q| {
+            matches!(&q.content, ast::TypeQualifierSpecData::Storage(s) if s.content == ast::StorageQualifierData::Const)
+        })
+    });
+    let is_int = matches!(
+        head.ty.ty.ty.content,
+        ast::TypeSpecifierNonArrayData::Int | ast::TypeSpecifierNonArrayData::UInt
+    );
+    if !is_const || !is_int || head.ty.ty.array_specifier.is_some() {
+        return;
+    }
+
+    let head_declarator = head
+        .name
+        .as_ref()
+        .filter(|_| head.array_specifier.is_none())
+        .map(|n| (n.content.as_str(), head.initializer.as_ref()));
+    let tail_declarators = decl_list
+        .content
+        .tail
+        .iter()
+        .filter(|d| d.ident.array_spec.is_none())
+        .map(|d| (d.ident.ident.content.as_str(), d.initializer.as_ref()));
+
+    for (name, initializer) in head_declarator.into_iter().chain(tail_declarators) {
+        let value = initializer.and_then(|init| match &init.content {
+            ast::InitializerData::Simple(expr) => eval_const_int(expr, consts),
+            ast::InitializerData::List(_) => None,
+        });
+        match value {
+            Some(value) => consts.insert(name.to_string(), value),
+            None => consts.remove(name),
+        };
+    }
+}
+
+// 计算整数常量表达式, 支持字面量, 已声明的常量, int()/uint() 构造和整数运算.
+// 宏在预处理阶段已经展开
+pub fn eval_const_int(expr: &ast::Expr, consts: &HashMap<String, i64>) -> Option<i64> {
     match &expr.content {
-        ast::ExprData::Variable(name) => {
-            vars.insert(name.content.as_str().to_string());
-        }
-        ast::ExprData::Unary(_, expr) => {
-            walk_expr(expr, vars);
-        }
-        ast::ExprData::Binary(_, left, right) => {
-            walk_expr(left, vars);
-            walk_expr(right, vars);
-        }
-        ast::ExprData::Ternary(cond, left, right) => {
-            walk_expr(cond, vars);
-            walk_expr(left, vars);
-            walk_expr(right, vars);
-        }
-        ast::ExprData::Assignment(left, _, right) => {
-            walk_expr(left, vars);
-            walk_expr(right, vars);
-        }
-        ast::ExprData::Bracket(arr, idx) => {
-            walk_expr(arr, vars);
-            walk_expr(idx, vars);
-        }
-        ast::ExprData::FunCall(_, args) => {
-            for arg in args {
-                walk_expr(arg, vars);
+        ast::ExprData::IntConst(value) => Some(*value as i64),
+        ast::ExprData::UIntConst(value) => Some(*value as i64),
+        ast::ExprData::Variable(name) => consts.get(name.content.as_str()).copied(),
+        ast::ExprData::Unary(op, expr) => {
+            let value = eval_const_int(expr, consts)?;
+            match op.content {
+                ast::UnaryOpData::Add => Some(value),
+                ast::UnaryOpData::Minus => value.checked_neg(),
+                ast::UnaryOpData::Complement => Some(!value),
+                _ => None,
             }
         }
-        ast::ExprData::Dot(struct_expr, _) => {
-            walk_expr(struct_expr, vars);
-        }
-        ast::ExprData::PostInc(expr) => {
-            walk_expr(expr, vars);
+        ast::ExprData::Binary(op, left, right) => {
+            let left = eval_const_int(left, consts)?;
+            let right = eval_const_int(right, consts)?;
+            match op.content {
+                ast::BinaryOpData::Add => left.checked_add(right),
+                ast::BinaryOpData::Sub => left.checked_sub(right),
+                ast::BinaryOpData::Mult => left.checked_mul(right),
+                ast::BinaryOpData::Div => left.checked_div(right),
+                ast::BinaryOpData::Mod => left.checked_rem(right),
+                ast::BinaryOpData::LShift => left.checked_shl(u32::try_from(right).ok()?),
+                ast::BinaryOpData::RShift => left.checked_shr(u32::try_from(right).ok()?),
+                ast::BinaryOpData::BitAnd => Some(left & right),
+                ast::BinaryOpData::BitOr => Some(left | right),
+                ast::BinaryOpData::BitXor => Some(left ^ right),
+                _ => None,
+            }
         }
-        ast::ExprData::PostDec(expr) => {
-            walk_expr(expr, vars);
+        ast::ExprData::FunCall(fun, args) => {
+            let ast::FunIdentifierData::TypeSpecifier(ty) = &fun.content else {
+                return None;
+            };
+            let is_int = matches!(
+                ty.ty.content,
+                ast::TypeSpecifierNonArrayData::Int | ast::TypeSpecifierNonArrayData::UInt
+            );
+            match args.as_slice() {
+                [arg] if is_int && ty.array_specifier.is_none() => eval_const_int(arg, consts),
+                _ => None,
+            }
         }
-        ast::ExprData::Comma(a, b) => {
-            walk_expr(a, vars);
-            walk_expr(b, vars);
+        _ => None,
+    }
+}
+
+// 计算数组长度, 没有数组限定时返回 None, 长度无法确定时报错. `subject` 是错误信息中的声明, 如 `uniform A`
+pub fn array_length(
+    array_specifier: Option<&ast::ArraySpecifier>,
+    consts: &HashMap<String, i64>,
+    subject: &str,
+) -> Result<Option<u32>, String> {
+    let Some(dimension) = array_specifier.and_then(|a| a.content.dimensions.first()) else {
+        return Ok(None);
+    };
+    let ast::ArraySpecifierDimensionData::ExplicitlySized(size) = &dimension.content else {
+        return Err(format!("{} 是数组, 必须指定长度", subject));
+    };
+    match eval_const_int(size, consts) {
+        Some(length) if length > 0 => u32::try_from(length)
+            .map(Some)
+            .map_err(|_| format!("{} 的数组长度 {} 过大", subject, length)),
+        Some(length) => Err(format!(
+            "{} 的数组长度必须大于 0, 实际为 {}",
+            subject, length
+        )),
+        None => Err(format!(
+            "{} 的数组长度必须是可计算的整数常量表达式",
+            subject
+        )),
+    }
+}
+
+// 收集使用的变量, 包括函数体和全局声明 (如全局初始化器, const 声明, 数组长度) 中的所有表达式.
+// 由 Visitor 遍历整棵语法树, 不需要逐个列出节点类型
+pub fn collect_used_variables(declarations: &[ast::ExternalDeclaration]) -> HashSet<String> {
+    let mut collector = VariableCollector::default();
+    for decl in declarations {
+        decl.visit(&mut collector);
+    }
+    collector.variables
+}
+
+#[derive(Default)]
+struct VariableCollector {
+    variables: HashSet<String>,
+}
+
+impl Visitor for VariableCollector {
+    fn visit_expr(&mut self, expr: &ast::Expr) -> Visit {
+        if let ast::ExprData::Variable(name) = &expr.content {
+            self.variables.insert(name.content.as_str().to_string());
         }
-        _ => {}
+        Visit::Children
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glsl_lang::ast::PrecisionQualifierData;
+    use glsl_lang::ast::TypeSpecifierNonArrayData;
+
+    #[test]
+    fn test_parse_glsl_preprocessed() {
+        let content = "#define MAX_LIGHTS 4\n\
+            #if defined(GL_ES)\n\
+            precision mediump float;\n\
+            uniform vec4 LIGHTS[MAX_LIGHTS];\n\
+            #else\n\
+            uniform vec3 LIGHTS[MAX_LIGHTS];\n\
+            #endif\n\
+            void main() { gl_FragColor = LIGHTS[0]; }\n";
+        let options = PreprocessOptions {
+            defines: vec![("GL_ES".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        let uniforms = parse_glsl_uniforms(content, &options).unwrap();
+        assert_eq!(uniforms.len(), 1);
+        assert_eq!(uniforms[0].name, "LIGHTS");
+        assert_eq!(uniforms[0].r#type, TypeSpecifierNonArrayData::Vec4);
+        assert_eq!(uniforms[0].array_length, Some(4));
+    }
+
+    #[test]
+    fn test_parse_glsl_condition_declaration() {
+        let content = "uniform float LIMIT;\n\
+            void main() {\n\
+            float x = 0.0;\n\
+            while (bool more = x < LIMIT) { x += 1.0; }\n\
+            gl_FragColor = vec4(x);\n\
+            }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        assert_eq!(uniforms.len(), 1);
+        assert_eq!(uniforms[0].name, "LIMIT");
+    }
+
+    fn used_uniform_names(content: &str) -> Vec<String> {
+        parse_glsl_uniforms(content, &PreprocessOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|u| u.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_uniform_used_in_for_initializer() {
+        let content = "uniform float START;\n\
+            void main() {\n\
+            float sum = 0.0;\n\
+            for (float i = START; i < 4.0; i += 1.0) { sum += i; }\n\
+            gl_FragColor = vec4(sum);\n\
+            }\n";
+        assert_eq!(used_uniform_names(content), ["START"]);
+    }
+
+    #[test]
+    fn test_uniform_used_in_global_initializer() {
+        let content = "uniform vec4 TINT;\n\
+            vec4 g_tint = TINT * 0.5;\n\
+            void main() { gl_FragColor = g_tint; }\n";
+        assert_eq!(used_uniform_names(content), ["TINT"]);
+    }
+
+    #[test]
+    fn test_uniform_used_in_global_declarator_list() {
+        // 只在全局声明的第二个声明符中读取
+        let content = "uniform vec4 TINT;\n\
+            uniform float UNUSED;\n\
+            vec4 g_base = vec4(1.0), g_tint = TINT;\n\
+            void main() { gl_FragColor = g_base * g_tint; }\n";
+        assert_eq!(used_uniform_names(content), ["TINT"]);
+    }
+
+    #[test]
+    fn test_uniform_used_in_array_size() {
+        // 只在局部数组的长度表达式中读取
+        let content = "uniform float SIZE;\n\
+            void main() {\n\
+            float weights[int(SIZE)];\n\
+            gl_FragColor = vec4(weights[0]);\n\
+            }\n";
+        assert_eq!(used_uniform_names(content), ["SIZE"]);
+    }
+
+    #[test]
+    fn test_parse_glsl_multi_declarator_uniforms() {
+        let content = "uniform vec4 A, B[2], C;\n\
+            uniform vec3[3] D, E;\n\
+            void main() { gl_FragColor = A + B[1] + C + vec4(D[0] + E[2], 1.0); }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        let summary: Vec<_> = uniforms
+            .iter()
+            .map(|u| (u.name.as_str(), u.array_length))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("A", None),
+                ("B", Some(2)),
+                ("C", None),
+                ("D", Some(3)),
+                ("E", Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_glsl_uniform_qualifiers() {
+        let content = "highp uniform vec4 A;\n\
+            uniform mediump vec3 B;\n\
+            uniform float C;\n\
+            void main() { gl_FragColor = A + vec4(B, C); }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        let precisions: Vec<_> = uniforms
+            .iter()
+            .map(|u| (u.name.as_str(), u.precision.clone()))
+            .collect();
+        assert_eq!(
+            precisions,
+            [
+                ("A", Some(PrecisionQualifierData::High)),
+                ("B", Some(PrecisionQualifierData::Medium)),
+                ("C", None),
+            ]
+        );
+
+        let content = "precision mediump float;\n\
+            uniform varying vec4 A;\n\
+            void main() { gl_FragColor = A; }\n";
+        let err = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap_err();
+        let diagnostic = err.downcast::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(
+            diagnostic.message,
+            "uniform A 不能同时使用存储限定符 varying"
+        );
+
+        let content = "precision mediump float;\n\
+            highp uniform lowp vec4 A;\n\
+            void main() { gl_FragColor = A; }\n";
+        let err = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap_err();
+        let diagnostic = err.downcast::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 2);
+        assert!(
+            diagnostic.message.contains("只能指定一个精度限定符"),
+            "{}",
+            diagnostic.message
+        );
+    }
+
+    #[test]
+    fn test_parse_glsl_const_array_length() {
+        let content = "#define MAX_LIGHTS 4\n\
+            const int N = 3;\n\
+            const int M = N * 2 - 1, K = int(2u);\n\
+            uniform vec4 LIGHTS[MAX_LIGHTS];\n\
+            uniform vec3 POINTS[N * 2];\n\
+            uniform float WEIGHTS[(M + K) / 2];\n\
+            void main() { gl_FragColor = LIGHTS[0] + vec4(POINTS[0], WEIGHTS[0]); }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        let lengths: Vec<_> = uniforms.iter().map(|u| u.array_length).collect();
+        assert_eq!(lengths, [Some(4), Some(6), Some(3)]);
+
+        let content = "uniform float SIZE;\n\
+            uniform vec4 A[int(SIZE)];\n\
+            void main() { gl_FragColor = A[0]; }\n";
+        let err = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap_err();
+        let diagnostic = err.downcast::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(
+            diagnostic.message,
+            "uniform A 的数组长度必须是可计算的整数常量表达式"
+        );
+
+        let content = "const int N = 0;\n\
+            uniform vec4 A, B[N];\n\
+            void main() { gl_FragColor = A + B[0]; }\n";
+        let err = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap_err();
+        let diagnostic = err.downcast::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(
+            diagnostic.message,
+            "uniform B 的数组长度必须大于 0, 实际为 0"
+        );
     }
 }
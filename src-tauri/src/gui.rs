@@ -0,0 +1,383 @@
+//! 图形界面的 Tauri 命令, 插件和启动入口, 桌面端和移动端共用
+
+use crate::check::check_shader;
+use crate::core::{self, BuildOptions, UniformEntry};
+use crate::diagnostic::Diagnostic;
+use crate::engine_uniforms::{ArrayShape, ENGINE_UNIFORMS};
+use crate::interpreter::Texture;
+use crate::ktex::{self, EncodeOptions, PixelFormat};
+use crate::lua;
+use crate::preprocessor::PreprocessOptions;
+use crate::preview::{self, read_texture, PreviewSettings};
+use crate::types::ShaderStage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShaderInfo {
+    name: String,
+    content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BuildKshParams {
+    output_path: String,
+    vs_name: String,
+    vs_content: String,
+    ps_name: String,
+    ps_content: String,
+    /// 预定义宏, 格式为 `NAME` 或 `NAME=VALUE`
+    #[serde(default)]
+    defines: Vec<String>,
+    /// #include 的搜索路径
+    #[serde(default)]
+    include_paths: Vec<String>,
+    /// 是否按 GLSL ES 1.00 规范检查
+    #[serde(default)]
+    es100: bool,
+    /// 是否跳过 naga 语义验证
+    #[serde(default)]
+    skip_validation: bool,
+    /// 是否在 .ksh 旁生成同名的 Lua 模块
+    #[serde(default)]
+    lua: bool,
+    /// 写入 KSH 的 uniform 默认值, 未指定的为 0
+    #[serde(default)]
+    uniform_defaults: HashMap<String, Vec<f32>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UniformTableParams {
+    vs_name: String,
+    vs_content: String,
+    ps_name: String,
+    ps_content: String,
+    #[serde(default)]
+    defines: Vec<String>,
+    #[serde(default)]
+    include_paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidateShaderParams {
+    stage: ShaderStage,
+    name: String,
+    content: String,
+    /// 另一个阶段的着色器, 提供时还会检查 varying 和 uniform 是否与其一致
+    #[serde(default)]
+    other_name: Option<String>,
+    #[serde(default)]
+    other_content: Option<String>,
+    #[serde(default)]
+    defines: Vec<String>,
+    #[serde(default)]
+    include_paths: Vec<String>,
+    #[serde(default)]
+    es100: bool,
+    #[serde(default)]
+    skip_validation: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenderPreviewParams {
+    ps_name: String,
+    ps_content: String,
+    /// 输入图片 (.png 或 .tex) 路径
+    image_path: String,
+    /// uniform 设置文件路径, 格式见 [`PreviewSettings`]
+    #[serde(default)]
+    settings_path: Option<String>,
+    /// 采样器绑定的纹理路径, 键如 `SAMPLER[1]`, 优先于设置文件
+    #[serde(default)]
+    textures: HashMap<String, String>,
+    #[serde(default)]
+    include_paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EncodeTexParams {
+    /// 输入的 PNG 图片路径
+    image_path: String,
+    /// 输出的 .tex 文件路径
+    output_path: String,
+    /// 像素格式: dxt1, dxt3, dxt5, rgba 或 rgb
+    format: String,
+    mipmaps: bool,
+    premultiply: bool,
+}
+
+/// 渲染结果, `data` 为 RGBA8 像素, 第一行为图像顶部
+#[derive(Debug, Serialize)]
+struct RenderedImage {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+/// 返回给前端的错误, 带有诊断信息时前端可以在编辑器中标出出错位置
+#[derive(Debug, Serialize)]
+struct CommandError {
+    message: String,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl From<Box<dyn std::error::Error>> for CommandError {
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        CommandError {
+            message: error.to_string(),
+            diagnostics: error
+                .downcast_ref::<Diagnostic>()
+                .cloned()
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError {
+            message,
+            diagnostics: vec![],
+        }
+    }
+}
+
+#[tauri::command]
+async fn analyze_ksh(file_path: String) -> Result<serde_json::Value, String> {
+    let input_path = Path::new(&file_path);
+    let ksh_content = fs::read(input_path).map_err(|e| e.to_string())?;
+
+    // 拖放和 --gui 打开的可能是任意文件, 损坏时返回错误给前端显示
+    let (vs_name, vs_content, ps_name, ps_content) = core::analyze_ksh(&ksh_content)
+        .map_err(|e| format!("无法解析 KSH 文件 {}: {}", file_path, e))?;
+
+    Ok(serde_json::json!({
+        "vs": {
+            "name": vs_name,
+            "content": vs_content
+        },
+        "ps": {
+            "name": ps_name,
+            "content": ps_content
+        }
+    }))
+}
+
+#[tauri::command]
+async fn build_ksh(params: BuildKshParams) -> Result<(), CommandError> {
+    let output_path = Path::new(&params.output_path);
+    let file_name = output_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| "无法从输出路径解析文件名".to_string())?;
+    let options = BuildOptions {
+        preprocess: PreprocessOptions {
+            defines: params
+                .defines
+                .iter()
+                .map(|d| PreprocessOptions::parse_define(d))
+                .collect::<Result<_, _>>()?,
+            include_paths: params.include_paths.iter().map(PathBuf::from).collect(),
+        },
+        es100: params.es100,
+        skip_validation: params.skip_validation,
+        uniform_defaults: params.uniform_defaults,
+    };
+
+    let ksh_content = core::build_ksh(
+        file_name,
+        &params.vs_name,
+        &params.vs_content,
+        &params.ps_name,
+        &params.ps_content,
+        &options,
+    )?;
+
+    fs::write(&params.output_path, ksh_content).map_err(|e| e.to_string())?;
+    if params.lua {
+        lua::write_lua_file(output_path, true, &options.preprocess)?;
+    }
+
+    Ok(())
+}
+
+/// 编辑时检查着色器, 返回所有诊断而不是在第一个错误处停止
+#[tauri::command]
+async fn validate_shader(params: ValidateShaderParams) -> Result<Vec<Diagnostic>, CommandError> {
+    let options = BuildOptions {
+        preprocess: PreprocessOptions {
+            defines: params
+                .defines
+                .iter()
+                .map(|d| PreprocessOptions::parse_define(d))
+                .collect::<Result<_, _>>()?,
+            include_paths: params.include_paths.iter().map(PathBuf::from).collect(),
+        },
+        es100: params.es100,
+        skip_validation: params.skip_validation,
+        ..Default::default()
+    };
+    let other = params
+        .other_name
+        .as_deref()
+        .zip(params.other_content.as_deref());
+    Ok(check_shader(
+        params.stage,
+        &params.name,
+        &params.content,
+        other,
+        &options,
+    ))
+}
+
+/// 当前编辑器内容构建出的 KSH 中的 uniform 表, 默认值均为 0, 由前端覆盖
+#[tauri::command]
+async fn get_uniforms(params: UniformTableParams) -> Result<Vec<UniformEntry>, CommandError> {
+    let options = BuildOptions {
+        preprocess: PreprocessOptions {
+            defines: params
+                .defines
+                .iter()
+                .map(|d| PreprocessOptions::parse_define(d))
+                .collect::<Result<_, _>>()?,
+            include_paths: params.include_paths.iter().map(PathBuf::from).collect(),
+        },
+        ..Default::default()
+    };
+    Ok(core::uniform_table(
+        &params.vs_name,
+        &params.vs_content,
+        &params.ps_name,
+        &params.ps_content,
+        &options,
+    )?)
+}
+
+#[tauri::command]
+async fn render_preview(params: RenderPreviewParams) -> Result<RenderedImage, CommandError> {
+    let options = PreprocessOptions {
+        include_paths: params.include_paths.iter().map(PathBuf::from).collect(),
+        ..Default::default()
+    };
+    let mut inputs = match &params.settings_path {
+        Some(path) => PreviewSettings::load(Path::new(path))?.into_inputs()?,
+        None => Default::default(),
+    };
+    for (slot, path) in &params.textures {
+        let image = read_texture(Path::new(path))?;
+        inputs.textures.insert(
+            slot.clone(),
+            Texture::from_rgba8(image.width, image.height, &image.data),
+        );
+    }
+    let image = read_texture(Path::new(&params.image_path))?;
+    let output = preview::render_preview(
+        Path::new(&params.ps_name),
+        &params.ps_content,
+        &image,
+        inputs,
+        &options,
+    )?;
+    Ok(RenderedImage {
+        width: output.width,
+        height: output.height,
+        data: output.data,
+    })
+}
+
+/// 像素着色器中可以绑定纹理的采样器, 如 `SAMPLER[0]`
+#[tauri::command]
+async fn get_sampler_slots(
+    ps_name: String,
+    ps_content: String,
+    include_paths: Vec<String>,
+) -> Result<Vec<String>, CommandError> {
+    let options = PreprocessOptions {
+        include_paths: include_paths.iter().map(PathBuf::from).collect(),
+        ..Default::default()
+    };
+    Ok(preview::find_sampler_slots(
+        Path::new(&ps_name),
+        &ps_content,
+        &options,
+    )?)
+}
+
+/// 把 PNG 图片转换为 KTEX 纹理, 与着色器一起打包到 mod 中
+#[tauri::command]
+async fn encode_tex(params: EncodeTexParams) -> Result<(), CommandError> {
+    let options = EncodeOptions {
+        pixel_format: PixelFormat::try_from(params.format.as_str())?,
+        mipmaps: params.mipmaps,
+        premultiply: params.premultiply,
+    };
+    ktex::encode_tex_file(
+        Path::new(&params.image_path),
+        Path::new(&params.output_path),
+        true,
+        &options,
+    )?;
+    Ok(())
+}
+
+#[tauri::command]
+fn get_engine_uniforms() -> serde_json::Value {
+    ENGINE_UNIFORMS
+        .iter()
+        .map(|u| {
+            serde_json::json!({
+                "name": u.name,
+                "type": u.type_names(),
+                "array": u.array == ArrayShape::AnyLength,
+                "declaration": u.declaration(),
+                "description": u.description,
+            })
+        })
+        .collect()
+}
+
+/// 启动时要打开的 .ksh 文件
+struct StartupFile(Option<String>);
+
+#[tauri::command]
+fn get_startup_file(startup_file: tauri::State<StartupFile>) -> Option<String> {
+    startup_file.0.clone()
+}
+
+/// 启动图形界面, `startup_file` 为启动后在编辑器中打开的 .ksh 文件
+pub fn run(startup_file: Option<PathBuf>) {
+    let startup_file = startup_file
+        .map(|path| std::path::absolute(&path).unwrap_or(path))
+        .map(|path| path.to_string_lossy().into_owned());
+    tauri::Builder::default()
+        .manage(StartupFile(startup_file))
+        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_dialog::init())
+        .invoke_handler(tauri::generate_handler![
+            analyze_ksh,
+            build_ksh,
+            validate_shader,
+            get_uniforms,
+            render_preview,
+            get_sampler_slots,
+            encode_tex,
+            get_engine_uniforms,
+            get_startup_file
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|_app, _event| {
+            // macOS 通过事件而不是命令行参数传入关联的文件
+            #[cfg(target_os = "macos")]
+            if let tauri::RunEvent::Opened { urls } = _event {
+                use tauri::Emitter;
+                for path in urls.iter().filter_map(|url| url.to_file_path().ok()) {
+                    let _ = _app.emit("open-file", path.to_string_lossy());
+                }
+            }
+        });
+}
@@ -0,0 +1,1671 @@
+//! 在 CPU 上逐像素执行像素着色器, 用于没有 GPU 的环境下预览和回归测试.
+//!
+//! 支持饥荒联机版着色器用到的 GLSL ES 子集: 标量, 向量, 矩阵和数组, 分量选择,
+//! 控制流, 自定义函数 (含 out/inout 参数), 常用内置函数和 `texture2D`.
+//! 没有相邻像素, 所以 `dFdx`/`dFdy`/`fwidth` 总是返回 0.
+
+use crate::diagnostic::Diagnostic;
+use crate::glsl_parser::ParsedShader;
+use crate::types::glsl_type_name;
+use glsl_lang::ast;
+use std::collections::HashMap;
+
+// 每个像素最多执行的循环次数, 防止死循环
+const MAX_LOOP_ITERATIONS: usize = 100_000;
+
+/// 着色器中的值. 向量按分量存储, 矩阵按列存储
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// `float` 和 `vec2`..`vec4`
+    Float(Vec<f32>),
+    /// `int` 和 `ivec2`..`ivec4`
+    Int(Vec<i32>),
+    /// `bool` 和 `bvec2`..`bvec4`
+    Bool(Vec<bool>),
+    /// `mat2`..`mat4`, 第二个值为阶数
+    Mat(Vec<f32>, usize),
+    /// 采样器, 值为绑定的纹理名称, 如 `SAMPLER[0]`
+    Sampler(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// 类型对应的初始值, 所有分量为 0
+    pub fn zero(ty: &ast::TypeSpecifierNonArrayData) -> Result<Value, String> {
+        use ast::TypeSpecifierNonArrayData as T;
+        Ok(match ty {
+            T::Float => Value::Float(vec![0.0]),
+            T::Vec2 => Value::Float(vec![0.0; 2]),
+            T::Vec3 => Value::Float(vec![0.0; 3]),
+            T::Vec4 => Value::Float(vec![0.0; 4]),
+            T::Int => Value::Int(vec![0]),
+            T::IVec2 => Value::Int(vec![0; 2]),
+            T::IVec3 => Value::Int(vec![0; 3]),
+            T::IVec4 => Value::Int(vec![0; 4]),
+            T::Bool => Value::Bool(vec![false]),
+            T::BVec2 => Value::Bool(vec![false; 2]),
+            T::BVec3 => Value::Bool(vec![false; 3]),
+            T::BVec4 => Value::Bool(vec![false; 4]),
+            T::Mat2 => Value::Mat(vec![0.0; 4], 2),
+            T::Mat3 => Value::Mat(vec![0.0; 9], 3),
+            T::Mat4 => Value::Mat(vec![0.0; 16], 4),
+            T::Sampler2D => Value::Sampler(String::new()),
+            _ => return Err(format!("不支持类型 {}", glsl_type_name(ty))),
+        })
+    }
+
+    /// 用一组数字按 `like` 的类型构造值, 数组按元素依次排列
+    pub fn from_floats(like: &Value, floats: &[f32]) -> Result<Value, String> {
+        let expected = like.flatten().len();
+        if floats.len() != expected {
+            return Err(format!("需要 {} 个数字, 实际为 {}", expected, floats.len()));
+        }
+        let mut offset = 0;
+        Ok(like.rebuild(floats, &mut offset))
+    }
+
+    fn rebuild(&self, floats: &[f32], offset: &mut usize) -> Value {
+        let mut take = |n: usize| {
+            let values = floats[*offset..*offset + n].to_vec();
+            *offset += n;
+            values
+        };
+        match self {
+            Value::Float(v) => Value::Float(take(v.len())),
+            Value::Int(v) => Value::Int(take(v.len()).into_iter().map(|f| f as i32).collect()),
+            Value::Bool(v) => Value::Bool(take(v.len()).into_iter().map(|f| f != 0.0).collect()),
+            Value::Mat(m, n) => Value::Mat(take(m.len()), *n),
+            Value::Sampler(name) => Value::Sampler(name.clone()),
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|i| i.rebuild(floats, offset)).collect())
+            }
+        }
+    }
+
+    /// 所有分量转为 float, 用于构造函数
+    fn flatten(&self) -> Vec<f32> {
+        match self {
+            Value::Float(v) => v.clone(),
+            Value::Int(v) => v.iter().map(|&i| i as f32).collect(),
+            Value::Bool(v) => v.iter().map(|&b| if b { 1.0 } else { 0.0 }).collect(),
+            Value::Mat(m, _) => m.clone(),
+            Value::Sampler(_) => vec![],
+            Value::Array(items) => items.iter().flat_map(Value::flatten).collect(),
+        }
+    }
+
+    fn type_name(&self) -> String {
+        let vector = |prefix: &str, scalar: &str, n: usize| match n {
+            1 => scalar.to_string(),
+            n => format!("{}vec{}", prefix, n),
+        };
+        match self {
+            Value::Float(v) => vector("", "float", v.len()),
+            Value::Int(v) => vector("i", "int", v.len()),
+            Value::Bool(v) => vector("b", "bool", v.len()),
+            Value::Mat(_, n) => format!("mat{}", n),
+            Value::Sampler(_) => "sampler2D".to_string(),
+            Value::Array(items) => match items.first() {
+                Some(item) => format!("{}[{}]", item.type_name(), items.len()),
+                None => "[]".to_string(),
+            },
+        }
+    }
+
+    // 类型相同 (数组还要求长度相同)
+    fn same_type(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a.len() == b.len(),
+            (Value::Int(a), Value::Int(b)) => a.len() == b.len(),
+            (Value::Bool(a), Value::Bool(b)) => a.len() == b.len(),
+            (Value::Mat(_, a), Value::Mat(_, b)) => a == b,
+            (Value::Sampler(_), Value::Sampler(_)) => true,
+            (Value::Array(a), Value::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.same_type(b))
+            }
+            _ => false,
+        }
+    }
+
+    // 赋值时把 int 转为 float, 兼容桌面 GLSL 的隐式转换
+    fn coerce(self, like: &Value) -> Value {
+        match (self, like) {
+            (Value::Int(v), Value::Float(_)) => Value::Float(v.iter().map(|&i| i as f32).collect()),
+            (value, _) => value,
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            Value::Bool(v) if v.len() == 1 => Ok(v[0]),
+            _ => Err(format!("需要 bool, 实际为 {}", self.type_name())),
+        }
+    }
+
+    fn as_index(&self) -> Result<usize, String> {
+        match self {
+            Value::Int(v) if v.len() == 1 && v[0] >= 0 => Ok(v[0] as usize),
+            Value::Int(v) if v.len() == 1 => Err(format!("下标 {} 越界", v[0])),
+            _ => Err(format!("下标需要 int, 实际为 {}", self.type_name())),
+        }
+    }
+
+    fn floats(&self) -> Result<&[f32], String> {
+        match self {
+            Value::Float(v) => Ok(v),
+            _ => Err(format!("需要 float 或 vec, 实际为 {}", self.type_name())),
+        }
+    }
+
+    fn index(&self, index: usize) -> Result<Value, String> {
+        let out_of_range = || format!("下标 {} 超出 {} 的范围", index, self.type_name());
+        match self {
+            Value::Array(items) => items.get(index).cloned().ok_or_else(out_of_range),
+            Value::Float(v) if v.len() > 1 => v
+                .get(index)
+                .map(|&f| Value::Float(vec![f]))
+                .ok_or_else(out_of_range),
+            Value::Int(v) if v.len() > 1 => v
+                .get(index)
+                .map(|&i| Value::Int(vec![i]))
+                .ok_or_else(out_of_range),
+            Value::Bool(v) if v.len() > 1 => v
+                .get(index)
+                .map(|&b| Value::Bool(vec![b]))
+                .ok_or_else(out_of_range),
+            Value::Mat(m, n) if index < *n => {
+                Ok(Value::Float(m[index * n..(index + 1) * n].to_vec()))
+            }
+            _ => Err(out_of_range()),
+        }
+    }
+
+    fn set_index(&mut self, index: usize, value: Value) -> Result<(), String> {
+        let type_name = self.type_name();
+        let out_of_range = || format!("下标 {} 超出 {} 的范围", index, type_name);
+        match (self, value) {
+            (Value::Array(items), value) => {
+                let item = items.get_mut(index).ok_or_else(out_of_range)?;
+                *item = value.coerce(item);
+            }
+            (Value::Float(v), Value::Float(f)) if f.len() == 1 => {
+                *v.get_mut(index).ok_or_else(out_of_range)? = f[0]
+            }
+            (Value::Int(v), Value::Int(i)) if i.len() == 1 => {
+                *v.get_mut(index).ok_or_else(out_of_range)? = i[0]
+            }
+            (Value::Bool(v), Value::Bool(b)) if b.len() == 1 => {
+                *v.get_mut(index).ok_or_else(out_of_range)? = b[0]
+            }
+            (Value::Mat(m, n), Value::Float(column)) if column.len() == *n && index < *n => {
+                m[index * *n..(index + 1) * *n].copy_from_slice(&column);
+            }
+            (_, value) => {
+                return Err(format!(
+                    "不能把 {} 赋值给 {} 的元素",
+                    value.type_name(),
+                    type_name
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn swizzle(&self, fields: &str) -> Result<Value, String> {
+        let indices = swizzle_indices(fields, self.component_count())?;
+        match self {
+            Value::Float(v) => Ok(Value::Float(indices.iter().map(|&i| v[i]).collect())),
+            Value::Int(v) => Ok(Value::Int(indices.iter().map(|&i| v[i]).collect())),
+            Value::Bool(v) => Ok(Value::Bool(indices.iter().map(|&i| v[i]).collect())),
+            _ => Err(format!("{} 没有分量 {}", self.type_name(), fields)),
+        }
+    }
+
+    fn set_swizzle(&mut self, fields: &str, value: Value) -> Result<(), String> {
+        let indices = swizzle_indices(fields, self.component_count())?;
+        let type_name = self.type_name();
+        let mismatch = |value: &Value| {
+            format!(
+                "不能把 {} 赋值给 {}.{}",
+                value.type_name(),
+                type_name,
+                fields
+            )
+        };
+        match (self, &value) {
+            (Value::Float(v), Value::Float(f)) if f.len() == indices.len() => {
+                indices.iter().zip(f).for_each(|(&i, &f)| v[i] = f)
+            }
+            (Value::Int(v), Value::Int(f)) if f.len() == indices.len() => {
+                indices.iter().zip(f).for_each(|(&i, &f)| v[i] = f)
+            }
+            (Value::Bool(v), Value::Bool(f)) if f.len() == indices.len() => {
+                indices.iter().zip(f).for_each(|(&i, &f)| v[i] = f)
+            }
+            _ => return Err(mismatch(&value)),
+        }
+        Ok(())
+    }
+
+    fn component_count(&self) -> usize {
+        match self {
+            Value::Float(v) => v.len(),
+            Value::Int(v) => v.len(),
+            Value::Bool(v) => v.len(),
+            _ => 0,
+        }
+    }
+}
+
+fn swizzle_indices(fields: &str, count: usize) -> Result<Vec<usize>, String> {
+    if fields.is_empty() || fields.len() > 4 {
+        return Err(format!("无效的分量 {}", fields));
+    }
+    fields
+        .chars()
+        .map(|c| {
+            let index = match c {
+                'x' | 'r' | 's' => 0,
+                'y' | 'g' | 't' => 1,
+                'z' | 'b' | 'p' => 2,
+                'w' | 'a' | 'q' => 3,
+                _ => return Err(format!("无效的分量 {}", fields)),
+            };
+            if index < count {
+                Ok(index)
+            } else {
+                Err(format!("分量 {} 超出范围", fields))
+            }
+        })
+        .collect()
+}
+
+/// 采样用的纹理, 第一行对应 v = 0
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    texels: Vec<[f32; 4]>,
+}
+
+impl Texture {
+    /// 从 RGBA8 数据创建纹理
+    pub fn from_rgba8(width: u32, height: u32, data: &[u8]) -> Self {
+        let texels = data
+            .chunks_exact(4)
+            .map(|p| {
+                [
+                    p[0] as f32 / 255.0,
+                    p[1] as f32 / 255.0,
+                    p[2] as f32 / 255.0,
+                    p[3] as f32 / 255.0,
+                ]
+            })
+            .collect();
+        Texture {
+            width,
+            height,
+            texels,
+        }
+    }
+
+    // 双线性过滤, 超出范围时取边缘的像素
+    fn sample(&self, u: f32, v: f32) -> [f32; 4] {
+        if self.width == 0 || self.height == 0 {
+            return [0.0; 4];
+        }
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let x = (x as i64).clamp(0, self.width as i64 - 1) as usize;
+            let y = (y as i64).clamp(0, self.height as i64 - 1) as usize;
+            self.texels[y * self.width as usize + x]
+        };
+        let (a, b) = (texel(x0, y0), texel(x0 + 1.0, y0));
+        let (c, d) = (texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+        let mut result = [0.0; 4];
+        for i in 0..4 {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            result[i] = top + (bottom - top) * fy;
+        }
+        result
+    }
+}
+
+/// 执行着色器所需的输入
+#[derive(Default)]
+pub struct RenderInputs {
+    /// uniform 的值, 按类型依次排列的数字, 数组按元素依次排列. 没有指定的 uniform 为 0
+    pub uniforms: HashMap<String, Vec<f32>>,
+    /// 所有像素共用的 varying 值. 没有指定的 vec2 varying 为像素的纹理坐标, 其他为 0
+    pub varyings: HashMap<String, Vec<f32>>,
+    /// 采样器绑定的纹理, 键为 uniform 名称, 数组元素为 `NAME[i]`
+    pub textures: HashMap<String, Texture>,
+}
+
+/// 对 `width` x `height` 的每个像素执行像素着色器, 返回 RGBA8 图像, 第一行为图像顶部.
+///
+/// vec2 varying 默认是像素中心的纹理坐标 (左上角为 (0, 0)), 与纹理的方向一致,
+/// 所以直接输出 `texture2D(SAMPLER[0], uv)` 会得到原图. `gl_FragCoord` 与 OpenGL 一致, 原点在左下角.
+/// 执行了 `discard` 的像素为全透明.
+pub fn render(
+    shader: &ParsedShader,
+    width: u32,
+    height: u32,
+    inputs: &RenderInputs,
+) -> Result<Vec<u8>, Diagnostic> {
+    let mut interpreter = Interpreter::new(shader, &inputs.textures)?;
+    interpreter.init_globals(inputs)?;
+    let main = interpreter
+        .functions
+        .get("main")
+        .and_then(|f| f.first().copied())
+        .ok_or_else(|| Diagnostic::error(1, "没有找到 main 函数"))?;
+
+    let base = interpreter.globals.clone();
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in 0..height {
+        for column in 0..width {
+            let u = (column as f32 + 0.5) / width as f32;
+            let v = (row as f32 + 0.5) / height as f32;
+            interpreter.globals.clone_from(&base);
+            for (name, like) in &interpreter.varyings {
+                let value = match inputs.varyings.get(name) {
+                    Some(floats) => Value::from_floats(like, floats)
+                        .map_err(|e| Diagnostic::error(1, format!("varying {} {}", name, e)))?,
+                    None if like.same_type(&Value::Float(vec![0.0; 2])) => Value::Float(vec![u, v]),
+                    None => like.clone(),
+                };
+                interpreter.globals.insert(name.clone(), value);
+            }
+            interpreter.globals.insert(
+                "gl_FragCoord".to_string(),
+                Value::Float(vec![
+                    column as f32 + 0.5,
+                    (height - row) as f32 - 0.5,
+                    0.5,
+                    1.0,
+                ]),
+            );
+            interpreter.loop_iterations = 0;
+
+            let colour = match interpreter.call(main, vec![], main.span)?.0 {
+                Flow::Discard => [0.0; 4],
+                _ => interpreter.frag_colour(),
+            };
+            pixels.extend(
+                colour
+                    .iter()
+                    .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8),
+            );
+        }
+    }
+    Ok(pixels)
+}
+
+// 语句执行的结果
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Option<Value>),
+    Discard,
+}
+
+struct Interpreter<'a> {
+    shader: &'a ParsedShader,
+    functions: HashMap<&'a str, Vec<&'a ast::FunctionDefinition>>,
+    textures: &'a HashMap<String, Texture>,
+    globals: HashMap<String, Value>,
+    /// 声明的 varying 及其初始值
+    varyings: Vec<(String, Value)>,
+    /// 函数调用栈, 每层是嵌套的作用域
+    frames: Vec<Vec<HashMap<String, Value>>>,
+    loop_iterations: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(
+        shader: &'a ParsedShader,
+        textures: &'a HashMap<String, Texture>,
+    ) -> Result<Self, Diagnostic> {
+        let mut functions: HashMap<&str, Vec<&ast::FunctionDefinition>> = HashMap::new();
+        for decl in &shader.declarations {
+            if let ast::ExternalDeclarationData::FunctionDefinition(f) = &decl.content {
+                functions
+                    .entry(f.content.prototype.name.content.as_str())
+                    .or_default()
+                    .push(f);
+            }
+        }
+        Ok(Interpreter {
+            shader,
+            functions,
+            textures,
+            globals: HashMap::new(),
+            varyings: vec![],
+            frames: vec![],
+            loop_iterations: 0,
+        })
+    }
+
+    fn error(&self, span: Option<ast::NodeSpan>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(self.shader.line_of(span), message)
+    }
+
+    // 内置变量, uniform, varying 和全局变量
+    fn init_globals(&mut self, inputs: &RenderInputs) -> Result<(), Diagnostic> {
+        self.globals
+            .insert("gl_FragColor".to_string(), Value::Float(vec![0.0; 4]));
+        self.globals.insert(
+            "gl_FragData".to_string(),
+            Value::Array(vec![Value::Float(vec![0.0; 4])]),
+        );
+        self.globals
+            .insert("gl_FrontFacing".to_string(), Value::Bool(vec![true]));
+        self.globals
+            .insert("gl_PointCoord".to_string(), Value::Float(vec![0.5, 0.5]));
+
+        let shader = self.shader;
+        for decl in &shader.declarations {
+            let ast::ExternalDeclarationData::Declaration(decl) = &decl.content else {
+                continue;
+            };
+            let list = match &decl.content {
+                ast::DeclarationData::InitDeclaratorList(list) => list,
+                ast::DeclarationData::Block(_) => {
+                    return Err(self.error(decl.span, "不支持接口块"));
+                }
+                _ => continue,
+            };
+            let storage = list
+                .head
+                .ty
+                .qualifier
+                .iter()
+                .flat_map(|q| &q.qualifiers)
+                .find_map(|q| match &q.content {
+                    ast::TypeQualifierSpecData::Storage(s) => Some(s.content.clone()),
+                    _ => None,
+                });
+            for (name, value) in self.declare(list, decl.span)? {
+                let value = match storage {
+                    Some(ast::StorageQualifierData::Uniform) => {
+                        self.uniform_value(&name, value, inputs, decl.span)?
+                    }
+                    Some(ast::StorageQualifierData::Varying | ast::StorageQualifierData::In) => {
+                        self.varyings.push((name.clone(), value.clone()));
+                        value
+                    }
+                    _ => value,
+                };
+                self.globals.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn uniform_value(
+        &self,
+        name: &str,
+        value: Value,
+        inputs: &RenderInputs,
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Value, Diagnostic> {
+        // 采样器的值是绑定的纹理名称
+        let value = match value {
+            Value::Sampler(_) => Value::Sampler(name.to_string()),
+            Value::Array(items) if matches!(items.first(), Some(Value::Sampler(_))) => {
+                Value::Array(
+                    (0..items.len())
+                        .map(|i| Value::Sampler(format!("{}[{}]", name, i)))
+                        .collect(),
+                )
+            }
+            value => value,
+        };
+        match inputs.uniforms.get(name) {
+            Some(floats) => Value::from_floats(&value, floats)
+                .map_err(|e| self.error(span, format!("uniform {} 的值无效: {}", name, e))),
+            None => Ok(value),
+        }
+    }
+
+    // 计算声明中每个变量的初始值
+    fn declare(
+        &mut self,
+        list: &ast::InitDeclaratorList,
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Vec<(String, Value)>, Diagnostic> {
+        let head = &list.head;
+        let base = Value::zero(&head.ty.ty.ty.content).map_err(|e| self.error(span, e))?;
+        let base = self.array_of(base, head.ty.ty.array_specifier.as_ref(), span)?;
+        let head_declarator = head
+            .name
+            .as_ref()
+            .map(|n| (n, head.array_specifier.as_ref(), head.initializer.as_ref()));
+        let tail = list.tail.iter().map(|d| {
+            (
+                &d.ident.ident,
+                d.ident.array_spec.as_ref(),
+                d.initializer.as_ref(),
+            )
+        });
+
+        let mut values = vec![];
+        for (name, array_specifier, initializer) in head_declarator.into_iter().chain(tail) {
+            let zero = self.array_of(base.clone(), array_specifier, span)?;
+            let value = match initializer.map(|i| &i.content) {
+                Some(ast::InitializerData::Simple(expr)) => {
+                    let value = self.eval(expr)?.coerce(&zero);
+                    if !value.same_type(&zero) {
+                        return Err(self.error(
+                            span,
+                            format!(
+                                "不能用 {} 初始化 {} {}",
+                                value.type_name(),
+                                zero.type_name(),
+                                name.content.as_str()
+                            ),
+                        ));
+                    }
+                    value
+                }
+                Some(ast::InitializerData::List(_)) => {
+                    return Err(self.error(span, "不支持初始化列表"));
+                }
+                None => zero,
+            };
+            // 同一声明中后面的变量可以引用前面的变量
+            self.define(name.content.as_str(), value.clone());
+            values.push((name.content.as_str().to_string(), value));
+        }
+        Ok(values)
+    }
+
+    fn array_of(
+        &mut self,
+        item: Value,
+        array_specifier: Option<&ast::ArraySpecifier>,
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Value, Diagnostic> {
+        let Some(array_specifier) = array_specifier else {
+            return Ok(item);
+        };
+        let mut value = item;
+        for dimension in array_specifier.dimensions.iter().rev() {
+            let length = match &dimension.content {
+                ast::ArraySpecifierDimensionData::ExplicitlySized(size) => self
+                    .eval(size)?
+                    .as_index()
+                    .map_err(|e| self.error(span, e))?,
+                ast::ArraySpecifierDimensionData::Unsized => {
+                    return Err(self.error(span, "数组必须指定长度"));
+                }
+            };
+            value = Value::Array(vec![value; length]);
+        }
+        Ok(value)
+    }
+
+    fn define(&mut self, name: &str, value: Value) {
+        match self.frames.last_mut().and_then(|f| f.last_mut()) {
+            Some(scope) => {
+                scope.insert(name.to_string(), value);
+            }
+            None => {
+                self.globals.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Value> {
+        self.frames
+            .last()
+            .and_then(|f| f.iter().rev().find_map(|scope| scope.get(name)))
+            .or_else(|| self.globals.get(name))
+    }
+
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut Value> {
+        if let Some(frame) = self.frames.last_mut() {
+            if let Some(scope) = frame.iter_mut().rev().find(|s| s.contains_key(name)) {
+                return scope.get_mut(name);
+            }
+        }
+        self.globals.get_mut(name)
+    }
+
+    fn frag_colour(&self) -> [f32; 4] {
+        let colour = match self.globals.get("gl_FragColor") {
+            Some(Value::Float(v)) if v.iter().any(|&c| c != 0.0) => v.clone(),
+            _ => match self.globals.get("gl_FragData") {
+                Some(Value::Array(items)) => items[0].flatten(),
+                _ => vec![0.0; 4],
+            },
+        };
+        [colour[0], colour[1], colour[2], colour[3]]
+    }
+
+    // 调用自定义函数, 返回执行结果和参数的最终值
+    fn call(
+        &mut self,
+        function: &ast::FunctionDefinition,
+        args: Vec<Value>,
+        span: Option<ast::NodeSpan>,
+    ) -> Result<(Flow, HashMap<String, Value>), Diagnostic> {
+        if self.frames.len() > 64 {
+            return Err(self.error(span, "函数调用层数过多, 不支持递归"));
+        }
+        let mut scope = HashMap::new();
+        for (parameter, arg) in function.prototype.parameters.iter().zip(args) {
+            if let ast::FunctionParameterDeclarationData::Named(_, declarator) = &parameter.content
+            {
+                scope.insert(declarator.ident.ident.content.as_str().to_string(), arg);
+            }
+        }
+        self.frames.push(vec![scope]);
+        let flow = self.exec_list(&function.statement.statement_list);
+        let parameters = self
+            .frames
+            .pop()
+            .and_then(|mut frame| frame.drain(..).next())
+            .unwrap_or_default();
+        Ok((flow?, parameters))
+    }
+
+    fn call_user_function(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Option<Value>, Diagnostic> {
+        let Some(candidates) = self.functions.get(name).cloned() else {
+            return Ok(None);
+        };
+        let values = args
+            .iter()
+            .map(|arg| self.eval(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // 按参数个数和类型选择重载
+        let mut chosen = None;
+        for function in candidates
+            .iter()
+            .filter(|f| f.prototype.parameters.len() == values.len())
+        {
+            let matches =
+                function
+                    .prototype
+                    .parameters
+                    .iter()
+                    .zip(&values)
+                    .all(|(parameter, value)| {
+                        self.parameter_zero(parameter)
+                            .is_some_and(|zero| zero.same_type(value))
+                    });
+            if matches || chosen.is_none() {
+                chosen = Some(*function);
+            }
+            if matches {
+                break;
+            }
+        }
+        let Some(function) = chosen else {
+            return Err(self.error(span, format!("函数 {} 的参数个数不匹配", name)));
+        };
+
+        let (flow, parameters) = self.call(function, values, span)?;
+        // 写回 out/inout 参数
+        for (parameter, arg) in function.prototype.parameters.iter().zip(args) {
+            let ast::FunctionParameterDeclarationData::Named(Some(qualifier), declarator) =
+                &parameter.content
+            else {
+                continue;
+            };
+            let writes_back = qualifier.qualifiers.iter().any(|q| {
+                matches!(
+                    &q.content,
+                    ast::TypeQualifierSpecData::Storage(s)
+                        if matches!(s.content, ast::StorageQualifierData::Out | ast::StorageQualifierData::InOut)
+                )
+            });
+            if writes_back {
+                let name = declarator.ident.ident.content.as_str();
+                if let Some(value) = parameters.get(name) {
+                    self.assign(arg, value.clone())?;
+                }
+            }
+        }
+
+        match flow {
+            Flow::Return(value) => Ok(Some(value.unwrap_or(Value::Array(vec![])))),
+            Flow::Discard => Err(self.error(span, "不支持在 main 以外的函数中 discard")),
+            _ => Ok(Some(Value::Array(vec![]))),
+        }
+    }
+
+    fn parameter_zero(&self, parameter: &ast::FunctionParameterDeclaration) -> Option<Value> {
+        let ty = match &parameter.content {
+            ast::FunctionParameterDeclarationData::Named(_, declarator) => &declarator.ty,
+            ast::FunctionParameterDeclarationData::Unnamed(_, ty) => ty,
+        };
+        Value::zero(&ty.ty.content).ok()
+    }
+
+    fn exec(&mut self, stmt: &ast::Statement) -> Result<Flow, Diagnostic> {
+        match &stmt.content {
+            ast::StatementData::Declaration(decl) => {
+                if let ast::DeclarationData::InitDeclaratorList(list) = &decl.content {
+                    self.declare(list, decl.span)?;
+                }
+                Ok(Flow::Normal)
+            }
+            ast::StatementData::Expression(expr) => {
+                if let Some(expr) = &expr.0 {
+                    self.eval(expr)?;
+                }
+                Ok(Flow::Normal)
+            }
+            ast::StatementData::Selection(selection) => {
+                let cond = self.eval(&selection.cond)?;
+                let cond = cond
+                    .as_bool()
+                    .map_err(|e| self.error(selection.cond.span, e))?;
+                match &selection.rest.content {
+                    ast::SelectionRestStatementData::Statement(body) if cond => {
+                        self.exec_scoped(body)
+                    }
+                    ast::SelectionRestStatementData::Statement(_) => Ok(Flow::Normal),
+                    ast::SelectionRestStatementData::Else(yes, no) => {
+                        self.exec_scoped(if cond { yes } else { no })
+                    }
+                }
+            }
+            ast::StatementData::Switch(switch) => self.exec_switch(switch),
+            ast::StatementData::CaseLabel(_) => Ok(Flow::Normal),
+            ast::StatementData::Iteration(iteration) => self.exec_loop(iteration, stmt.span),
+            ast::StatementData::Jump(jump) => Ok(match &jump.content {
+                ast::JumpStatementData::Continue => Flow::Continue,
+                ast::JumpStatementData::Break => Flow::Break,
+                ast::JumpStatementData::Discard => Flow::Discard,
+                ast::JumpStatementData::Return(expr) => Flow::Return(match expr {
+                    Some(expr) => Some(self.eval(expr)?),
+                    None => None,
+                }),
+            }),
+            ast::StatementData::Compound(compound) => {
+                self.push_scope();
+                let flow = self.exec_list(&compound.statement_list);
+                self.pop_scope();
+                flow
+            }
+        }
+    }
+
+    fn exec_list(&mut self, statements: &[ast::Statement]) -> Result<Flow, Diagnostic> {
+        for stmt in statements {
+            let flow = self.exec(stmt)?;
+            if !matches!(flow, Flow::Normal) {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_scoped(&mut self, stmt: &ast::Statement) -> Result<Flow, Diagnostic> {
+        self.push_scope();
+        let flow = self.exec(stmt);
+        self.pop_scope();
+        flow
+    }
+
+    fn push_scope(&mut self) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.push(HashMap::new());
+        }
+    }
+
+    fn pop_scope(&mut self) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pop();
+        }
+    }
+
+    fn exec_switch(&mut self, switch: &ast::SwitchStatement) -> Result<Flow, Diagnostic> {
+        let head = self.eval(&switch.head)?;
+        let mut start = None;
+        for (i, stmt) in switch.body.iter().enumerate() {
+            if let ast::StatementData::CaseLabel(label) = &stmt.content {
+                match &label.content {
+                    ast::CaseLabelData::Case(expr) => {
+                        if start.is_none() && self.eval(expr)? == head {
+                            start = Some(i);
+                        }
+                    }
+                    ast::CaseLabelData::Def => {}
+                }
+            }
+        }
+        let default = switch.body.iter().position(|stmt| {
+            matches!(&stmt.content, ast::StatementData::CaseLabel(label)
+                if matches!(label.content, ast::CaseLabelData::Def))
+        });
+        let Some(start) = start.or(default) else {
+            return Ok(Flow::Normal);
+        };
+        self.push_scope();
+        let flow = self.exec_list(&switch.body[start..]);
+        self.pop_scope();
+        Ok(match flow? {
+            Flow::Break => Flow::Normal,
+            flow => flow,
+        })
+    }
+
+    fn exec_loop(
+        &mut self,
+        iteration: &ast::IterationStatement,
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Flow, Diagnostic> {
+        self.push_scope();
+        let result = self.exec_loop_inner(iteration, span);
+        self.pop_scope();
+        result
+    }
+
+    fn exec_loop_inner(
+        &mut self,
+        iteration: &ast::IterationStatement,
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Flow, Diagnostic> {
+        let (condition, body, post, check_first) = match &iteration.content {
+            ast::IterationStatementData::While(condition, body) => {
+                (Some(condition), body, None, true)
+            }
+            ast::IterationStatementData::DoWhile(body, _) => (None, body, None, false),
+            ast::IterationStatementData::For(init, rest, body) => {
+                match &init.content {
+                    ast::ForInitStatementData::Declaration(decl) => {
+                        if let ast::DeclarationData::InitDeclaratorList(list) = &decl.content {
+                            self.declare(list, decl.span)?;
+                        }
+                    }
+                    ast::ForInitStatementData::Expression(Some(expr)) => {
+                        self.eval(expr)?;
+                    }
+                    ast::ForInitStatementData::Expression(None) => {}
+                }
+                (rest.condition.as_ref(), body, rest.post_expr.as_ref(), true)
+            }
+        };
+
+        let mut first = true;
+        loop {
+            if check_first || !first {
+                let proceed = match (condition, &iteration.content) {
+                    (Some(condition), _) => self.eval_condition(condition)?,
+                    (None, ast::IterationStatementData::DoWhile(_, cond)) => {
+                        let value = self.eval(cond)?;
+                        value.as_bool().map_err(|e| self.error(cond.span, e))?
+                    }
+                    (None, _) => true,
+                };
+                if !proceed {
+                    break;
+                }
+            }
+            first = false;
+            self.loop_iterations += 1;
+            if self.loop_iterations > MAX_LOOP_ITERATIONS {
+                return Err(self.error(span, "循环次数过多, 可能是死循环"));
+            }
+            match self.exec_scoped(body)? {
+                Flow::Break => break,
+                Flow::Normal | Flow::Continue => {}
+                flow => return Ok(flow),
+            }
+            if let Some(post) = post {
+                self.eval(post)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn eval_condition(&mut self, condition: &ast::Condition) -> Result<bool, Diagnostic> {
+        let value = match &condition.content {
+            ast::ConditionData::Expr(expr) => self.eval(expr)?,
+            ast::ConditionData::Assignment(ty, name, initializer) => {
+                let ast::InitializerData::Simple(expr) = &initializer.content else {
+                    return Err(self.error(condition.span, "不支持初始化列表"));
+                };
+                let zero =
+                    Value::zero(&ty.ty.ty.content).map_err(|e| self.error(condition.span, e))?;
+                let value = self.eval(expr)?.coerce(&zero);
+                self.define(name.content.as_str(), value.clone());
+                value
+            }
+        };
+        value.as_bool().map_err(|e| self.error(condition.span, e))
+    }
+
+    fn eval(&mut self, expr: &ast::Expr) -> Result<Value, Diagnostic> {
+        let span = expr.span;
+        match &expr.content {
+            ast::ExprData::Variable(name) => self
+                .lookup(name.content.as_str())
+                .cloned()
+                .ok_or_else(|| self.error(span, format!("未定义的变量 {}", name.content.as_str()))),
+            ast::ExprData::IntConst(i) => Ok(Value::Int(vec![*i])),
+            ast::ExprData::UIntConst(u) => Ok(Value::Int(vec![*u as i32])),
+            ast::ExprData::BoolConst(b) => Ok(Value::Bool(vec![*b])),
+            ast::ExprData::FloatConst(f) => Ok(Value::Float(vec![*f])),
+            ast::ExprData::DoubleConst(d) => Ok(Value::Float(vec![*d as f32])),
+            ast::ExprData::Unary(op, operand) => match op.content {
+                ast::UnaryOpData::Inc | ast::UnaryOpData::Dec => {
+                    let value = self.eval(operand)?;
+                    let value = step(&value, op.content == ast::UnaryOpData::Inc)
+                        .map_err(|e| self.error(span, e))?;
+                    self.assign(operand, value.clone())?;
+                    Ok(value)
+                }
+                _ => {
+                    let value = self.eval(operand)?;
+                    unary(&op.content, value).map_err(|e| self.error(span, e))
+                }
+            },
+            ast::ExprData::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                // && 和 || 短路求值
+                match op.content {
+                    ast::BinaryOpData::And | ast::BinaryOpData::Or => {
+                        let l = left.as_bool().map_err(|e| self.error(span, e))?;
+                        if l == (op.content == ast::BinaryOpData::Or) {
+                            return Ok(Value::Bool(vec![l]));
+                        }
+                        let r = self.eval(right)?;
+                        let r = r.as_bool().map_err(|e| self.error(span, e))?;
+                        return Ok(Value::Bool(vec![r]));
+                    }
+                    _ => {}
+                }
+                let right = self.eval(right)?;
+                binary(&op.content, left, right).map_err(|e| self.error(span, e))
+            }
+            ast::ExprData::Ternary(cond, yes, no) => {
+                let cond = self.eval(cond)?;
+                if cond.as_bool().map_err(|e| self.error(span, e))? {
+                    self.eval(yes)
+                } else {
+                    self.eval(no)
+                }
+            }
+            ast::ExprData::Assignment(target, op, value) => {
+                let value = self.eval(value)?;
+                let value = match assignment_op(&op.content) {
+                    Some(op) => {
+                        let current = self.eval(target)?;
+                        binary(&op, current, value).map_err(|e| self.error(span, e))?
+                    }
+                    None => value,
+                };
+                self.assign(target, value.clone())?;
+                Ok(value)
+            }
+            ast::ExprData::Bracket(array, index) => {
+                let array = self.eval(array)?;
+                let index = self.eval(index)?;
+                let index = index.as_index().map_err(|e| self.error(span, e))?;
+                array.index(index).map_err(|e| self.error(span, e))
+            }
+            ast::ExprData::FunCall(fun, args) => self.eval_call(fun, args, span),
+            ast::ExprData::Dot(base, field) => {
+                let base = self.eval(base)?;
+                base.swizzle(field.content.as_str())
+                    .map_err(|e| self.error(span, e))
+            }
+            ast::ExprData::PostInc(operand) | ast::ExprData::PostDec(operand) => {
+                let value = self.eval(operand)?;
+                let increment = matches!(expr.content, ast::ExprData::PostInc(_));
+                let stepped = step(&value, increment).map_err(|e| self.error(span, e))?;
+                self.assign(operand, stepped)?;
+                Ok(value)
+            }
+            ast::ExprData::Comma(left, right) => {
+                self.eval(left)?;
+                self.eval(right)
+            }
+        }
+    }
+
+    fn assign(&mut self, target: &ast::Expr, value: Value) -> Result<(), Diagnostic> {
+        let span = target.span;
+        match &target.content {
+            ast::ExprData::Variable(name) => {
+                let name = name.content.as_str();
+                let Some(slot) = self.lookup_mut(name) else {
+                    return Err(self.error(span, format!("未定义的变量 {}", name)));
+                };
+                let value = value.coerce(slot);
+                if !value.same_type(slot) {
+                    let message = format!(
+                        "不能把 {} 赋值给 {} {}",
+                        value.type_name(),
+                        slot.type_name(),
+                        name
+                    );
+                    return Err(self.error(span, message));
+                }
+                *slot = value;
+                Ok(())
+            }
+            ast::ExprData::Bracket(base, index) => {
+                let index = self.eval(index)?;
+                let index = index.as_index().map_err(|e| self.error(span, e))?;
+                let mut container = self.eval(base)?;
+                container
+                    .set_index(index, value)
+                    .map_err(|e| self.error(span, e))?;
+                self.assign(base, container)
+            }
+            ast::ExprData::Dot(base, field) => {
+                let mut container = self.eval(base)?;
+                container
+                    .set_swizzle(field.content.as_str(), value)
+                    .map_err(|e| self.error(span, e))?;
+                self.assign(base, container)
+            }
+            _ => Err(self.error(span, "表达式不能被赋值")),
+        }
+    }
+
+    fn eval_call(
+        &mut self,
+        fun: &ast::FunIdentifier,
+        args: &[ast::Expr],
+        span: Option<ast::NodeSpan>,
+    ) -> Result<Value, Diagnostic> {
+        let name = match &fun.content {
+            ast::FunIdentifierData::TypeSpecifier(ty) => match &ty.ty.content {
+                ast::TypeSpecifierNonArrayData::TypeName(name) => name.as_str().to_string(),
+                other => {
+                    let zero = Value::zero(other).map_err(|e| self.error(span, e))?;
+                    let values = self.eval_args(args)?;
+                    return construct(&zero, &values).map_err(|e| self.error(span, e));
+                }
+            },
+            ast::FunIdentifierData::Expr(callee) => match &callee.content {
+                ast::ExprData::Variable(name) => name.content.as_str().to_string(),
+                _ => return Err(self.error(span, "不支持的函数调用")),
+            },
+        };
+
+        if let Some(value) = self.call_user_function(&name, args, span)? {
+            return Ok(value);
+        }
+        let values = self.eval_args(args)?;
+        match name.as_str() {
+            "texture2D" | "texture2DProj" | "texture2DLod" | "texture2DProjLod" => {
+                self.sample(&name, &values).map_err(|e| self.error(span, e))
+            }
+            _ => builtin(&name, &values).map_err(|e| self.error(span, e)),
+        }
+    }
+
+    fn eval_args(&mut self, args: &[ast::Expr]) -> Result<Vec<Value>, Diagnostic> {
+        args.iter().map(|arg| self.eval(arg)).collect()
+    }
+
+    // 纹理采样, 忽略 bias 和 lod; 没有绑定纹理的采样器返回 0
+    fn sample(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        let (Some(Value::Sampler(texture)), Some(coord)) = (args.first(), args.get(1)) else {
+            return Err(format!("{} 的参数无效", name));
+        };
+        let coord = coord.floats()?;
+        let (u, v) = match (name.contains("Proj"), coord.len()) {
+            (false, 2) => (coord[0], coord[1]),
+            (true, 3) => (coord[0] / coord[2], coord[1] / coord[2]),
+            (true, 4) => (coord[0] / coord[3], coord[1] / coord[3]),
+            _ => return Err(format!("{} 的纹理坐标无效", name)),
+        };
+        let texel = self
+            .textures
+            .get(texture)
+            .map_or([0.0; 4], |t| t.sample(u, v));
+        Ok(Value::Float(texel.to_vec()))
+    }
+}
+
+fn step(value: &Value, increment: bool) -> Result<Value, String> {
+    let delta = if increment { 1 } else { -1 };
+    match value {
+        Value::Int(v) => Ok(Value::Int(
+            v.iter().map(|i| i.wrapping_add(delta)).collect(),
+        )),
+        Value::Float(v) => Ok(Value::Float(v.iter().map(|f| f + delta as f32).collect())),
+        Value::Mat(m, n) => Ok(Value::Mat(m.iter().map(|f| f + delta as f32).collect(), *n)),
+        _ => Err(format!("{} 不能自增或自减", value.type_name())),
+    }
+}
+
+fn unary(op: &ast::UnaryOpData, value: Value) -> Result<Value, String> {
+    match (op, value) {
+        (ast::UnaryOpData::Add, value) => Ok(value),
+        (ast::UnaryOpData::Minus, Value::Float(v)) => {
+            Ok(Value::Float(v.iter().map(|f| -f).collect()))
+        }
+        (ast::UnaryOpData::Minus, Value::Int(v)) => {
+            Ok(Value::Int(v.iter().map(|i| i.wrapping_neg()).collect()))
+        }
+        (ast::UnaryOpData::Minus, Value::Mat(m, n)) => {
+            Ok(Value::Mat(m.iter().map(|f| -f).collect(), n))
+        }
+        (ast::UnaryOpData::Not, Value::Bool(v)) if v.len() == 1 => Ok(Value::Bool(vec![!v[0]])),
+        (ast::UnaryOpData::Complement, Value::Int(v)) => {
+            Ok(Value::Int(v.iter().map(|i| !i).collect()))
+        }
+        (_, value) => Err(format!("运算符不能用于 {}", value.type_name())),
+    }
+}
+
+fn assignment_op(op: &ast::AssignmentOpData) -> Option<ast::BinaryOpData> {
+    Some(match op {
+        ast::AssignmentOpData::Equal => return None,
+        ast::AssignmentOpData::Mult => ast::BinaryOpData::Mult,
+        ast::AssignmentOpData::Div => ast::BinaryOpData::Div,
+        ast::AssignmentOpData::Mod => ast::BinaryOpData::Mod,
+        ast::AssignmentOpData::Add => ast::BinaryOpData::Add,
+        ast::AssignmentOpData::Sub => ast::BinaryOpData::Sub,
+        ast::AssignmentOpData::LShift => ast::BinaryOpData::LShift,
+        ast::AssignmentOpData::RShift => ast::BinaryOpData::RShift,
+        ast::AssignmentOpData::And => ast::BinaryOpData::BitAnd,
+        ast::AssignmentOpData::Xor => ast::BinaryOpData::BitXor,
+        ast::AssignmentOpData::Or => ast::BinaryOpData::BitOr,
+    })
+}
+
+// 逐分量运算, 其中一边是标量时扩展到另一边的长度
+fn zip<T: Copy, R>(a: &[T], b: &[T], f: impl Fn(T, T) -> R) -> Result<Vec<R>, String> {
+    match (a.len(), b.len()) {
+        (x, y) if x == y => Ok(a.iter().zip(b).map(|(&a, &b)| f(a, b)).collect()),
+        (1, _) => Ok(b.iter().map(|&b| f(a[0], b)).collect()),
+        (_, 1) => Ok(a.iter().map(|&a| f(a, b[0])).collect()),
+        (x, y) => Err(format!("分量个数不一致: {} 和 {}", x, y)),
+    }
+}
+
+fn binary(op: &ast::BinaryOpData, left: Value, right: Value) -> Result<Value, String> {
+    use ast::BinaryOpData as Op;
+    let mismatch = |left: &Value, right: &Value| {
+        format!(
+            "运算符不能用于 {} 和 {}",
+            left.type_name(),
+            right.type_name()
+        )
+    };
+    match op {
+        Op::Equal => return Ok(Value::Bool(vec![left == right])),
+        Op::NonEqual => return Ok(Value::Bool(vec![left != right])),
+        Op::Xor => {
+            return Ok(Value::Bool(vec![left.as_bool()? != right.as_bool()?]));
+        }
+        Op::Or => return Ok(Value::Bool(vec![left.as_bool()? || right.as_bool()?])),
+        Op::And => return Ok(Value::Bool(vec![left.as_bool()? && right.as_bool()?])),
+        Op::Lt | Op::Gt | Op::Lte | Op::Gte => {
+            let (a, b) = match (&left, &right) {
+                (Value::Float(a), Value::Float(b)) if a.len() == 1 && b.len() == 1 => {
+                    (a[0] as f64, b[0] as f64)
+                }
+                (Value::Int(a), Value::Int(b)) if a.len() == 1 && b.len() == 1 => {
+                    (a[0] as f64, b[0] as f64)
+                }
+                _ => return Err(mismatch(&left, &right)),
+            };
+            let result = match op {
+                Op::Lt => a < b,
+                Op::Gt => a > b,
+                Op::Lte => a <= b,
+                _ => a >= b,
+            };
+            return Ok(Value::Bool(vec![result]));
+        }
+        _ => {}
+    }
+
+    match (&left, &right) {
+        (Value::Float(a), Value::Float(b)) => {
+            let f = match op {
+                Op::Add => |a: f32, b: f32| a + b,
+                Op::Sub => |a, b| a - b,
+                Op::Mult => |a, b| a * b,
+                Op::Div => |a, b| a / b,
+                _ => return Err(mismatch(&left, &right)),
+            };
+            Ok(Value::Float(zip(a, b, f)?))
+        }
+        (Value::Int(a), Value::Int(b)) => {
+            let f = match op {
+                Op::Add => |a: i32, b: i32| a.wrapping_add(b),
+                Op::Sub => |a: i32, b: i32| a.wrapping_sub(b),
+                Op::Mult => |a: i32, b: i32| a.wrapping_mul(b),
+                Op::Div => |a: i32, b: i32| a.checked_div(b).unwrap_or(0),
+                Op::Mod => |a: i32, b: i32| a.checked_rem(b).unwrap_or(0),
+                Op::BitAnd => |a, b| a & b,
+                Op::BitOr => |a, b| a | b,
+                Op::BitXor => |a, b| a ^ b,
+                Op::LShift => |a: i32, b: i32| a.wrapping_shl(b as u32),
+                Op::RShift => |a: i32, b: i32| a.wrapping_shr(b as u32),
+                _ => return Err(mismatch(&left, &right)),
+            };
+            Ok(Value::Int(zip(a, b, f)?))
+        }
+        (Value::Mat(a, n), Value::Mat(b, m)) if n == m => match op {
+            Op::Mult => {
+                let n = *n;
+                let mut result = vec![0.0; n * n];
+                for c in 0..n {
+                    for r in 0..n {
+                        result[c * n + r] = (0..n).map(|k| a[k * n + r] * b[c * n + k]).sum();
+                    }
+                }
+                Ok(Value::Mat(result, n))
+            }
+            Op::Add => Ok(Value::Mat(zip(a, b, |a, b| a + b)?, *n)),
+            Op::Sub => Ok(Value::Mat(zip(a, b, |a, b| a - b)?, *n)),
+            Op::Div => Ok(Value::Mat(zip(a, b, |a, b| a / b)?, *n)),
+            _ => Err(mismatch(&left, &right)),
+        },
+        (Value::Mat(m, n), Value::Float(v)) if *op == Op::Mult && v.len() == *n => {
+            let n = *n;
+            Ok(Value::Float(
+                (0..n)
+                    .map(|r| (0..n).map(|c| m[c * n + r] * v[c]).sum())
+                    .collect(),
+            ))
+        }
+        (Value::Float(v), Value::Mat(m, n)) if *op == Op::Mult && v.len() == *n => {
+            let n = *n;
+            Ok(Value::Float(
+                (0..n)
+                    .map(|c| (0..n).map(|r| v[r] * m[c * n + r]).sum())
+                    .collect(),
+            ))
+        }
+        (Value::Mat(m, n), Value::Float(s)) if s.len() == 1 => {
+            let f = float_op(op).ok_or_else(|| mismatch(&left, &right))?;
+            Ok(Value::Mat(m.iter().map(|&a| f(a, s[0])).collect(), *n))
+        }
+        (Value::Float(s), Value::Mat(m, n)) if s.len() == 1 => {
+            let f = float_op(op).ok_or_else(|| mismatch(&left, &right))?;
+            Ok(Value::Mat(m.iter().map(|&b| f(s[0], b)).collect(), *n))
+        }
+        _ => Err(mismatch(&left, &right)),
+    }
+}
+
+fn float_op(op: &ast::BinaryOpData) -> Option<fn(f32, f32) -> f32> {
+    match op {
+        ast::BinaryOpData::Add => Some(|a, b| a + b),
+        ast::BinaryOpData::Sub => Some(|a, b| a - b),
+        ast::BinaryOpData::Mult => Some(|a, b| a * b),
+        ast::BinaryOpData::Div => Some(|a, b| a / b),
+        _ => None,
+    }
+}
+
+// 构造函数, 如 vec4(rgb, 1.0), mat3(1.0), float(i)
+fn construct(zero: &Value, args: &[Value]) -> Result<Value, String> {
+    let floats: Vec<f32> = args.iter().flat_map(Value::flatten).collect();
+    if floats.is_empty() {
+        return Err(format!("{} 的构造函数缺少参数", zero.type_name()));
+    }
+    let single_scalar = args.len() == 1 && floats.len() == 1;
+    match zero {
+        Value::Mat(_, n) => {
+            let n = *n;
+            let mut m = vec![0.0; n * n];
+            match args {
+                [Value::Mat(source, k)] => {
+                    // 从其他阶数的矩阵构造, 多余的部分为单位矩阵
+                    for c in 0..n {
+                        for r in 0..n {
+                            m[c * n + r] = if c < *k && r < *k {
+                                source[c * k + r]
+                            } else if c == r {
+                                1.0
+                            } else {
+                                0.0
+                            };
+                        }
+                    }
+                }
+                _ if single_scalar => (0..n).for_each(|i| m[i * n + i] = floats[0]),
+                _ if floats.len() >= n * n => m.copy_from_slice(&floats[..n * n]),
+                _ => return Err(format!("mat{} 的构造函数参数不足", n)),
+            }
+            Ok(Value::Mat(m, n))
+        }
+        Value::Sampler(_) | Value::Array(_) => {
+            Err(format!("不支持 {} 的构造函数", zero.type_name()))
+        }
+        _ => {
+            let n = zero.component_count();
+            let values: Vec<f32> = if single_scalar {
+                vec![floats[0]; n]
+            } else if floats.len() >= n {
+                floats[..n].to_vec()
+            } else {
+                return Err(format!("{} 的构造函数参数不足", zero.type_name()));
+            };
+            Value::from_floats(zero, &values)
+        }
+    }
+}
+
+fn builtin(name: &str, args: &[Value]) -> Result<Value, String> {
+    let arg = |i: usize| -> Result<&[f32], String> {
+        args.get(i)
+            .ok_or_else(|| format!("{} 的参数不足", name))?
+            .floats()
+    };
+    let map = |f: fn(f32) -> f32| -> Result<Value, String> {
+        Ok(Value::Float(arg(0)?.iter().map(|&x| f(x)).collect()))
+    };
+    let map2 = |f: fn(f32, f32) -> f32| -> Result<Value, String> {
+        Ok(Value::Float(zip(arg(0)?, arg(1)?, f)?))
+    };
+    let dot = |a: &[f32], b: &[f32]| -> f32 { a.iter().zip(b).map(|(a, b)| a * b).sum() };
+    let compare = |f: fn(f32, f32) -> bool| -> Result<Value, String> {
+        let (a, b) = match (args.first(), args.get(1)) {
+            (Some(a), Some(b)) => (a.flatten(), b.flatten()),
+            _ => return Err(format!("{} 的参数不足", name)),
+        };
+        Ok(Value::Bool(zip(&a, &b, f)?))
+    };
+    let bools = || -> Result<&[bool], String> {
+        match args.first() {
+            Some(Value::Bool(v)) => Ok(v),
+            _ => Err(format!("{} 需要 bvec 参数", name)),
+        }
+    };
+
+    match name {
+        "radians" => map(f32::to_radians),
+        "degrees" => map(f32::to_degrees),
+        "sin" => map(f32::sin),
+        "cos" => map(f32::cos),
+        "tan" => map(f32::tan),
+        "asin" => map(f32::asin),
+        "acos" => map(f32::acos),
+        "atan" if args.len() == 2 => map2(f32::atan2),
+        "atan" => map(f32::atan),
+        "pow" => map2(f32::powf),
+        "exp" => map(f32::exp),
+        "log" => map(f32::ln),
+        "exp2" => map(f32::exp2),
+        "log2" => map(f32::log2),
+        "sqrt" => map(f32::sqrt),
+        "inversesqrt" => map(|x| 1.0 / x.sqrt()),
+        "abs" => map(f32::abs),
+        "sign" => map(|x| {
+            if x > 0.0 {
+                1.0
+            } else if x < 0.0 {
+                -1.0
+            } else {
+                0.0
+            }
+        }),
+        "floor" => map(f32::floor),
+        "ceil" => map(f32::ceil),
+        "fract" => map(|x| x - x.floor()),
+        "mod" => map2(|x, y| x - y * (x / y).floor()),
+        "min" => map2(f32::min),
+        "max" => map2(f32::max),
+        "step" => map2(|edge, x| if x < edge { 0.0 } else { 1.0 }),
+        "clamp" => {
+            let low = zip(arg(0)?, arg(1)?, f32::max)?;
+            Ok(Value::Float(zip(&low, arg(2)?, f32::min)?))
+        }
+        "mix" => {
+            let (x, y, a) = (arg(0)?, arg(1)?, arg(2)?);
+            let diff = zip(y, x, |y, x| y - x)?;
+            let scaled = zip(&diff, a, |d, a| d * a)?;
+            Ok(Value::Float(zip(x, &scaled, |x, s| x + s)?))
+        }
+        "smoothstep" => {
+            let (e0, e1, x) = (arg(0)?, arg(1)?, arg(2)?);
+            let range = zip(e1, e0, |e1, e0| e1 - e0)?;
+            let offset = zip(x, e0, |x, e0| x - e0)?;
+            let t = zip(&offset, &range, |o, r| (o / r).clamp(0.0, 1.0))?;
+            Ok(Value::Float(
+                t.iter().map(|t| t * t * (3.0 - 2.0 * t)).collect(),
+            ))
+        }
+        "length" => Ok(Value::Float(vec![dot(arg(0)?, arg(0)?).sqrt()])),
+        "distance" => {
+            let d = zip(arg(0)?, arg(1)?, |a, b| a - b)?;
+            Ok(Value::Float(vec![dot(&d, &d).sqrt()]))
+        }
+        "dot" => Ok(Value::Float(vec![dot(arg(0)?, arg(1)?)])),
+        "cross" => {
+            let (a, b) = (arg(0)?, arg(1)?);
+            if a.len() != 3 || b.len() != 3 {
+                return Err("cross 需要 vec3 参数".to_string());
+            }
+            Ok(Value::Float(vec![
+                a[1] * b[2] - a[2] * b[1],
+                a[2] * b[0] - a[0] * b[2],
+                a[0] * b[1] - a[1] * b[0],
+            ]))
+        }
+        "normalize" => {
+            let v = arg(0)?;
+            let length = dot(v, v).sqrt();
+            Ok(Value::Float(v.iter().map(|x| x / length).collect()))
+        }
+        "faceforward" => {
+            let (n, i, nref) = (arg(0)?, arg(1)?, arg(2)?);
+            let sign = if dot(nref, i) < 0.0 { 1.0 } else { -1.0 };
+            Ok(Value::Float(n.iter().map(|x| x * sign).collect()))
+        }
+        "reflect" => {
+            let (i, n) = (arg(0)?, arg(1)?);
+            let d = 2.0 * dot(n, i);
+            Ok(Value::Float(zip(i, n, |i, n| i - d * n)?))
+        }
+        "refract" => {
+            let (i, n) = (arg(0)?, arg(1)?);
+            let eta = arg(2)?.first().copied().unwrap_or(1.0);
+            let d = dot(n, i);
+            let k = 1.0 - eta * eta * (1.0 - d * d);
+            if k < 0.0 {
+                return Ok(Value::Float(vec![0.0; i.len()]));
+            }
+            Ok(Value::Float(zip(i, n, |i, n| {
+                eta * i - (eta * d + k.sqrt()) * n
+            })?))
+        }
+        "matrixCompMult" => match (args.first(), args.get(1)) {
+            (Some(Value::Mat(a, n)), Some(Value::Mat(b, m))) if n == m => {
+                Ok(Value::Mat(zip(a, b, |a, b| a * b)?, *n))
+            }
+            _ => Err("matrixCompMult 需要两个同阶矩阵".to_string()),
+        },
+        "lessThan" => compare(|a, b| a < b),
+        "lessThanEqual" => compare(|a, b| a <= b),
+        "greaterThan" => compare(|a, b| a > b),
+        "greaterThanEqual" => compare(|a, b| a >= b),
+        "equal" => compare(|a, b| a == b),
+        "notEqual" => compare(|a, b| a != b),
+        "any" => Ok(Value::Bool(vec![bools()?.iter().any(|&b| b)])),
+        "all" => Ok(Value::Bool(vec![bools()?.iter().all(|&b| b)])),
+        "not" => Ok(Value::Bool(bools()?.iter().map(|b| !b).collect())),
+        // 没有相邻像素, 导数总是 0
+        "dFdx" | "dFdy" | "fwidth" => map(|_| 0.0),
+        _ => Err(format!("不支持函数 {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_parser::parse_glsl;
+    use crate::preprocessor::PreprocessOptions;
+
+    // 渲染 1x1 的图像, 返回唯一像素的 RGBA8 值
+    fn run(ps: &str, inputs: &RenderInputs) -> Result<[u8; 4], Diagnostic> {
+        let shader = parse_glsl(ps, &PreprocessOptions::default()).unwrap();
+        let pixels = render(&shader, 1, 1, inputs)?;
+        Ok([pixels[0], pixels[1], pixels[2], pixels[3]])
+    }
+
+    fn run_error(ps: &str) -> Diagnostic {
+        run(ps, &RenderInputs::default()).unwrap_err()
+    }
+
+    #[test]
+    fn test_arithmetic_and_swizzle() {
+        let ps = "void main() {\n\
+            vec4 v = vec4(0.1, 0.2, 0.3, 0.4);\n\
+            v.xy = v.wz * 2.0;\n\
+            v.z = v.x - v.y * 0.5;\n\
+            int k = 7 / 2;\n\
+            mat2 m = mat2(1.0, 0.0, 0.0, 2.0);\n\
+            vec2 b = m * vec2(0.1, float(k) * 0.1);\n\
+            gl_FragColor = vec4(v.xyz, b.y - 0.2);\n\
+            }\n";
+        // v = (0.8, 0.6, 0.5), b.y = 2.0 * 0.3
+        assert_eq!(
+            run(ps, &RenderInputs::default()).unwrap(),
+            [204, 153, 128, 102]
+        );
+
+        let ps = "void main() {\n\
+            vec3 c = vec3(0.2, 0.4, 0.6);\n\
+            gl_FragColor = vec4(c.bgr, -c.r + 1.0).rgba;\n\
+            }\n";
+        assert_eq!(
+            run(ps, &RenderInputs::default()).unwrap(),
+            [153, 102, 51, 204]
+        );
+    }
+
+    #[test]
+    fn test_control_flow_and_loops() {
+        let ps = "float pick(float x) { if (x > 0.5) { return 1.0; } else { return 0.0; } }\n\
+            void main() {\n\
+            float sum = 0.0;\n\
+            for (int i = 0; i < 10; i++) {\n\
+            if (i == 2) continue;\n\
+            if (i == 5) break;\n\
+            sum += 0.1;\n\
+            }\n\
+            int n = 0;\n\
+            while (n < 3) { n++; }\n\
+            float t = 0.0;\n\
+            do { t += 0.2; } while (t < 0.5);\n\
+            gl_FragColor = vec4(sum, float(n) * 0.2, t, pick(0.7) > 0.0 ? 1.0 : 0.0);\n\
+            }\n";
+        assert_eq!(
+            run(ps, &RenderInputs::default()).unwrap(),
+            [102, 153, 153, 255]
+        );
+
+        // discard 的像素为全透明
+        let ps = "void main() { gl_FragColor = vec4(1.0); if (gl_FragColor.a > 0.5) discard; }\n";
+        assert_eq!(run(ps, &RenderInputs::default()).unwrap(), [0, 0, 0, 0]);
+
+        let ps = "void main() {\n\
+            float x = 0.0;\n\
+            while (true) { x += 1.0; }\n\
+            gl_FragColor = vec4(x);\n\
+            }\n";
+        let diagnostic = run_error(ps);
+        assert_eq!(diagnostic.line, 3);
+        assert!(diagnostic.message.contains("死循环"), "{}", diagnostic);
+    }
+
+    #[test]
+    fn test_builtins() {
+        let ps = "uniform sampler2D SAMPLER[2];\n\
+            void main() {\n\
+            vec4 left = texture2D(SAMPLER[1], vec2(0.25, 0.5));\n\
+            vec4 right = texture2D(SAMPLER[1], vec2(0.75, 0.5));\n\
+            vec4 unbound = texture2D(SAMPLER[0], vec2(0.5));\n\
+            float m = mix(left.r, right.r, 0.5);\n\
+            float c = clamp(right.g * 4.0, 0.0, 0.6);\n\
+            gl_FragColor = vec4(m, c, mix(vec2(0.0), vec2(0.4, 1.0), vec2(0.5)).x, unbound.a + 1.0);\n\
+            }\n";
+        let mut inputs = RenderInputs::default();
+        // 左边的像素为 (0.2, 0, 0, 1), 右边为 (0.6, 1, 0, 1)
+        inputs.textures.insert(
+            "SAMPLER[1]".to_string(),
+            Texture::from_rgba8(2, 1, &[51, 0, 0, 255, 153, 255, 0, 255]),
+        );
+        assert_eq!(run(ps, &inputs).unwrap(), [102, 153, 51, 255]);
+    }
+
+    #[test]
+    fn test_array_uniforms() {
+        let ps = "uniform vec4 COLOURS[2];\n\
+            uniform float WEIGHTS[3];\n\
+            void main() {\n\
+            float total = 0.0;\n\
+            for (int i = 0; i < 3; i++) { total += WEIGHTS[i]; }\n\
+            gl_FragColor = COLOURS[1] * total + COLOURS[0];\n\
+            }\n";
+        let mut inputs = RenderInputs::default();
+        inputs.uniforms.insert(
+            "COLOURS".to_string(),
+            vec![0.2, 0.0, 0.0, 0.0, 0.0, 0.4, 0.8, 1.0],
+        );
+        inputs
+            .uniforms
+            .insert("WEIGHTS".to_string(), vec![0.25, 0.25, 0.5]);
+        assert_eq!(run(ps, &inputs).unwrap(), [51, 102, 204, 255]);
+
+        // 值的个数与数组不一致
+        inputs
+            .uniforms
+            .insert("WEIGHTS".to_string(), vec![0.25, 0.25]);
+        let diagnostic = run(ps, &inputs).unwrap_err();
+        assert_eq!(diagnostic.line, 2);
+        assert!(diagnostic.message.contains("WEIGHTS"), "{}", diagnostic);
+    }
+
+    #[test]
+    fn test_unsupported_constructs() {
+        let diagnostic = run_error(
+            "precision mediump float;\n\
+            uniform samplerCube CUBE;\n\
+            void main() { gl_FragColor = vec4(1.0); }\n",
+        );
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(diagnostic.message, "不支持类型 samplerCube");
+
+        let diagnostic = run_error(
+            "uniform Block { vec4 TINT; };\n\
+            void main() { gl_FragColor = TINT; }\n",
+        );
+        assert_eq!(diagnostic.line, 1);
+        assert_eq!(diagnostic.message, "不支持接口块");
+
+        let diagnostic = run_error(
+            "float f(float x) { return f(x); }\n\
+            void main() { gl_FragColor = vec4(f(1.0)); }\n",
+        );
+        assert_eq!(diagnostic.line, 1);
+        assert!(diagnostic.message.contains("不支持递归"), "{}", diagnostic);
+
+        let diagnostic = run_error("void main() {\ngl_FragColor = vec4(unknown(1.0));\n}\n");
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(diagnostic.message, "不支持函数 unknown");
+
+        assert_eq!(
+            run_error("void helper() {}\n").message,
+            "没有找到 main 函数"
+        );
+    }
+}
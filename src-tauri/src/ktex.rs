@@ -0,0 +1,828 @@
+//! Klei 的 KTEX 纹理 (.tex) 格式.
+//!
+//! 文件以 `KTEX` 开头, 接着是 32 位的头部位域, 然后是每一级 mipmap 的尺寸和数据大小,
+//! 最后依次是每一级的像素数据. 像素数据按 OpenGL 的习惯从图像底部开始存储.
+
+use crate::image::{read_png, RgbaImage};
+use std::io::{self, Read};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"KTEX";
+
+/// 像素格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Dxt1,
+    Dxt3,
+    Dxt5,
+    Rgba,
+    Rgb,
+}
+
+impl PixelFormat {
+    pub fn from_id(id: u32) -> Result<Self, String> {
+        Ok(match id {
+            0 => PixelFormat::Dxt1,
+            1 => PixelFormat::Dxt3,
+            2 => PixelFormat::Dxt5,
+            4 => PixelFormat::Rgba,
+            5 => PixelFormat::Rgb,
+            _ => return Err(format!("不支持的像素格式: {}", id)),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            PixelFormat::Dxt1 => 0,
+            PixelFormat::Dxt3 => 1,
+            PixelFormat::Dxt5 => 2,
+            PixelFormat::Rgba => 4,
+            PixelFormat::Rgb => 5,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::Dxt1 => "dxt1",
+            PixelFormat::Dxt3 => "dxt3",
+            PixelFormat::Dxt5 => "dxt5",
+            PixelFormat::Rgba => "rgba",
+            PixelFormat::Rgb => "rgb",
+        }
+    }
+}
+
+impl TryFrom<&str> for PixelFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "dxt1" | "bc1" => Ok(PixelFormat::Dxt1),
+            "dxt3" | "bc2" => Ok(PixelFormat::Dxt3),
+            "dxt5" | "bc3" => Ok(PixelFormat::Dxt5),
+            "rgba" => Ok(PixelFormat::Rgba),
+            "rgb" => Ok(PixelFormat::Rgb),
+            _ => Err(format!(
+                "不支持的像素格式: {}, 可选 dxt1, dxt3, dxt5, rgba, rgb",
+                value
+            )),
+        }
+    }
+}
+
+/// 编码选项
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub pixel_format: PixelFormat,
+    /// 生成完整的 mipmap 链, 直到 1x1
+    pub mipmaps: bool,
+    /// 把颜色乘以 alpha, 游戏按预乘 alpha 混合
+    pub premultiply: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            pixel_format: PixelFormat::Dxt5,
+            mipmaps: true,
+            premultiply: true,
+        }
+    }
+}
+
+/// 一级 mipmap
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mipmap {
+    pub width: u16,
+    pub height: u16,
+    /// 一行的字节数, 压缩格式为一行块的字节数
+    pub pitch: u16,
+    pub data: Vec<u8>,
+}
+
+/// KTEX 纹理
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ktex {
+    /// 平台, 12 为 PC
+    pub platform: u32,
+    pub pixel_format: PixelFormat,
+    /// 纹理类型, 2 为二维纹理
+    pub texture_type: u32,
+    pub flags: u32,
+    pub mipmaps: Vec<Mipmap>,
+}
+
+fn read_u16(cursor: &mut io::Cursor<&[u8]>) -> io::Result<u16> {
+    let mut buffer = [0; 2];
+    cursor.read_exact(&mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+
+fn read_u32(cursor: &mut io::Cursor<&[u8]>) -> io::Result<u32> {
+    let mut buffer = [0; 4];
+    cursor.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+impl Ktex {
+    /// 解析 KTEX 文件内容
+    pub fn parse(content: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cursor = io::Cursor::new(content);
+        let mut magic = [0; 4];
+        cursor
+            .read_exact(&mut magic)
+            .map_err(|_| "不是 KTEX 文件")?;
+        if &magic != MAGIC {
+            return Err("不是 KTEX 文件".into());
+        }
+
+        // 联机版的头部高 12 位全为 1, 单机版早期的头部各字段更短
+        let header = read_u32(&mut cursor)?;
+        let (platform, pixel_format, texture_type, mipmap_count, flags) = if header >> 20 == 0xFFF {
+            (
+                header & 0xF,
+                (header >> 4) & 0x1F,
+                (header >> 9) & 0xF,
+                (header >> 13) & 0x1F,
+                (header >> 18) & 0x3,
+            )
+        } else {
+            (
+                header & 0x7,
+                (header >> 3) & 0x7,
+                (header >> 6) & 0x7,
+                (header >> 9) & 0xF,
+                (header >> 13) & 0x1,
+            )
+        };
+        let pixel_format = PixelFormat::from_id(pixel_format)?;
+
+        let mut sizes = vec![];
+        for _ in 0..mipmap_count {
+            let width = read_u16(&mut cursor)?;
+            let height = read_u16(&mut cursor)?;
+            let pitch = read_u16(&mut cursor)?;
+            let data_size = read_u32(&mut cursor)? as usize;
+            sizes.push((width, height, pitch, data_size));
+        }
+        let mut mipmaps = vec![];
+        for (width, height, pitch, data_size) in sizes {
+            // 数据长度来自文件, 先与剩余的字节数比较再分配
+            let remaining = content.len() - cursor.position() as usize;
+            if data_size > remaining {
+                return Err(format!(
+                    "mipmap {}x{} 的数据不完整: 需要 {} 字节, 剩余 {} 字节",
+                    width, height, data_size, remaining
+                )
+                .into());
+            }
+            let mut data = vec![0; data_size];
+            cursor
+                .read_exact(&mut data)
+                .map_err(|_| format!("mipmap {}x{} 的数据不完整", width, height))?;
+            mipmaps.push(Mipmap {
+                width,
+                height,
+                pitch,
+                data,
+            });
+        }
+        if mipmaps.is_empty() {
+            return Err("KTEX 文件中没有图像".into());
+        }
+
+        Ok(Ktex {
+            platform,
+            pixel_format,
+            texture_type,
+            flags,
+            mipmaps,
+        })
+    }
+
+    /// 解码一级 mipmap 为 RGBA8 图像, 第一行为图像顶部. 不改变预乘的 alpha
+    pub fn decode(&self, level: usize) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+        let mipmap = self
+            .mipmaps
+            .get(level)
+            .ok_or_else(|| format!("没有第 {} 级 mipmap", level))?;
+        let (width, height) = (mipmap.width as usize, mipmap.height as usize);
+        if width == 0 || height == 0 {
+            return Err("mipmap 的尺寸为 0".into());
+        }
+        let mut data = vec![0; width * height * 4];
+        match self.pixel_format {
+            PixelFormat::Rgba | PixelFormat::Rgb => {
+                let channels = if self.pixel_format == PixelFormat::Rgba {
+                    4
+                } else {
+                    3
+                };
+                let pitch = match mipmap.pitch as usize {
+                    0 => width * channels,
+                    pitch => pitch,
+                };
+                if mipmap.data.len() < pitch * height.saturating_sub(1) + width * channels {
+                    return Err(format!("mipmap {}x{} 的数据不完整", width, height).into());
+                }
+                for y in 0..height {
+                    for x in 0..width {
+                        let source = &mipmap.data[y * pitch + x * channels..][..channels];
+                        let target = &mut data[(y * width + x) * 4..][..4];
+                        target[..channels].copy_from_slice(source);
+                        if channels == 3 {
+                            target[3] = 255;
+                        }
+                    }
+                }
+            }
+            format => {
+                let block_size = if format == PixelFormat::Dxt1 { 8 } else { 16 };
+                let (blocks_x, blocks_y) = (width.div_ceil(4).max(1), height.div_ceil(4).max(1));
+                if mipmap.data.len() < blocks_x * blocks_y * block_size {
+                    return Err(format!("mipmap {}x{} 的数据不完整", width, height).into());
+                }
+                for (i, block) in mipmap
+                    .data
+                    .chunks_exact(block_size)
+                    .take(blocks_x * blocks_y)
+                    .enumerate()
+                {
+                    let texels = decode_block(format, block);
+                    let (bx, by) = (i % blocks_x * 4, i / blocks_x * 4);
+                    for (j, texel) in texels.iter().enumerate() {
+                        let (x, y) = (bx + j % 4, by + j / 4);
+                        if x < width && y < height {
+                            data[(y * width + x) * 4..][..4].copy_from_slice(texel);
+                        }
+                    }
+                }
+            }
+        }
+
+        // 翻转为从顶部开始
+        let row = width * 4;
+        let flipped = data.chunks_exact(row).rev().flatten().copied().collect();
+        Ok(RgbaImage {
+            width: width as u32,
+            height: height as u32,
+            data: flipped,
+        })
+    }
+}
+
+impl Ktex {
+    /// 把 RGBA8 图像 (第一行为图像顶部) 编码为 PC 平台的二维纹理
+    pub fn encode(
+        image: &RgbaImage,
+        options: &EncodeOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (width, height) = (image.width as usize, image.height as usize);
+        if width == 0 || height == 0 {
+            return Err("图像的尺寸为 0".into());
+        }
+        if width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(format!("图像尺寸 {}x{} 超出 KTEX 的限制", width, height).into());
+        }
+        if image.data.len() < width * height * 4 {
+            return Err("图像数据不完整".into());
+        }
+
+        let mut data = image.data[..width * height * 4].to_vec();
+        if options.premultiply {
+            for texel in data.chunks_exact_mut(4) {
+                let a = texel[3] as u32;
+                for c in &mut texel[..3] {
+                    *c = ((*c as u32 * a + 127) / 255) as u8;
+                }
+            }
+        }
+        // 翻转为从底部开始
+        let mut level: Vec<u8> = data
+            .chunks_exact(width * 4)
+            .rev()
+            .flatten()
+            .copied()
+            .collect();
+
+        let (mut w, mut h) = (width, height);
+        let mut mipmaps = vec![];
+        loop {
+            mipmaps.push(encode_mipmap(options.pixel_format, w, h, &level)?);
+            if !options.mipmaps || (w == 1 && h == 1) {
+                break;
+            }
+            (level, w, h) = downsample(&level, w, h);
+        }
+        if mipmaps.len() > 0x1F {
+            return Err("mipmap 数量超出 KTEX 的限制".into());
+        }
+
+        Ok(Ktex {
+            platform: 12,
+            pixel_format: options.pixel_format,
+            texture_type: 2,
+            flags: 0,
+            mipmaps,
+        })
+    }
+
+    /// 按联机版的头部格式序列化
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = (self.platform & 0xF)
+            | (self.pixel_format.id() & 0x1F) << 4
+            | (self.texture_type & 0xF) << 9
+            | (self.mipmaps.len() as u32 & 0x1F) << 13
+            | (self.flags & 0x3) << 18
+            | 0xFFF << 20;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&header.to_le_bytes());
+        for mipmap in &self.mipmaps {
+            bytes.extend_from_slice(&mipmap.width.to_le_bytes());
+            bytes.extend_from_slice(&mipmap.height.to_le_bytes());
+            bytes.extend_from_slice(&mipmap.pitch.to_le_bytes());
+            bytes.extend_from_slice(&(mipmap.data.len() as u32).to_le_bytes());
+        }
+        for mipmap in &self.mipmaps {
+            bytes.extend_from_slice(&mipmap.data);
+        }
+        bytes
+    }
+}
+
+/// 读取 .tex 文件并解码最大的一级 mipmap
+pub fn read_tex(path: &Path) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+    let content =
+        std::fs::read(path).map_err(|e| format!("读取纹理 {} 失败: {}", path.display(), e))?;
+    Ktex::parse(&content)
+        .and_then(|tex| tex.decode(0))
+        .map_err(|e| format!("解码纹理 {} 失败: {}", path.display(), e).into())
+}
+
+/// 读取 PNG 图像, 编码后写入 .tex 文件
+pub fn encode_tex_file(
+    image_path: &Path,
+    out_path: &Path,
+    force: bool,
+    options: &EncodeOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !force && out_path.exists() {
+        return Err(format!("输出文件已存在: {}", out_path.display()).into());
+    }
+    let image = read_png(image_path)?;
+    let tex = Ktex::encode(&image, options)
+        .map_err(|e| format!("编码纹理 {} 失败: {}", image_path.display(), e))?;
+    std::fs::write(out_path, tex.to_bytes())
+        .map_err(|e| format!("写入文件 {} 失败: {}", out_path.display(), e))?;
+    log::info!(
+        "已写入: {} ({}x{}, {}, {} 级 mipmap)",
+        out_path.display(),
+        image.width,
+        image.height,
+        options.pixel_format.name(),
+        tex.mipmaps.len()
+    );
+    Ok(())
+}
+
+// 2x2 平均缩小一半, 奇数尺寸时最后一行/列与自身平均
+fn downsample(data: &[u8], width: usize, height: usize) -> (Vec<u8>, usize, usize) {
+    let (w, h) = ((width / 2).max(1), (height / 2).max(1));
+    let mut result = vec![0; w * h * 4];
+    for y in 0..h {
+        for x in 0..w {
+            let (x0, y0) = ((x * 2).min(width - 1), (y * 2).min(height - 1));
+            let (x1, y1) = ((x * 2 + 1).min(width - 1), (y * 2 + 1).min(height - 1));
+            for c in 0..4 {
+                let sum: u32 = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)]
+                    .iter()
+                    .map(|&(sx, sy)| data[(sy * width + sx) * 4 + c] as u32)
+                    .sum();
+                result[(y * w + x) * 4 + c] = ((sum + 2) / 4) as u8;
+            }
+        }
+    }
+    (result, w, h)
+}
+
+// 编码一级 mipmap, data 为从底部开始的 RGBA8 像素
+fn encode_mipmap(
+    format: PixelFormat,
+    width: usize,
+    height: usize,
+    data: &[u8],
+) -> Result<Mipmap, Box<dyn std::error::Error>> {
+    let (bytes, pitch) = match format {
+        PixelFormat::Rgba => (data.to_vec(), width * 4),
+        PixelFormat::Rgb => (
+            data.chunks_exact(4)
+                .flat_map(|t| [t[0], t[1], t[2]])
+                .collect(),
+            width * 3,
+        ),
+        format => {
+            let block_size = if format == PixelFormat::Dxt1 { 8 } else { 16 };
+            let (blocks_x, blocks_y) = (width.div_ceil(4), height.div_ceil(4));
+            let mut bytes = Vec::with_capacity(blocks_x * blocks_y * block_size);
+            for by in 0..blocks_y {
+                for bx in 0..blocks_x {
+                    // 超出图像的部分重复边缘像素
+                    let mut texels = [[0u8; 4]; 16];
+                    for (j, texel) in texels.iter_mut().enumerate() {
+                        let x = (bx * 4 + j % 4).min(width - 1);
+                        let y = (by * 4 + j / 4).min(height - 1);
+                        texel.copy_from_slice(&data[(y * width + x) * 4..][..4]);
+                    }
+                    bytes.extend_from_slice(&encode_block(format, &texels));
+                }
+            }
+            (bytes, blocks_x * block_size)
+        }
+    };
+    let pitch = u16::try_from(pitch).map_err(|_| "图像过宽, 行字节数超出 KTEX 的限制")?;
+    Ok(Mipmap {
+        width: width as u16,
+        height: height as u16,
+        pitch,
+        data: bytes,
+    })
+}
+
+fn rgb565(value: u16) -> [u8; 3] {
+    let r = (value >> 11 & 0x1F) as u8;
+    let g = (value >> 5 & 0x3F) as u8;
+    let b = (value & 0x1F) as u8;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+fn to_rgb565(texel: &[u8; 4]) -> u16 {
+    let r = (texel[0] as u16 * 31 + 127) / 255;
+    let g = (texel[1] as u16 * 63 + 127) / 255;
+    let b = (texel[2] as u16 * 31 + 127) / 255;
+    r << 11 | g << 5 | b
+}
+
+fn mix(a: u8, b: u8, wa: u32, wb: u32) -> u8 {
+    ((a as u32 * wa + b as u32 * wb) / (wa + wb)) as u8
+}
+
+// 颜色块的 4 种颜色. DXT1 中 c0 <= c1 时只有 3 种颜色, 第 4 种为透明; DXT3/5 总是 4 种颜色
+fn colour_palette(c0: u16, c1: u16, format: PixelFormat) -> [[u8; 4]; 4] {
+    let (p0, p1) = (rgb565(c0), rgb565(c1));
+    let mut palette = [[0u8; 4]; 4];
+    palette[0] = [p0[0], p0[1], p0[2], 255];
+    palette[1] = [p1[0], p1[1], p1[2], 255];
+    if c0 > c1 || format != PixelFormat::Dxt1 {
+        for i in 0..3 {
+            palette[2][i] = mix(p0[i], p1[i], 2, 1);
+            palette[3][i] = mix(p0[i], p1[i], 1, 2);
+        }
+        palette[3][3] = 255;
+    } else {
+        for i in 0..3 {
+            palette[2][i] = mix(p0[i], p1[i], 1, 1);
+        }
+    }
+    palette[2][3] = 255;
+    palette
+}
+
+// DXT5 的 8 种 alpha, a0 <= a1 时只有 6 种插值, 另外两种为 0 和 255
+fn alpha_palette(a0: u8, a1: u8) -> [u8; 8] {
+    let mut alphas = [a0, a1, 0, 0, 0, 0, 0, 255];
+    if a0 > a1 {
+        for i in 1..7 {
+            alphas[i + 1] = mix(a0, a1, 7 - i as u32, i as u32);
+        }
+    } else {
+        for i in 1..5 {
+            alphas[i + 1] = mix(a0, a1, 5 - i as u32, i as u32);
+        }
+    }
+    alphas
+}
+
+// 解码一个 4x4 的块, 按行返回 16 个像素
+fn decode_block(format: PixelFormat, block: &[u8]) -> [[u8; 4]; 16] {
+    let colour_block = if format == PixelFormat::Dxt1 {
+        block
+    } else {
+        &block[8..]
+    };
+    let c0 = u16::from_le_bytes([colour_block[0], colour_block[1]]);
+    let c1 = u16::from_le_bytes([colour_block[2], colour_block[3]]);
+    let palette = colour_palette(c0, c1, format);
+
+    let indices = u32::from_le_bytes([
+        colour_block[4],
+        colour_block[5],
+        colour_block[6],
+        colour_block[7],
+    ]);
+    let mut texels = [[0u8; 4]; 16];
+    for (i, texel) in texels.iter_mut().enumerate() {
+        *texel = palette[(indices >> (i * 2) & 0x3) as usize];
+    }
+
+    match format {
+        PixelFormat::Dxt3 => {
+            let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+            for (i, texel) in texels.iter_mut().enumerate() {
+                let a = (alpha >> (i * 4) & 0xF) as u8;
+                texel[3] = a << 4 | a;
+            }
+        }
+        PixelFormat::Dxt5 => {
+            let alphas = alpha_palette(block[0], block[1]);
+            let mut bits = [0; 8];
+            bits[..6].copy_from_slice(&block[2..8]);
+            let indices = u64::from_le_bytes(bits);
+            for (i, texel) in texels.iter_mut().enumerate() {
+                texel[3] = alphas[(indices >> (i * 3) & 0x7) as usize];
+            }
+        }
+        _ => {}
+    }
+    texels
+}
+
+fn colour_distance(a: &[u8; 4], b: &[u8; 4]) -> u32 {
+    (0..3)
+        .map(|i| (a[i] as i32 - b[i] as i32).pow(2) as u32)
+        .sum()
+}
+
+// 编码一个 4x4 的块. 颜色端点取包围盒的两角, 每个像素取最接近的颜色
+fn encode_block(format: PixelFormat, texels: &[[u8; 4]; 16]) -> Vec<u8> {
+    // DXT1 有透明像素时使用 3 色模式, 索引 3 为透明
+    let transparent = format == PixelFormat::Dxt1 && texels.iter().any(|t| t[3] < 128);
+    let mut min = [255u8; 4];
+    let mut max = [0u8; 4];
+    for texel in texels.iter().filter(|t| !transparent || t[3] >= 128) {
+        for i in 0..3 {
+            min[i] = min[i].min(texel[i]);
+            max[i] = max[i].max(texel[i]);
+        }
+    }
+    let (mut c0, mut c1) = (to_rgb565(&max), to_rgb565(&min));
+    if transparent {
+        // 全部透明时 min > max, 端点无意义
+        if c0 > c1 {
+            std::mem::swap(&mut c0, &mut c1);
+        }
+    } else if c0 < c1 {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+    let palette = colour_palette(c0, c1, format);
+    let colours = if c0 > c1 || format != PixelFormat::Dxt1 {
+        4
+    } else {
+        3
+    };
+    let mut indices = 0u32;
+    for (i, texel) in texels.iter().enumerate() {
+        let index = if transparent && texel[3] < 128 {
+            3
+        } else {
+            (0..colours)
+                .min_by_key(|&j| colour_distance(texel, &palette[j]))
+                .unwrap()
+        };
+        indices |= (index as u32) << (i * 2);
+    }
+
+    let mut block = vec![];
+    match format {
+        PixelFormat::Dxt3 => {
+            let mut alpha = 0u64;
+            for (i, texel) in texels.iter().enumerate() {
+                alpha |= ((texel[3] as u64 + 8) / 17) << (i * 4);
+            }
+            block.extend_from_slice(&alpha.to_le_bytes());
+        }
+        PixelFormat::Dxt5 => {
+            let a0 = texels.iter().map(|t| t[3]).max().unwrap();
+            let a1 = texels.iter().map(|t| t[3]).min().unwrap();
+            let alphas = alpha_palette(a0, a1);
+            let mut alpha_indices = 0u64;
+            for (i, texel) in texels.iter().enumerate() {
+                let index = (0..8)
+                    .min_by_key(|&j| (alphas[j] as i32 - texel[3] as i32).abs())
+                    .unwrap();
+                alpha_indices |= (index as u64) << (i * 3);
+            }
+            block.extend_from_slice(&[a0, a1]);
+            block.extend_from_slice(&alpha_indices.to_le_bytes()[..6]);
+        }
+        _ => {}
+    }
+    block.extend_from_slice(&c0.to_le_bytes());
+    block.extend_from_slice(&c1.to_le_bytes());
+    block.extend_from_slice(&indices.to_le_bytes());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ktex_bytes(pixel_format: u32, width: u16, height: u16, data: &[u8]) -> Vec<u8> {
+        let header = 12 | pixel_format << 4 | 2 << 9 | 1 << 13 | 0xFFF << 20;
+        let mut bytes = b"KTEX".to_vec();
+        bytes.extend(header.to_le_bytes());
+        bytes.extend(width.to_le_bytes());
+        bytes.extend(height.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes());
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn test_decode_ktex() {
+        // RGBA 从底部的行开始存储
+        let tex = Ktex::parse(&ktex_bytes(4, 1, 2, &[1, 2, 3, 4, 5, 6, 7, 8])).unwrap();
+        assert_eq!(tex.decode(0).unwrap().data, [5, 6, 7, 8, 1, 2, 3, 4]);
+
+        // DXT1: c0 为白色, c1 为黑色, 第二个像素为 c1, 第三个像素为插值
+        let mut block = vec![0xFF, 0xFF, 0, 0];
+        block.extend(0b10_01_00u32.to_le_bytes());
+        let tex = Ktex::parse(&ktex_bytes(0, 3, 1, &block)).unwrap();
+        assert_eq!(
+            tex.decode(0).unwrap().data,
+            [255, 255, 255, 255, 0, 0, 0, 255, 170, 170, 170, 255]
+        );
+
+        assert!(Ktex::parse(b"DDS ").is_err());
+    }
+
+    #[test]
+    fn test_parse_ktex_truncated() {
+        // 声明的数据长度超过文件剩余的字节数
+        let mut bytes = ktex_bytes(4, 1, 1, &[1, 2, 3, 4]);
+        let size_offset = bytes.len() - 8;
+        bytes[size_offset..size_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = Ktex::parse(&bytes).unwrap_err().to_string();
+        assert!(err.contains("数据不完整"), "{}", err);
+
+        let mut bytes = ktex_bytes(4, 1, 1, &[1, 2, 3, 4]);
+        bytes.pop();
+        assert!(Ktex::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_encode_ktex() {
+        let image = RgbaImage {
+            width: 2,
+            height: 2,
+            data: vec![
+                255, 0, 0, 255, 0, 255, 0, 255, //
+                0, 0, 255, 255, 255, 255, 255, 128,
+            ],
+        };
+
+        // RGBA 不压缩, 预乘后可以原样解码
+        let options = EncodeOptions {
+            pixel_format: PixelFormat::Rgba,
+            mipmaps: true,
+            premultiply: true,
+        };
+        let tex = Ktex::parse(&Ktex::encode(&image, &options).unwrap().to_bytes()).unwrap();
+        assert_eq!(tex.mipmaps.len(), 2);
+        let decoded = tex.decode(0).unwrap();
+        assert_eq!(decoded.data[..12], image.data[..12]);
+        assert_eq!(decoded.data[12..], [128, 128, 128, 128]);
+        assert_eq!(tex.decode(1).unwrap().data, [96, 96, 96, 223]);
+
+        // DXT5 有损, 灰度渐变和 alpha 接近即可
+        let image = RgbaImage {
+            width: 4,
+            height: 1,
+            data: vec![
+                0, 0, 0, 0, 85, 85, 85, 109, 170, 170, 170, 182, 255, 255, 255, 255,
+            ],
+        };
+        let options = EncodeOptions {
+            pixel_format: PixelFormat::Dxt5,
+            mipmaps: false,
+            premultiply: false,
+        };
+        let tex = Ktex::parse(&Ktex::encode(&image, &options).unwrap().to_bytes()).unwrap();
+        assert_eq!(tex.mipmaps.len(), 1);
+        let decoded = tex.decode(0).unwrap();
+        for (a, b) in decoded.data.iter().zip(&image.data) {
+            assert!((*a as i32 - *b as i32).abs() <= 8, "{} != {}", a, b);
+        }
+    }
+
+    // 4x4 的红白棋盘格, 两种颜色都能用 RGB565 精确表示, 像素的 alpha 依次取自 `alphas`
+    fn checker_image(alphas: [u8; 2]) -> RgbaImage {
+        let mut data = vec![];
+        for i in 0..16 {
+            let (colour, alpha) = if (i + i / 4) % 2 == 0 {
+                ([255, 0, 0], alphas[0])
+            } else {
+                ([255, 255, 255], alphas[1])
+            };
+            data.extend(colour);
+            data.push(alpha);
+        }
+        RgbaImage {
+            width: 4,
+            height: 4,
+            data,
+        }
+    }
+
+    fn round_trip(image: &RgbaImage, pixel_format: PixelFormat) -> Ktex {
+        let options = EncodeOptions {
+            pixel_format,
+            mipmaps: false,
+            premultiply: false,
+        };
+        Ktex::parse(&Ktex::encode(image, &options).unwrap().to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_rgba_rgb() {
+        let image = checker_image([200, 255]);
+        let tex = round_trip(&image, PixelFormat::Rgba);
+        assert_eq!(tex.pixel_format, PixelFormat::Rgba);
+        assert_eq!(tex.mipmaps[0].pitch, 16);
+        assert_eq!(tex.decode(0).unwrap().data, image.data);
+
+        // RGB 不保存 alpha, 解码为不透明
+        let tex = round_trip(&image, PixelFormat::Rgb);
+        assert_eq!(tex.pixel_format, PixelFormat::Rgb);
+        assert_eq!(tex.mipmaps[0].pitch, 12);
+        assert_eq!(tex.decode(0).unwrap().data, checker_image([255, 255]).data);
+    }
+
+    #[test]
+    fn test_round_trip_dxt1() {
+        let image = checker_image([255, 255]);
+        let tex = round_trip(&image, PixelFormat::Dxt1);
+        assert_eq!(tex.pixel_format, PixelFormat::Dxt1);
+        assert_eq!(tex.mipmaps[0].data.len(), 8);
+        assert_eq!(tex.decode(0).unwrap().data, image.data);
+
+        // alpha 小于 128 的像素编码为透明
+        let mut image = checker_image([255, 255]);
+        image.data[3] = 100;
+        let decoded = round_trip(&image, PixelFormat::Dxt1).decode(0).unwrap();
+        assert_eq!(decoded.data[..4], [0, 0, 0, 0]);
+        assert_eq!(decoded.data[4..], image.data[4..]);
+    }
+
+    #[test]
+    fn test_round_trip_dxt3_dxt5() {
+        // DXT3 的 alpha 只有 4 位, 17 的倍数可以精确还原
+        let image = checker_image([68, 255]);
+        let tex = round_trip(&image, PixelFormat::Dxt3);
+        assert_eq!(tex.pixel_format, PixelFormat::Dxt3);
+        assert_eq!(tex.mipmaps[0].data.len(), 16);
+        assert_eq!(tex.decode(0).unwrap().data, image.data);
+
+        // DXT5 的 alpha 端点取最大和最小值, 只有两种 alpha 时可以精确还原
+        let image = checker_image([30, 220]);
+        let tex = round_trip(&image, PixelFormat::Dxt5);
+        assert_eq!(tex.pixel_format, PixelFormat::Dxt5);
+        assert_eq!(tex.mipmaps[0].data.len(), 16);
+        assert_eq!(tex.decode(0).unwrap().data, image.data);
+    }
+
+    #[test]
+    fn test_encode_mipmaps() {
+        let image = RgbaImage {
+            width: 8,
+            height: 4,
+            data: [10, 20, 30, 40].repeat(32),
+        };
+        let options = EncodeOptions {
+            pixel_format: PixelFormat::Rgba,
+            mipmaps: true,
+            premultiply: false,
+        };
+        let tex = Ktex::parse(&Ktex::encode(&image, &options).unwrap().to_bytes()).unwrap();
+        let sizes: Vec<_> = tex
+            .mipmaps
+            .iter()
+            .map(|m| (m.width, m.height, m.pitch))
+            .collect();
+        assert_eq!(sizes, [(8, 4, 32), (4, 2, 16), (2, 1, 8), (1, 1, 4)]);
+        for (level, mipmap) in tex.mipmaps.iter().enumerate() {
+            let decoded = tex.decode(level).unwrap();
+            let pixels = mipmap.width as usize * mipmap.height as usize;
+            assert_eq!(decoded.data, [10, 20, 30, 40].repeat(pixels));
+        }
+
+        // 压缩格式的 mipmap 按 4x4 块向上取整
+        let options = EncodeOptions {
+            pixel_format: PixelFormat::Dxt1,
+            ..options
+        };
+        let tex = Ktex::parse(&Ktex::encode(&image, &options).unwrap().to_bytes()).unwrap();
+        let lengths: Vec<_> = tex.mipmaps.iter().map(|m| m.data.len()).collect();
+        assert_eq!(lengths, [16, 8, 8, 8]);
+    }
+}
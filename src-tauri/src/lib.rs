@@ -1,14 +1,31 @@
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
-#[tauri::command]
-fn greet(name: &str) -> String {
-    format!("Hello, {}! You've been greeted from Rust!", name)
-}
+//! 饥荒联机版着色器的解析, 构建与检查, 供命令行工具, 图形界面和语言服务器共用
+
+pub mod check;
+pub mod core;
+pub mod diagnostic;
+pub mod engine_uniforms;
+pub mod es100;
+#[cfg(feature = "naga")]
+pub mod export;
+pub mod glsl_parser;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod image;
+pub mod interpreter;
+pub mod ktex;
+pub mod linker;
+pub mod lua;
+pub mod manifest;
+#[cfg(feature = "naga")]
+pub mod naga_glsl;
+pub mod preprocessor;
+pub mod preview;
+pub mod symbols;
+pub mod types;
 
+/// 启动图形界面, 也是移动端的入口
+#[cfg(feature = "gui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+    gui::run(None);
 }
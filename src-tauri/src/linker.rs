@@ -0,0 +1,436 @@
+use crate::diagnostic::Diagnostic;
+use crate::glsl_parser::{array_length, collect_const_ints, collect_used_variables, ParsedShader};
+use crate::types::{glsl_declaration, ShaderStage};
+use glsl_lang::ast;
+use glsl_lang::visitor::{Host, Visit, Visitor};
+use std::collections::{HashMap, HashSet};
+
+/// 顶点着色器与像素着色器之间传递的 varying
+#[derive(Debug)]
+pub struct Varying {
+    pub name: String,
+    pub r#type: ast::TypeSpecifierNonArrayData,
+    pub array_length: Option<u32>,
+    pub precision: Option<ast::PrecisionQualifierData>,
+    /// 声明所在行
+    pub line: usize,
+}
+
+/// 收集着色器中声明的 varying.
+///
+/// 除了 GLSL ES 1.00 的 `varying`, 顶点着色器中的 `out` 和像素着色器中的 `in` 也视为 varying.
+pub fn collect_varyings(
+    shader: &ParsedShader,
+    stage: ShaderStage,
+) -> Result<Vec<Varying>, Diagnostic> {
+    let interface_qualifier = match stage {
+        ShaderStage::Vertex => ast::StorageQualifierData::Out,
+        ShaderStage::Fragment => ast::StorageQualifierData::In,
+    };
+    let mut varyings = vec![];
+    let mut consts = HashMap::new();
+
+    for decl in &shader.declarations {
+        let ast::ExternalDeclarationData::Declaration(decl) = &decl.content else {
+            continue;
+        };
+        let ast::DeclarationData::InitDeclaratorList(decl_list) = &decl.content else {
+            continue;
+        };
+        collect_const_ints(decl_list, &mut consts);
+        let head = &decl_list.content.head;
+        let Some(qualifier) = head.ty.content.qualifier.as_ref() else {
+            continue;
+        };
+
+        let mut is_varying = false;
+        let mut precision = None;
+        for q in &qualifier.qualifiers {
+            match &q.content {
+                ast::TypeQualifierSpecData::Storage(s)
+                    if s.content == ast::StorageQualifierData::Varying
+                        || s.content == interface_qualifier =>
+                {
+                    is_varying = true;
+                }
+                ast::TypeQualifierSpecData::Precision(p) => precision = Some(p.content.clone()),
+                _ => {}
+            }
+        }
+        if !is_varying {
+            continue;
+        }
+
+        let line = shader.line_of(decl.span);
+        let Some(head_name) = head.name.as_ref().map(|n| n.content.as_str()) else {
+            continue;
+        };
+        let type_array_length = array_length(
+            head.ty.ty.array_specifier.as_ref(),
+            &consts,
+            &format!("varying {}", head_name),
+        )
+        .map_err(|e| Diagnostic::error(line, e))?;
+        let declarators = std::iter::once((head_name, head.array_specifier.as_ref())).chain(
+            decl_list
+                .content
+                .tail
+                .iter()
+                .map(|d| (d.ident.ident.content.as_str(), d.ident.array_spec.as_ref())),
+        );
+        for (name, array_specifier) in declarators {
+            varyings.push(Varying {
+                name: name.to_string(),
+                r#type: head.ty.ty.ty.content.clone(),
+                array_length: array_length(array_specifier, &consts, &format!("varying {}", name))
+                    .map_err(|e| Diagnostic::error(line, e))?
+                    .or(type_array_length),
+                precision: precision.clone(),
+                line,
+            });
+        }
+    }
+
+    Ok(varyings)
+}
+
+/// 检查顶点着色器输出的 varying 与像素着色器读取的 varying 是否一致.
+///
+/// 像素着色器使用了顶点着色器没有声明或从未赋值的 varying, 或两边类型, 数组长度不一致时为错误;
+/// 只在一边声明或像素着色器从未读取的 varying, 以及精度不一致时为警告.
+/// 返回的诊断行号属于对应阶段的着色器.
+pub fn link_varyings(vs: &ParsedShader, ps: &ParsedShader) -> Vec<(ShaderStage, Diagnostic)> {
+    let vs_varyings = match collect_varyings(vs, ShaderStage::Vertex) {
+        Ok(varyings) => varyings,
+        Err(diagnostic) => return vec![(ShaderStage::Vertex, diagnostic)],
+    };
+    let ps_varyings = match collect_varyings(ps, ShaderStage::Fragment) {
+        Ok(varyings) => varyings,
+        Err(diagnostic) => return vec![(ShaderStage::Fragment, diagnostic)],
+    };
+    let ps_used = collect_used_variables(&ps.declarations);
+    let vs_assigned = collect_assigned_variables(vs);
+    let mut diagnostics = vec![];
+
+    for input in &ps_varyings {
+        let Some(output) = vs_varyings.iter().find(|v| v.name == input.name) else {
+            let message = format!("varying {} 没有在顶点着色器中声明", input.name);
+            let diagnostic = if ps_used.contains(&input.name) {
+                Diagnostic::error(input.line, message)
+            } else {
+                Diagnostic::warning(input.line, message)
+            };
+            diagnostics.push((ShaderStage::Fragment, diagnostic));
+            continue;
+        };
+
+        if output.r#type != input.r#type || output.array_length != input.array_length {
+            let message = format!(
+                "varying {} 的类型与顶点着色器不一致: 顶点着色器中为 {}, 像素着色器中为 {}",
+                input.name,
+                glsl_declaration(&output.r#type, &output.name, output.array_length),
+                glsl_declaration(&input.r#type, &input.name, input.array_length)
+            );
+            diagnostics.push((
+                ShaderStage::Fragment,
+                Diagnostic::error(input.line, message),
+            ));
+        } else if ps_used.contains(&input.name) && !vs_assigned.contains(&input.name) {
+            let message = format!(
+                "varying {} 在顶点着色器中声明了但从未赋值, 像素着色器读到的值是未定义的",
+                input.name
+            );
+            diagnostics.push((
+                ShaderStage::Fragment,
+                Diagnostic::error(input.line, message),
+            ));
+        } else if !ps_used.contains(&input.name) {
+            let message = format!("varying {} 在像素着色器中声明了但从未读取", input.name);
+            diagnostics.push((
+                ShaderStage::Fragment,
+                Diagnostic::warning(input.line, message),
+            ));
+        } else if output.precision != input.precision {
+            let message = format!(
+                "varying {} 的精度与顶点着色器不一致: 顶点着色器中为 {}, 像素着色器中为 {}",
+                input.name,
+                precision_name(output.precision.as_ref()),
+                precision_name(input.precision.as_ref())
+            );
+            diagnostics.push((
+                ShaderStage::Fragment,
+                Diagnostic::warning(input.line, message),
+            ));
+        }
+    }
+
+    for output in &vs_varyings {
+        if !ps_varyings.iter().any(|v| v.name == output.name) {
+            let message = format!("varying {} 没有被像素着色器使用", output.name);
+            diagnostics.push((
+                ShaderStage::Vertex,
+                Diagnostic::warning(output.line, message),
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+/// 收集着色器中被写入的变量: 赋值, 自增自减, 以及作为 out/inout 参数传给函数.
+/// 写入数组元素或分量 (如 `v[0]`, `v.xy`) 视为写入整个变量.
+pub fn collect_assigned_variables(shader: &ParsedShader) -> HashSet<String> {
+    let mut collector = AssignmentCollector::default();
+    // 先记录每个函数哪些参数是 out/inout, 调用出现在定义之前时也能识别
+    for decl in &shader.declarations {
+        let prototype = match &decl.content {
+            ast::ExternalDeclarationData::FunctionDefinition(f) => &f.content.prototype,
+            ast::ExternalDeclarationData::Declaration(d) => match &d.content {
+                ast::DeclarationData::FunctionPrototype(p) => p,
+                _ => continue,
+            },
+            ast::ExternalDeclarationData::Preprocessor(_) => continue,
+        };
+        let outputs = prototype
+            .parameters
+            .iter()
+            .map(|param| {
+                let qualifier = match &param.content {
+                    ast::FunctionParameterDeclarationData::Named(q, _)
+                    | ast::FunctionParameterDeclarationData::Unnamed(q, _) => q.as_ref(),
+                };
+                qualifier.is_some_and(|q| {
+                    q.qualifiers.iter().any(|q| {
+                        matches!(&q.content, ast::TypeQualifierSpecData::Storage(s)
+                            if matches!(s.content, ast::StorageQualifierData::Out | ast::StorageQualifierData::InOut))
+                    })
+                })
+            })
+            .collect();
+        collector
+            .out_parameters
+            .insert(prototype.name.content.as_str().to_string(), outputs);
+    }
+    for decl in &shader.declarations {
+        decl.visit(&mut collector);
+    }
+    collector.assigned
+}
+
+#[derive(Default)]
+struct AssignmentCollector {
+    // 函数名 -> 每个参数是否为 out/inout
+    out_parameters: HashMap<String, Vec<bool>>,
+    assigned: HashSet<String>,
+}
+
+impl AssignmentCollector {
+    fn assign(&mut self, target: &ast::Expr) {
+        match &target.content {
+            ast::ExprData::Variable(name) => {
+                self.assigned.insert(name.content.as_str().to_string());
+            }
+            ast::ExprData::Bracket(target, _) | ast::ExprData::Dot(target, _) => {
+                self.assign(target)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Visitor for AssignmentCollector {
+    fn visit_expr(&mut self, expr: &ast::Expr) -> Visit {
+        match &expr.content {
+            ast::ExprData::Assignment(target, _, _)
+            | ast::ExprData::PostInc(target)
+            | ast::ExprData::PostDec(target) => self.assign(target),
+            ast::ExprData::Unary(op, target)
+                if matches!(op.content, ast::UnaryOpData::Inc | ast::UnaryOpData::Dec) =>
+            {
+                self.assign(target)
+            }
+            ast::ExprData::FunCall(fun, args) => {
+                let name = match &fun.content {
+                    ast::FunIdentifierData::TypeSpecifier(ty) => match &ty.ty.content {
+                        ast::TypeSpecifierNonArrayData::TypeName(name) => Some(name.as_str()),
+                        _ => None,
+                    },
+                    ast::FunIdentifierData::Expr(callee) => match &callee.content {
+                        ast::ExprData::Variable(name) => Some(name.content.as_str()),
+                        _ => None,
+                    },
+                };
+                if let Some(outputs) = name.and_then(|n| self.out_parameters.get(n)).cloned() {
+                    for (arg, _) in args.iter().zip(outputs).filter(|(_, out)| *out) {
+                        self.assign(arg);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Visit::Children
+    }
+}
+
+fn precision_name(precision: Option<&ast::PrecisionQualifierData>) -> &'static str {
+    match precision {
+        Some(ast::PrecisionQualifierData::High) => "highp",
+        Some(ast::PrecisionQualifierData::Medium) => "mediump",
+        Some(ast::PrecisionQualifierData::Low) => "lowp",
+        None => "默认精度",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+    use crate::glsl_parser::parse_glsl;
+    use crate::preprocessor::PreprocessOptions;
+
+    fn link(vs: &str, ps: &str) -> Vec<(ShaderStage, Diagnostic)> {
+        let options = PreprocessOptions::default();
+        let vs = parse_glsl(vs, &options).unwrap();
+        let ps = parse_glsl(ps, &options).unwrap();
+        link_varyings(&vs, &ps)
+    }
+
+    fn assert_single(
+        diagnostics: &[(ShaderStage, Diagnostic)],
+        stage: ShaderStage,
+        severity: Severity,
+        line: usize,
+        message: &str,
+    ) {
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        let (diagnostic_stage, diagnostic) = &diagnostics[0];
+        assert_eq!(*diagnostic_stage, stage);
+        assert_eq!(diagnostic.severity, severity);
+        assert_eq!(diagnostic.line, line);
+        assert!(
+            diagnostic.message.contains(message),
+            "{}",
+            diagnostic.message
+        );
+    }
+
+    #[test]
+    fn test_matching_varyings() {
+        let vs = "varying vec2 uv;\nvoid main() { uv = vec2(0.0); }\n";
+        let ps = "varying vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert!(link(vs, ps).is_empty());
+    }
+
+    #[test]
+    fn test_missing_in_vertex_shader() {
+        let vs = "void main() {}\n";
+        let ps = "precision mediump float;\nvarying vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Error,
+            2,
+            "varying uv 没有在顶点着色器中声明",
+        );
+    }
+
+    #[test]
+    fn test_missing_in_vertex_shader_unused() {
+        let vs = "void main() {}\n";
+        let ps = "varying vec2 uv;\nvoid main() { gl_FragColor = vec4(1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Warning,
+            1,
+            "varying uv 没有在顶点着色器中声明",
+        );
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        let vs = "varying vec3 uv;\nvoid main() { uv = vec3(0.0); }\n";
+        let ps = "varying vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Error,
+            1,
+            "varying uv 的类型与顶点着色器不一致: 顶点着色器中为 vec3 uv, 像素着色器中为 vec2 uv",
+        );
+    }
+
+    #[test]
+    fn test_array_length_mismatch() {
+        let vs = "varying float weights[4];\nvoid main() { weights[0] = 1.0; }\n";
+        let ps = "varying float weights[2];\nvoid main() { gl_FragColor = vec4(weights[0]); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Error,
+            1,
+            "varying weights 的类型与顶点着色器不一致",
+        );
+    }
+
+    #[test]
+    fn test_never_written() {
+        let vs = "varying vec2 uv;\nvoid main() { gl_Position = vec4(0.0); }\n";
+        let ps = "varying vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Error,
+            1,
+            "varying uv 在顶点着色器中声明了但从未赋值",
+        );
+    }
+
+    #[test]
+    fn test_written_through_out_parameter() {
+        let vs = "varying vec2 uv;\n\
+            void fill(out vec2 v) { v = vec2(0.0); }\n\
+            void main() { fill(uv); }\n";
+        let ps = "varying vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert!(link(vs, ps).is_empty());
+    }
+
+    #[test]
+    fn test_precision_mismatch() {
+        let vs = "varying highp vec2 uv;\nvoid main() { uv = vec2(0.0); }\n";
+        let ps = "varying mediump vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Warning,
+            1,
+            "varying uv 的精度与顶点着色器不一致: 顶点着色器中为 highp, 像素着色器中为 mediump",
+        );
+    }
+
+    #[test]
+    fn test_unused_by_pixel_shader() {
+        let vs = "varying vec2 uv;\nvarying vec4 color;\nvoid main() { uv = vec2(0.0); color = vec4(1.0); }\n";
+        let ps = "varying vec2 uv;\nvoid main() { gl_FragColor = vec4(uv, 0.0, 1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Vertex,
+            Severity::Warning,
+            2,
+            "varying color 没有被像素着色器使用",
+        );
+    }
+
+    #[test]
+    fn test_declared_but_never_read_in_pixel_shader() {
+        let vs = "varying vec2 uv;\nvoid main() { uv = vec2(0.0); }\n";
+        let ps = "precision mediump float;\nvarying vec2 uv;\nvoid main() { gl_FragColor = vec4(1.0); }\n";
+        assert_single(
+            &link(vs, ps),
+            ShaderStage::Fragment,
+            Severity::Warning,
+            2,
+            "varying uv 在像素着色器中声明了但从未读取",
+        );
+    }
+}
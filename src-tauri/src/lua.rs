@@ -0,0 +1,224 @@
+//! 为构建好的 KSH 生成 mod 中使用的 Lua 模块: 声明资源, 注册后处理效果, 以及按类型设置 uniform 的函数
+
+use crate::core::{analyze_ksh, uniform_table, BuildOptions, UniformEntry};
+use crate::preprocessor::PreprocessOptions;
+use crate::types::VariableType;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Lua 的保留字不能用 `.` 访问字段
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// 访问 UniformVariables 中的字段
+fn lua_field(name: &str) -> String {
+    if LUA_KEYWORDS.contains(&name) {
+        format!("[\"{}\"]", name)
+    } else {
+        format!(".{}", name)
+    }
+}
+
+// SetUniformVariable 接受的参数名, 最多 4 个分量
+fn setter_params(uniform: &UniformEntry) -> Option<&'static [&'static str]> {
+    if uniform.array_length.is_some() {
+        return None;
+    }
+    match VariableType::try_from(uniform.r#type.as_str()).ok()? {
+        VariableType::Float => Some(&["value"]),
+        VariableType::Vec2 => Some(&["x", "y"]),
+        VariableType::Vec3 => Some(&["x", "y", "z"]),
+        VariableType::Vec4 => Some(&["x", "y", "z", "w"]),
+        VariableType::Mat4 | VariableType::Sampler2D => None,
+    }
+}
+
+/// 生成 Lua 模块. `ksh_name` 为不含扩展名的文件名, 着色器按 `shaders/<ksh_name>.ksh` 引用.
+/// 引擎 uniform 由渲染器赋值, 不生成设置函数
+pub fn generate_lua(ksh_name: &str, uniforms: &[UniformEntry]) -> String {
+    let uniforms: Vec<&UniformEntry> = uniforms.iter().filter(|u| !u.engine).collect();
+    let settable: Vec<(&UniformEntry, &[&str])> = uniforms
+        .iter()
+        .filter_map(|u| setter_params(u).map(|params| (*u, params)))
+        .collect();
+
+    let mut lua = format!("-- 由 dst-ksh-analyze 根据 {}.ksh 生成\n--\n", ksh_name);
+    lua.push_str(&format!("--   local shader = require(\"{}\")\n", ksh_name));
+    lua.push_str("--   Assets = shader.Assets -- 或合并到 modmain 的 Assets 中\n");
+    lua.push_str("--\n--   -- 作为后处理效果, Set* 函数设置的 uniform 只对后处理效果有效\n");
+    lua.push_str("--   shader.Enable(true)\n");
+    if let Some((uniform, _)) = settable.first() {
+        lua.push_str(&format!("--   shader.Set{}(...)\n", uniform.name));
+    }
+    lua.push_str("--\n--   -- 作为动画的泛光效果, 只有引擎 uniform 会被赋值\n");
+    lua.push_str("--   shader.SetBloomEffect(inst.AnimState)\n");
+    lua.push_str(&format!(
+        r#"
+local M = {{}}
+
+M.PATH = "shaders/{}.ksh"
+
+M.Assets = {{
+    Asset("SHADER", M.PATH),
+}}
+
+-- 着色器的完整路径
+function M.Handle()
+    return resolvefilepath(M.PATH)
+end
+
+-- 设置为动画的泛光效果着色器, 渲染器只为其赋值引擎 uniform
+function M.SetBloomEffect(anim_state)
+    anim_state:SetBloomEffectHandle(M.Handle())
+end
+
+-- 注册为后处理效果和 uniform, 只在第一次调用时注册
+function M.AddPostProcessEffect()
+    if M.effect == nil then
+        M.effect = PostProcessor:AddPostProcessEffect(M.Handle())
+"#,
+        ksh_name
+    ));
+    for (uniform, params) in &settable {
+        let field = lua_field(&uniform.name);
+        lua.push_str(&format!(
+            "        UniformVariables{0} = UniformVariables{0} or PostProcessor:AddUniformVariable(\"{1}\", {2})\n",
+            field,
+            uniform.name,
+            params.len()
+        ));
+    }
+    if !settable.is_empty() {
+        let variables: Vec<String> = settable
+            .iter()
+            .map(|(u, _)| format!("UniformVariables{}", lua_field(&u.name)))
+            .collect();
+        lua.push_str(&format!(
+            "        PostProcessor:SetEffectUniformVariables(M.effect, {})\n",
+            variables.join(", ")
+        ));
+    }
+    lua.push_str(
+        r#"    end
+    return M.effect
+end
+
+function M.Enable(enabled)
+    PostProcessor:EnablePostProcessEffect(M.AddPostProcessEffect(), enabled)
+end
+"#,
+    );
+
+    for uniform in &uniforms {
+        let declaration = match uniform.array_length {
+            Some(length) => format!("{} {}[{}]", uniform.r#type, uniform.name, length),
+            None => format!("{} {}", uniform.r#type, uniform.name),
+        };
+        let Some(params) = setter_params(uniform) else {
+            lua.push_str(&format!(
+                "\n-- {}: SetUniformVariable 最多设置 4 个分量, 需要在引擎中赋值\n",
+                declaration
+            ));
+            continue;
+        };
+        let checks: Vec<String> = params
+            .iter()
+            .map(|p| format!("type({}) == \"number\"", p))
+            .collect();
+        lua.push_str(&format!(
+            r#"
+-- {declaration}
+function M.Set{name}({params})
+    assert({checks}, "{name} 需要 {count} 个数字")
+    M.AddPostProcessEffect()
+    PostProcessor:SetUniformVariable(UniformVariables{field}, {params})
+end
+"#,
+            declaration = declaration,
+            name = uniform.name,
+            params = params.join(", "),
+            checks = checks.join(" and "),
+            count = params.len(),
+            field = lua_field(&uniform.name),
+        ));
+    }
+
+    lua.push_str("\nreturn M\n");
+    lua
+}
+
+/// KSH 中两个着色器的 uniform 表, 与构建 KSH 时的合并方式一致
+pub fn ksh_uniforms(
+    content: &[u8],
+    options: &PreprocessOptions,
+) -> Result<Vec<UniformEntry>, Box<dyn std::error::Error>> {
+    let (vs_name, vs_content, ps_name, ps_content) = analyze_ksh(content)?;
+    let options = BuildOptions {
+        preprocess: options.clone(),
+        ..Default::default()
+    };
+    uniform_table(&vs_name, &vs_content, &ps_name, &ps_content, &options)
+}
+
+/// 为 .ksh 文件生成同名的 .lua 文件, 返回写入的路径
+pub fn write_lua_file(
+    ksh_path: &Path,
+    force: bool,
+    options: &PreprocessOptions,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let out_path = ksh_path.with_extension("lua");
+    if !force && out_path.exists() {
+        return Err(format!("输出文件已存在: {}", out_path.display()).into());
+    }
+    let content =
+        fs::read(ksh_path).map_err(|e| format!("读取文件 {} 失败: {}", ksh_path.display(), e))?;
+    let uniforms = ksh_uniforms(&content, options)?;
+    let ksh_name = ksh_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+    fs::write(&out_path, generate_lua(ksh_name, &uniforms))
+        .map_err(|e| format!("写入文件 {} 失败: {}", out_path.display(), e))?;
+    log::info!("已写入: {}", out_path.display());
+    Ok(out_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{build_ksh, BuildOptions};
+
+    #[test]
+    fn test_generate_lua() {
+        let vs = "uniform mat4 MatrixP;\n\
+            attribute vec3 POSITION;\n\
+            void main() { gl_Position = MatrixP * vec4(POSITION, 1.0); }\n";
+        let ps = "uniform vec4 TINT;\n\
+            uniform float end;\n\
+            uniform mat4 COLOURS;\n\
+            void main() { gl_FragColor = COLOURS * TINT * end; }\n";
+        let options = BuildOptions::default();
+        let ksh = build_ksh("tinted", "tinted.vs", vs, "tinted.ps", ps, &options).unwrap();
+        let uniforms = ksh_uniforms(&ksh, &options.preprocess).unwrap();
+        // 与构建时的 uniform 表一致
+        let names: Vec<&str> = uniforms.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(names, ["MatrixP", "TINT", "end", "COLOURS"]);
+        assert!(uniforms[0].engine);
+        let lua = crate::lua::generate_lua("tinted", &uniforms);
+
+        assert!(lua.contains("Asset(\"SHADER\", M.PATH)"));
+        assert!(lua.contains("M.PATH = \"shaders/tinted.ksh\""));
+        assert!(lua.contains("function M.SetTINT(x, y, z, w)"));
+        assert!(lua.contains("anim_state:SetBloomEffectHandle(M.Handle())"));
+        assert!(!lua.contains("inst.AnimState:SetBloomEffectHandle"));
+        assert!(lua.contains("PostProcessor:AddUniformVariable(\"TINT\", 4)"));
+        // Lua 保留字用下标访问
+        assert!(lua.contains("PostProcessor:SetUniformVariable(UniformVariables[\"end\"], value)"));
+        // 引擎 uniform 不生成设置函数, mat4 只生成注释
+        assert!(!lua.contains("MatrixP"));
+        assert!(!lua.contains("SetCOLOURS"));
+        assert!(lua.contains("-- mat4 COLOURS"));
+    }
+}
@@ -1,75 +1,32 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
-#![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-mod core;
-mod glsl_parser;
-mod types;
+#![cfg_attr(
+    all(not(debug_assertions), feature = "gui"),
+    windows_subsystem = "windows"
+)]
+#[cfg(feature = "naga")]
+use dst_ksh_analyze_lib::export;
+use dst_ksh_analyze_lib::{
+    core, engine_uniforms, image, ktex, lua, manifest, preprocessor, preview,
+};
 
 use clap::{Arg, Command};
 use log::info;
-use serde::{Deserialize, Serialize};
+use manifest::Manifest;
+use preprocessor::PreprocessOptions;
 use std::ffi::OsStr;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ShaderInfo {
-    name: String,
-    content: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct BuildKshParams {
-    output_path: String,
-    vs_name: String,
-    vs_content: String,
-    ps_name: String,
-    ps_content: String,
-}
-
-#[tauri::command]
-async fn analyze_ksh(file_path: String) -> Result<serde_json::Value, String> {
-    let input_path = Path::new(&file_path);
-    let ksh_content = fs::read(input_path).map_err(|e| e.to_string())?;
-
-    let (vs_name, vs_content, ps_name, ps_content) =
-        core::analyze_ksh(&ksh_content).map_err(|e| e.to_string())?;
-
-    Ok(serde_json::json!({
-        "vs": {
-            "name": vs_name,
-            "content": vs_content
-        },
-        "ps": {
-            "name": ps_name,
-            "content": ps_content
-        }
-    }))
-}
-
-#[tauri::command]
-async fn build_ksh(params: BuildKshParams) -> Result<(), String> {
-    let output_path = Path::new(&params.output_path);
-    let file_name = output_path
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .ok_or_else(|| "无法从输出路径解析文件名".to_string())?;
-
-    let ksh_content = core::build_ksh(
-        file_name,
-        &params.vs_name,
-        &params.vs_content,
-        &params.ps_name,
-        &params.ps_content,
-    )
-    .map_err(|e| e.to_string())?;
-
-    fs::write(&params.output_path, ksh_content).map_err(|e| e.to_string())?;
-
-    Ok(())
+fn main() {
+    // 直接输出错误的 Display 形式, 保证诊断中的代码片段能正常换行显示
+    if let Err(e) = run() {
+        eprintln!("错误: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let matches = Command::new("dst-ksh-analyze")
         .version("0.1.0")
         .author("TohsakaKuro<tohsakakuro@outlook.com>")
@@ -80,6 +37,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help(
                     "输入路径，可以是：\n\
                        - .ksh 文件（用于分析）\n\
+                       - .tex 纹理（转换为 PNG）\n\
+                       - .png 图片（转换为 .tex 纹理）\n\
                        - 包含 vs 和 ps 着色器文件的目录\n\
                        - 两个着色器文件（vs 和 ps，顺序任意）",
                 )
@@ -112,6 +71,107 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('d')
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("define")
+                .help("预定义宏，格式为 NAME 或 NAME=VALUE，可多次指定。仅影响 uniform 解析，不会写入着色器代码。")
+                .required(false)
+                .long("define")
+                .short('D')
+                .value_name("宏")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("include")
+                .help("#include 的搜索路径，可多次指定。着色器所在目录总是会被搜索。")
+                .required(false)
+                .long("include")
+                .short('I')
+                .value_name("目录")
+                .value_hint(clap::ValueHint::DirPath)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("es100")
+                .help("构建时按 GLSL ES 1.00 规范检查着色器，包括附录 A 对循环和数组下标的限制。")
+                .required(false)
+                .long("es100")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-validate")
+                .help("构建时跳过 naga 语义验证（类型检查、未定义的标识符、函数签名等）。")
+                .required(false)
+                .long("no-validate")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("lua")
+                .help("构建后为每个 .ksh 生成同名的 Lua 模块，声明着色器资源并提供设置 uniform 的函数。")
+                .required(false)
+                .long("lua")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("export")
+                .help("把 .ksh 文件中的着色器转换为 WGSL、SPIR-V 或 HLSL，并输出绑定表。")
+                .required(false)
+                .long("export")
+                .value_name("目标")
+                .value_parser(["wgsl", "spirv", "hlsl"]),
+        )
+        .arg(
+            Arg::new("render")
+                .help("在 CPU 上对输入图片（.png 或 .tex）的每个像素执行像素着色器（.ps 或 .ksh 文件），输出 PNG。输出路径默认为输入文件同名的 .png。")
+                .required(false)
+                .long("render")
+                .value_name("输入图片")
+                .value_hint(clap::ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("uniforms")
+                .help("--render 使用的 uniform、varying 和纹理设置（JSON 文件）。")
+                .required(false)
+                .long("uniforms")
+                .value_name("文件")
+                .value_hint(clap::ValueHint::FilePath)
+                .requires("render"),
+        )
+        .arg(
+            Arg::new("format")
+                .help("把 .png 转换为 .tex 时的像素格式，默认为 dxt5。")
+                .required(false)
+                .long("format")
+                .value_name("格式")
+                .value_parser(["dxt1", "dxt3", "dxt5", "rgba", "rgb"]),
+        )
+        .arg(
+            Arg::new("no-mipmaps")
+                .help("把 .png 转换为 .tex 时不生成 mipmap。")
+                .required(false)
+                .long("no-mipmaps")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-premultiply")
+                .help("把 .png 转换为 .tex 时不预乘 alpha（图片已经是预乘 alpha 时使用）。")
+                .required(false)
+                .long("no-premultiply")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("inspect")
+                .help("列出着色器或 .ksh 文件中使用的 uniform 并检查引擎 uniform。未指定输入路径时列出所有引擎 uniform。")
+                .required(false)
+                .long("inspect")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("gui")
+                .help("启动图形界面，并在编辑器中打开输入的 .ksh 文件。")
+                .required(false)
+                .long("gui")
+                .action(clap::ArgAction::SetTrue),
+        )
         // 允许覆盖文件
         .arg(
             Arg::new("force")
@@ -130,6 +190,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                      从包含着色器文件的目录构建：\n\
                      \tksh-analyzer shader_dir output.ksh\n\
                      \n\
+                     目录中有 ksh.json 清单时，按变体构建到输出目录（输出路径不能以 .ksh 结尾）：\n\
+                     \tksh-analyzer shader_dir output_dir\n\
+                     \n\
                      从两个着色器文件构建（顺序任意）：\n\
                      \tksh-analyzer input.vs input.ps output.ksh\n\
                      \n\
@@ -137,26 +200,90 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                      \tksh-analyzer input.ksh --debug\n\
                      \n\
                      强制覆盖已存在的文件：\n\
-                     \tksh-analyzer input.ksh --force",
+                     \tksh-analyzer input.ksh --force\n\
+                     \n\
+                     构建时预定义宏：\n\
+                     \tksh-analyzer shader_dir output.ksh -D GL_ES -D MAX_LIGHTS=4\n\
+                     \n\
+                     使用共享的着色器库：\n\
+                     \tksh-analyzer input.vs input.ps output.ksh -I shader_lib\n\
+                     \n\
+                     构建时检查是否符合 GLSL ES 1.00：\n\
+                     \tksh-analyzer shader_dir output.ksh --es100\n\
+                     \n\
+                     构建时生成 mod 使用的 Lua 模块（output.lua）：\n\
+                     \tksh-analyzer shader_dir output.ksh --lua\n\
+                     \n\
+                     导出为 WGSL（也可以是 spirv、hlsl）：\n\
+                     \tksh-analyzer input.ksh output_dir --export wgsl\n\
+                     \n\
+                     把 .tex 纹理转换为 PNG：\n\
+                     \tksh-analyzer atlas-0.tex atlas-0.png\n\
+                     \n\
+                     把 PNG 图片转换为 .tex 纹理（DXT5，含 mipmap）：\n\
+                     \tksh-analyzer atlas-0.png atlas-0.tex --format dxt5\n\
+                     \n\
+                     在 CPU 上渲染像素着色器预览：\n\
+                     \tksh-analyzer input.ksh output.png --render input.png --uniforms uniforms.json\n\
+                     \n\
+                     查看着色器使用的 uniform：\n\
+                     \tksh-analyzer input.ps --inspect\n\
+                     \n\
+                     在图形界面中打开 .ksh 文件：\n\
+                     \tksh-analyzer --gui input.ksh",
         )
         .get_matches();
 
     // 如果没有任何参数，启动 Tauri 应用
+    #[cfg(feature = "gui")]
     if std::env::args().len() <= 1 {
-        tauri::Builder::default()
-            .plugin(tauri_plugin_opener::init())
-            .plugin(tauri_plugin_fs::init())
-            .plugin(tauri_plugin_dialog::init())
-            .invoke_handler(tauri::generate_handler![analyze_ksh, build_ksh])
-            .run(tauri::generate_context!())
-            .expect("error while running tauri application");
+        dst_ksh_analyze_lib::run();
         return Ok(());
     }
+    // 安装包注册的文件关联会以 `--gui <文件>` 启动, 见 tauri.conf.json
+    let is_ksh = |path: &Path| {
+        path.extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("ksh"))
+    };
+    let startup_file = matches.get_one::<String>("path1").map(PathBuf::from);
+    if matches.get_flag("gui") {
+        if let Some(path) = &startup_file {
+            if !is_ksh(path) || !path.is_file() {
+                return Err(format!("图形界面只能打开 .ksh 文件: {}", path.display()).into());
+            }
+        }
+        #[cfg(feature = "gui")]
+        {
+            dst_ksh_analyze_lib::gui::run(startup_file);
+            return Ok(());
+        }
+        #[cfg(not(feature = "gui"))]
+        return Err("编译时没有启用 gui 特性, 无法启动图形界面".into());
+    }
 
     // 设置日志级别
     let debug = matches.get_flag("debug");
     let force = matches.get_flag("force");
-    
+    let emit_lua = matches.get_flag("lua");
+    let build_options = core::BuildOptions {
+        preprocess: PreprocessOptions {
+            defines: matches
+                .get_many::<String>("define")
+                .unwrap_or_default()
+                .map(|d| PreprocessOptions::parse_define(d))
+                .collect::<Result<_, _>>()?,
+            include_paths: matches
+                .get_many::<String>("include")
+                .unwrap_or_default()
+                .map(PathBuf::from)
+                .collect(),
+        },
+        es100: matches.get_flag("es100"),
+        skip_validation: matches.get_flag("no-validate"),
+        ..Default::default()
+    };
+    let options = &build_options.preprocess;
+
     if debug {
         env_logger::Builder::from_default_env()
             .filter_level(log::LevelFilter::Debug)
@@ -168,6 +295,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .format(|buf, record| writeln!(buf, "{}: {}", record.level(), record.args()))
             .init();
     }
+    if matches.get_flag("inspect") {
+        match matches.get_one::<String>("path1") {
+            Some(input) => print!("{}", core::inspect_file(Path::new(input), options)?),
+            None => {
+                for uniform in engine_uniforms::ENGINE_UNIFORMS {
+                    println!("{:<40} {}", uniform.declaration(), uniform.description);
+                }
+            }
+        }
+        return Ok(());
+    }
     let input = match matches.get_one::<String>("path1") {
         Some(input) => input,
         None => {
@@ -175,7 +313,62 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
     let input_path = Path::new(input);
-    if input_path.extension().and_then(|s| s.to_str()) == Some("ksh") {
+    if let Some(image_path) = matches.get_one::<String>("render") {
+        let mut output_path = matches
+            .get_one::<String>("path2")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                PathBuf::from(input_path.file_stem().unwrap_or(OsStr::new("output")))
+            });
+        if output_path.extension().and_then(|s| s.to_str()) != Some("png") {
+            output_path.set_extension("png");
+        }
+        preview::render_preview_file(
+            input_path,
+            Path::new(image_path),
+            matches.get_one::<String>("uniforms").map(Path::new),
+            &output_path,
+            force,
+            options,
+        )
+        .map_err(|e| format!("渲染预览失败: {}", e))?;
+    } else if let Some(target) = matches.get_one::<String>("export") {
+        export_ksh(&matches, input_path, target, force, options)?;
+    } else if input_path.extension().and_then(|s| s.to_str()) == Some("tex") {
+        let mut output_path = matches
+            .get_one::<String>("path2")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                PathBuf::from(input_path.file_stem().unwrap_or(OsStr::new("output")))
+            });
+        if output_path.extension().and_then(|s| s.to_str()) != Some("png") {
+            output_path.set_extension("png");
+        }
+        if !force && output_path.exists() {
+            return Err(format!("输出文件已存在: {}", output_path.display()).into());
+        }
+        image::write_png(&output_path, &ktex::read_tex(input_path)?)?;
+        info!("已写入: {}", output_path.display());
+    } else if input_path.extension().and_then(|s| s.to_str()) == Some("png") {
+        let mut output_path = matches
+            .get_one::<String>("path2")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                PathBuf::from(input_path.file_stem().unwrap_or(OsStr::new("output")))
+            });
+        if output_path.extension().and_then(|s| s.to_str()) != Some("tex") {
+            output_path.set_extension("tex");
+        }
+        let encode_options = ktex::EncodeOptions {
+            pixel_format: match matches.get_one::<String>("format") {
+                Some(format) => ktex::PixelFormat::try_from(format.as_str())?,
+                None => ktex::PixelFormat::Dxt5,
+            },
+            mipmaps: !matches.get_flag("no-mipmaps"),
+            premultiply: !matches.get_flag("no-premultiply"),
+        };
+        ktex::encode_tex_file(input_path, &output_path, force, &encode_options)?;
+    } else if input_path.extension().and_then(|s| s.to_str()) == Some("ksh") {
         let output_path = matches
             .get_one::<String>("path3")
             .map(Path::new)
@@ -188,23 +381,63 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         core::analyze_ksh_file(input_path, output_path, force)
             .map_err(|e| format!("分析着色器文件失败: {}", e))?;
     } else if input_path.is_dir() {
-        let output_path =
-            if let Some(output_path) = matches.get_one::<String>("path2").map(Path::new) {
-                let mut path = output_path.to_path_buf();
-                if path.extension().and_then(|s| s.to_str()) != Some("ksh") {
-                    path.set_extension("ksh");
+        let manifest = Manifest::load(input_path)?.filter(|m| !m.variants.is_empty());
+        if let Some(manifest) = manifest {
+            // 目录中有清单时, 按变体输出到目录
+            let output_dir = matches
+                .get_one::<String>("path2")
+                .map(Path::new)
+                .unwrap_or_else(|| Path::new("."));
+            if is_ksh(output_dir) {
+                return Err(format!(
+                    "{} 中有 ksh.json 清单, 会按变体构建到输出目录, 输出路径不能是 .ksh 文件: {}",
+                    input_path.display(),
+                    output_dir.display()
+                )
+                .into());
+            }
+            if !output_dir.exists() {
+                fs::create_dir_all(output_dir).map_err(|e| format!("创建输出目录失败: {}", e))?;
+            } else if !output_dir.is_dir() {
+                return Err("存在清单文件时输出路径必须是目录".into());
+            }
+            for variant in &manifest.variants {
+                check_ksh_output(
+                    &output_dir.join(format!("{}.ksh", variant.name)),
+                    force,
+                    emit_lua,
+                )?;
+            }
+            let outputs = core::build_ksh_variants_from_dir(
+                input_path,
+                output_dir,
+                &manifest,
+                &build_options,
+            )
+            .map_err(|e| format!("构建着色器文件失败: {}", e))?;
+            if emit_lua {
+                for output_path in &outputs {
+                    lua::write_lua_file(output_path, force, options)?;
                 }
-                path
-            } else {
-                return Err("需要指定输出.ksh文件".into());
-            };
-        if !force {
-            if output_path.exists() {
-                return Err(format!("输出文件已存在: {}", output_path.display()).into());
+            }
+        } else {
+            let output_path =
+                if let Some(output_path) = matches.get_one::<String>("path2").map(Path::new) {
+                    let mut path = output_path.to_path_buf();
+                    if path.extension().and_then(|s| s.to_str()) != Some("ksh") {
+                        path.set_extension("ksh");
+                    }
+                    path
+                } else {
+                    return Err("需要指定输出.ksh文件".into());
+                };
+            check_ksh_output(&output_path, force, emit_lua)?;
+            core::build_ksh_file_from_dir(input_path, &output_path, &build_options)
+                .map_err(|e| format!("构建着色器文件失败: {}", e))?;
+            if emit_lua {
+                lua::write_lua_file(&output_path, force, options)?;
             }
         }
-        core::build_ksh_file_from_dir(input_path, &output_path)
-            .map_err(|e| format!("构建着色器文件失败: {}", e))?;
     } else if let Some(second_file) = matches.get_one::<String>("path2") {
         if !input_path.exists() {
             return Err(format!("未找到第一个着色器文件: {}", input).into());
@@ -240,16 +473,75 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             } else {
                 return Err("需要指定输出.ksh文件".into());
             };
-        if !force {
-            if output_path.exists() {
-                return Err(format!("输出文件已存在: {}", output_path.display()).into());
-            }
-        }
-        core::build_ksh_file(input_path, second_path, &output_path)
+        check_ksh_output(&output_path, force, emit_lua)?;
+        core::build_ksh_file(input_path, second_path, &output_path, &build_options)
             .map_err(|e| format!("构建着色器文件失败: {}", e))?;
+        if emit_lua {
+            lua::write_lua_file(&output_path, force, options)?;
+        }
     } else {
         return Err("无效的输入. 期望: - .ksh 文件, - 包含 .vs 和 .ps 着色器文件的目录, - 两个着色器文件（.vs 和 .ps，顺序任意）".into());
     }
     info!("所有任务已完成");
     Ok(())
 }
+
+// 构建前检查输出的 .ksh 和 .lua 是否已存在, 避免生成 .lua 失败时留下新写入的 .ksh
+fn check_ksh_output(
+    output_path: &Path,
+    force: bool,
+    emit_lua: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if force {
+        return Ok(());
+    }
+    if output_path.exists() {
+        return Err(format!("输出文件已存在: {}", output_path.display()).into());
+    }
+    let lua_path = output_path.with_extension("lua");
+    if emit_lua && lua_path.exists() {
+        return Err(format!("输出文件已存在: {}", lua_path.display()).into());
+    }
+    Ok(())
+}
+
+// 把 .ksh 文件导出为其他着色器语言, 默认输出到与输入同名的目录
+#[cfg(feature = "naga")]
+fn export_ksh(
+    matches: &clap::ArgMatches,
+    input_path: &Path,
+    target: &str,
+    force: bool,
+    options: &PreprocessOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if input_path.extension().and_then(|s| s.to_str()) != Some("ksh") {
+        return Err("--export 需要输入 .ksh 文件".into());
+    }
+    let output_path = matches
+        .get_one::<String>("path2")
+        .map(Path::new)
+        .unwrap_or_else(|| Path::new(input_path.file_stem().unwrap_or(OsStr::new("output"))));
+    if !output_path.exists() {
+        fs::create_dir_all(output_path).map_err(|e| format!("创建输出目录失败: {}", e))?;
+    } else if !output_path.is_dir() {
+        return Err("输出路径不是目录".into());
+    }
+    let target = export::ExportTarget::try_from(target)?;
+    for path in export::export_ksh_file(input_path, output_path, target, force, options)
+        .map_err(|e| format!("导出着色器失败: {}", e))?
+    {
+        info!("已写入: {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "naga"))]
+fn export_ksh(
+    _matches: &clap::ArgMatches,
+    _input_path: &Path,
+    _target: &str,
+    _force: bool,
+    _options: &PreprocessOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("导出需要启用 naga 特性".into())
+}
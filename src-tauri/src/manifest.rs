@@ -0,0 +1,77 @@
+use crate::preprocessor::PreprocessOptions;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// 着色器目录中的清单文件名
+pub const MANIFEST_FILE_NAME: &str = "ksh.json";
+
+/// 着色器目录的构建清单
+///
+/// ```json
+/// {
+///     "variants": [
+///         { "name": "anim" },
+///         { "name": "anim_bloom", "defines": ["BLOOM"] },
+///         { "name": "anim_bloom_haunted", "defines": ["BLOOM", "HAUNTED=1"] }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub variants: Vec<Variant>,
+}
+
+/// 一个变体, 对应输出的一个 .ksh 文件
+#[derive(Debug, Deserialize)]
+pub struct Variant {
+    /// 输出文件名, 不含扩展名
+    pub name: String,
+    /// 写入着色器代码的宏定义, 格式为 `NAME` 或 `NAME=VALUE`
+    #[serde(default)]
+    pub defines: Vec<String>,
+}
+
+impl Variant {
+    pub fn parse_defines(&self) -> Result<Vec<(String, String)>, String> {
+        self.defines
+            .iter()
+            .map(|d| PreprocessOptions::parse_define(d))
+            .collect()
+    }
+}
+
+impl Manifest {
+    /// 读取目录中的清单文件, 不存在时返回 None
+    pub fn load(dir: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let path = dir.join(MANIFEST_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("读取清单文件 {} 失败: {}", path.display(), e))?;
+        let manifest: Manifest = serde_json::from_str(&content)
+            .map_err(|e| format!("解析清单文件 {} 失败: {}", path.display(), e))?;
+        manifest.validate()?;
+        Ok(Some(manifest))
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for (index, variant) in self.variants.iter().enumerate() {
+            if variant.name.is_empty() || variant.name.contains(['/', '\\']) {
+                return Err(format!("无效的变体名称: \"{}\"", variant.name).into());
+            }
+            if self.variants[..index]
+                .iter()
+                .any(|v| v.name == variant.name)
+            {
+                return Err(format!("重复的变体名称: {}", variant.name).into());
+            }
+            variant
+                .parse_defines()
+                .map_err(|e| format!("变体 {}: {}", variant.name, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,835 @@
+//! 借助 naga 对着色器做语义验证.
+//!
+//! naga 的 GLSL 前端只接受 Vulkan 风格的 GLSL 450, 所以先把 GLSL ES 1.00 代码转换过去:
+//! `attribute`/`varying` 改为带 location 的 `in`/`out`, uniform 放入 uniform 块,
+//! `sampler2D` 拆分为纹理和采样器, `texture2D` 等函数改为新的名称.
+//! 转换时保持原代码的行号, 只在开头插入声明, 诊断信息可以映射回原始代码.
+
+use crate::diagnostic::Diagnostic;
+use crate::glsl_parser::{array_length, collect_const_ints, eval_const_int, ParsedShader};
+use crate::linker::collect_varyings;
+use crate::types::{ShaderStage, Variable, VariableType};
+use glsl_lang::ast;
+use glsl_lang::visitor::{Host, Visit, Visitor};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// 非采样器 uniform 所在的 uniform 块名称, 位于 set 0 binding 0
+pub const UNIFORM_BLOCK_NAME: &str = "KshUniforms";
+
+// 转换后像素着色器的颜色输出, 替代 `gl_FragColor`
+const FRAG_COLOR_NAME: &str = "_ksh_FragColor";
+
+/// uniform 块中的成员
+#[derive(Debug)]
+pub struct UniformMember {
+    pub name: String,
+    pub r#type: VariableType,
+    pub array_length: Option<u32>,
+}
+
+/// 采样器 (或采样器数组中的一个元素) 拆分出的纹理和采样器的绑定位置
+#[derive(Debug)]
+pub struct SamplerBinding {
+    pub name: String,
+    /// 采样器数组中的下标, 不是数组时为 `None`
+    pub index: Option<u32>,
+    pub texture_binding: u32,
+    pub sampler_binding: u32,
+}
+
+impl SamplerBinding {
+    /// 转换后的纹理变量名, 如 `SAMPLER_0_texture`
+    pub fn texture_name(&self) -> String {
+        match self.index {
+            Some(index) => format!("{}_{}_texture", self.name, index),
+            None => format!("{}_texture", self.name),
+        }
+    }
+
+    /// 转换后的采样器变量名, 如 `SAMPLER_0_sampler`
+    pub fn sampler_name(&self) -> String {
+        match self.index {
+            Some(index) => format!("{}_{}_sampler", self.name, index),
+            None => format!("{}_sampler", self.name),
+        }
+    }
+}
+
+/// 两个阶段共用的接口布局, 保证转换后顶点着色器的输出与像素着色器的输入 location 一致,
+/// uniform 在两个阶段的绑定位置也相同
+#[derive(Debug)]
+pub struct InterfaceLayout {
+    /// varying 的名称和 location
+    pub varyings: Vec<(String, u32)>,
+    /// 非采样器 uniform, 按顺序作为 uniform 块的成员
+    pub uniforms: Vec<UniformMember>,
+    /// 采样器依次占用 uniform 块之后的 binding
+    pub samplers: Vec<SamplerBinding>,
+}
+
+impl InterfaceLayout {
+    /// 根据合并后的 uniform 和两个阶段的 varying 生成接口布局.
+    /// 出错时返回诊断所属的阶段, 诊断的行号属于该阶段的着色器
+    pub fn new(
+        uniforms: &[&Variable],
+        vs: &ParsedShader,
+        ps: &ParsedShader,
+    ) -> Result<Self, (ShaderStage, Diagnostic)> {
+        let mut varyings = vec![];
+        let mut location = 0;
+        let vs_varyings =
+            collect_varyings(vs, ShaderStage::Vertex).map_err(|d| (ShaderStage::Vertex, d))?;
+        let ps_varyings =
+            collect_varyings(ps, ShaderStage::Fragment).map_err(|d| (ShaderStage::Fragment, d))?;
+        for varying in vs_varyings.iter().chain(&ps_varyings) {
+            if varyings.iter().any(|(name, _)| *name == varying.name) {
+                continue;
+            }
+            varyings.push((varying.name.clone(), location));
+            location += location_count(&varying.r#type, varying.array_length);
+        }
+
+        let mut members = vec![];
+        let mut samplers = vec![];
+        let mut binding = 1;
+        for uniform in uniforms {
+            let Some(r#type) = uniform.variable_type() else {
+                continue;
+            };
+            if r#type != VariableType::Sampler2D {
+                members.push(UniformMember {
+                    name: uniform.name.clone(),
+                    r#type,
+                    array_length: uniform.array_length,
+                });
+                continue;
+            }
+            let indices: Vec<Option<u32>> = match uniform.array_length {
+                Some(length) => (0..length).map(Some).collect(),
+                None => vec![None],
+            };
+            for index in indices {
+                samplers.push(SamplerBinding {
+                    name: uniform.name.clone(),
+                    index,
+                    texture_binding: binding,
+                    sampler_binding: binding + 1,
+                });
+                binding += 2;
+            }
+        }
+
+        Ok(InterfaceLayout {
+            varyings,
+            uniforms: members,
+            samplers,
+        })
+    }
+}
+
+/// 转换为 GLSL 450 后的着色器
+pub struct Translation {
+    pub source: String,
+    // 开头插入的每一行声明对应的原始行号
+    header_lines: Vec<usize>,
+    // 因替换标识符而列号发生变化的行 (原始行号)
+    rewritten_lines: HashSet<usize>,
+    // 预处理后每一行开头与原始代码相同的字符数
+    unchanged_prefixes: Vec<usize>,
+}
+
+impl Translation {
+    /// 把转换后代码中的位置映射回原始代码
+    fn diagnostic(&self, line: usize, column: usize, message: String) -> Diagnostic {
+        if line <= self.header_lines.len() {
+            let original = self.header_lines[line.max(1) - 1];
+            return Diagnostic::error(original, message);
+        }
+        let original = line - self.header_lines.len();
+        let diagnostic = Diagnostic::error(original, message);
+        let unchanged = self.unchanged_prefixes.get(original - 1).copied();
+        if self.rewritten_lines.contains(&original)
+            || unchanged.is_none_or(|unchanged| column.saturating_sub(1) > unchanged)
+        {
+            diagnostic
+        } else {
+            diagnostic.with_column(column)
+        }
+    }
+}
+
+// 需要转换的全局接口变量声明
+#[derive(PartialEq)]
+enum InterfaceKind {
+    Attribute,
+    Varying,
+    Output,
+    Uniform,
+}
+
+struct InterfaceDeclaration {
+    kind: InterfaceKind,
+    // 声明在预处理后代码中的字节范围
+    range: Range<usize>,
+    line: usize,
+    type_text: String,
+    // 变量名, 数组说明 (如 `[4]`) 和占用的 location 数量
+    declarators: Vec<(String, String, u32)>,
+}
+
+/// 转换失败的原因
+#[derive(Debug)]
+pub enum TranslateError {
+    /// 着色器本身有错误
+    Invalid(Diagnostic),
+    /// 着色器使用了无法转换为 naga 可以解析的代码的写法 (如把采样器传给函数), 不一定是错误
+    Unsupported(Diagnostic),
+}
+
+/// 把预处理后的 GLSL ES 1.00 着色器转换为 naga 可以解析的 GLSL 450
+pub fn translate(
+    shader: &ParsedShader,
+    stage: ShaderStage,
+    layout: &InterfaceLayout,
+) -> Result<Translation, TranslateError> {
+    // naga 不支持 sampler2D 等组合采样器类型的函数参数
+    if let Some(diagnostic) = find_sampler_parameter(shader) {
+        return Err(TranslateError::Unsupported(diagnostic));
+    }
+    let declarations =
+        collect_interface_declarations(shader, stage).map_err(TranslateError::Invalid)?;
+
+    let mut body = shader.source.clone().into_bytes();
+    for declaration in &declarations {
+        let mut end = declaration.range.end;
+        // 声明的范围可能不含结尾的分号, 一起去掉, 避免留下空语句
+        while end < body.len() && body[end].is_ascii_whitespace() {
+            end += 1;
+        }
+        let end = if body.get(end) == Some(&b';') {
+            end + 1
+        } else {
+            declaration.range.end
+        };
+        blank(&mut body[declaration.range.start..end]);
+    }
+    let mut body = String::from_utf8(body).unwrap_or_default();
+    body = blank_directives(&body);
+
+    let uniform_lines: HashMap<&str, usize> = declarations
+        .iter()
+        .filter(|d| d.kind == InterfaceKind::Uniform)
+        .flat_map(|d| {
+            d.declarators
+                .iter()
+                .map(|(name, ..)| (name.as_str(), d.line))
+        })
+        .collect();
+    let samplers: Vec<&SamplerBinding> = layout
+        .samplers
+        .iter()
+        .filter(|s| uniform_lines.contains_key(s.name.as_str()))
+        .collect();
+    let sampler_indices = collect_sampler_indices(shader, &samplers);
+    let (body, rewritten_lines) = rewrite_identifiers(&body, &samplers, &sampler_indices, stage)?;
+
+    let mut header = vec![("#version 450".to_string(), 1)];
+    if !layout.uniforms.is_empty() {
+        let members: String = layout
+            .uniforms
+            .iter()
+            .map(|u| match u.array_length {
+                Some(length) => format!(" {} {}[{}];", u.r#type.name(), u.name, length),
+                None => format!(" {} {};", u.r#type.name(), u.name),
+            })
+            .collect();
+        let line = layout
+            .uniforms
+            .iter()
+            .find_map(|u| uniform_lines.get(u.name.as_str()).copied())
+            .unwrap_or(1);
+        header.push((
+            format!(
+                "layout(set = 0, binding = 0) uniform {} {{{} }};",
+                UNIFORM_BLOCK_NAME, members
+            ),
+            line,
+        ));
+    }
+    for sampler in &samplers {
+        let line = uniform_lines[sampler.name.as_str()];
+        header.push((
+            format!(
+                "layout(set = 0, binding = {}) uniform texture2D {};",
+                sampler.texture_binding,
+                sampler.texture_name()
+            ),
+            line,
+        ));
+        header.push((
+            format!(
+                "layout(set = 0, binding = {}) uniform sampler {};",
+                sampler.sampler_binding,
+                sampler.sampler_name()
+            ),
+            line,
+        ));
+    }
+
+    let mut attribute_location = 0;
+    let mut output_location = 0;
+    for declaration in &declarations {
+        for (name, array, count) in &declaration.declarators {
+            let (qualifier, location) = match declaration.kind {
+                InterfaceKind::Uniform => continue,
+                InterfaceKind::Attribute => {
+                    attribute_location += count;
+                    ("in", attribute_location - count)
+                }
+                InterfaceKind::Output => {
+                    output_location += count;
+                    ("out", output_location - count)
+                }
+                InterfaceKind::Varying => {
+                    let qualifier = match stage {
+                        ShaderStage::Vertex => "out",
+                        ShaderStage::Fragment => "in",
+                    };
+                    let Some((_, location)) = layout.varyings.iter().find(|(n, _)| n == name)
+                    else {
+                        continue;
+                    };
+                    (qualifier, *location)
+                }
+            };
+            header.push((
+                format!(
+                    "layout(location = {}) {} {} {}{};",
+                    location, qualifier, declaration.type_text, name, array
+                ),
+                declaration.line,
+            ));
+        }
+    }
+    if stage == ShaderStage::Fragment && body.contains(FRAG_COLOR_NAME) {
+        header.push((
+            format!("layout(location = 0) out vec4 {};", FRAG_COLOR_NAME),
+            1,
+        ));
+    }
+
+    let mut source = String::new();
+    for (line, _) in &header {
+        source.push_str(line);
+        source.push('\n');
+    }
+    source.push_str(&body);
+    Ok(Translation {
+        source,
+        header_lines: header.into_iter().map(|(_, line)| line).collect(),
+        rewritten_lines,
+        unchanged_prefixes: shader.unchanged_prefixes.clone(),
+    })
+}
+
+/// 用 naga 解析并验证着色器, 返回所有错误, 行号属于预处理后的代码.
+/// 着色器使用了无法转换的写法时只返回一条警告, 跳过该阶段的验证
+pub fn validate_with_naga(
+    shader: &ParsedShader,
+    stage: ShaderStage,
+    layout: &InterfaceLayout,
+) -> Vec<Diagnostic> {
+    let translation = match translate(shader, stage, layout) {
+        Ok(translation) => translation,
+        Err(TranslateError::Invalid(diagnostic)) => return vec![diagnostic],
+        Err(TranslateError::Unsupported(diagnostic)) => {
+            let message = format!("naga 无法验证此着色器, 已跳过: {}", diagnostic.message);
+            return vec![Diagnostic::warning(diagnostic.line, message)];
+        }
+    };
+    let module = match parse_module(&translation, stage) {
+        Ok(module) => module,
+        Err(diagnostics) => return diagnostics,
+    };
+    let Err(error) = validator().validate(&module) else {
+        return vec![];
+    };
+    match error.as_inner() {
+        // 入口点本身的要求 (如顶点着色器必须写入 gl_Position) 游戏并不检查, 只作为警告并指向 main.
+        // 函数体中的错误同样包装在入口点错误里, 仍然是错误
+        naga::valid::ValidationError::EntryPoint { source, .. }
+            if !matches!(source, naga::valid::EntryPointError::Function(_)) =>
+        {
+            let message = validation_diagnostic(&translation, &error).message;
+            vec![Diagnostic::warning(main_line(shader), message)]
+        }
+        _ => vec![validation_diagnostic(&translation, &error)],
+    }
+}
+
+// 第一个采样器类型的函数参数
+fn find_sampler_parameter(shader: &ParsedShader) -> Option<Diagnostic> {
+    shader.declarations.iter().find_map(|decl| {
+        let prototype = match &decl.content {
+            ast::ExternalDeclarationData::FunctionDefinition(f) => &f.content.prototype,
+            ast::ExternalDeclarationData::Declaration(d) => match &d.content {
+                ast::DeclarationData::FunctionPrototype(p) => p,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        prototype.content.parameters.iter().find_map(|parameter| {
+            let (ty, name) = match &parameter.content {
+                ast::FunctionParameterDeclarationData::Named(_, d) => {
+                    (&d.content.ty, d.content.ident.ident.content.as_str())
+                }
+                ast::FunctionParameterDeclarationData::Unnamed(_, ty) => (ty, ""),
+            };
+            matches!(
+                ty.ty.content,
+                ast::TypeSpecifierNonArrayData::Sampler2D
+                    | ast::TypeSpecifierNonArrayData::SamplerCube
+            )
+            .then(|| {
+                let message = format!(
+                    "函数 {} 的参数 {} 是采样器, naga 不支持",
+                    prototype.content.name.as_str(),
+                    name
+                );
+                Diagnostic::error(shader.line_of(decl.span), message)
+            })
+        })
+    })
+}
+
+// main 函数所在行, 找不到时为第一行
+fn main_line(shader: &ParsedShader) -> usize {
+    shader
+        .declarations
+        .iter()
+        .find(|decl| match &decl.content {
+            ast::ExternalDeclarationData::FunctionDefinition(f) => {
+                f.content.prototype.content.name.as_str() == "main"
+            }
+            _ => false,
+        })
+        .map_or(1, |decl| shader.line_of(decl.span))
+}
+
+/// 用 naga 解析转换后的着色器
+pub fn parse_module(
+    translation: &Translation,
+    stage: ShaderStage,
+) -> Result<naga::Module, Vec<Diagnostic>> {
+    let stage = match stage {
+        ShaderStage::Vertex => naga::ShaderStage::Vertex,
+        ShaderStage::Fragment => naga::ShaderStage::Fragment,
+    };
+    let options = naga::front::glsl::Options::from(stage);
+    naga::front::glsl::Frontend::default()
+        .parse(&options, &translation.source)
+        .map_err(|e| {
+            e.errors
+                .iter()
+                .map(|error| {
+                    let location = error.meta.location(&translation.source);
+                    translation.diagnostic(
+                        location.line_number as usize,
+                        location.line_position as usize,
+                        error.kind.to_string(),
+                    )
+                })
+                .collect()
+        })
+}
+
+/// 用 naga 验证模块, 返回模块信息供后端生成代码
+pub fn validate_module(
+    translation: &Translation,
+    module: &naga::Module,
+) -> Result<naga::valid::ModuleInfo, Diagnostic> {
+    validator()
+        .validate(module)
+        .map_err(|e| validation_diagnostic(translation, &e))
+}
+
+fn validator() -> naga::valid::Validator {
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::all(),
+    )
+}
+
+fn validation_diagnostic(
+    translation: &Translation,
+    e: &naga::WithSpan<naga::valid::ValidationError>,
+) -> Diagnostic {
+    // 验证错误是嵌套的, 如 "函数无效: 表达式无效: 类型不匹配", 逐层拼接
+    let mut message = e.as_inner().to_string();
+    let mut source = std::error::Error::source(e.as_inner());
+    while let Some(inner) = source {
+        message.push_str(": ");
+        message.push_str(&inner.to_string());
+        source = inner.source();
+    }
+    // 取最内层的位置, 通常是出错的表达式
+    match e.spans().last() {
+        Some((span, _)) => {
+            let location = span.location(&translation.source);
+            translation.diagnostic(
+                location.line_number as usize,
+                location.line_position as usize,
+                message,
+            )
+        }
+        None => Diagnostic::error(1, message),
+    }
+}
+
+// 收集需要转换的 attribute, varying, 输出变量和 uniform 声明, 已经带有 layout 的声明保持不变
+fn collect_interface_declarations(
+    shader: &ParsedShader,
+    stage: ShaderStage,
+) -> Result<Vec<InterfaceDeclaration>, Diagnostic> {
+    let mut declarations = vec![];
+    let mut consts = HashMap::new();
+    for decl in &shader.declarations {
+        let ast::ExternalDeclarationData::Declaration(decl) = &decl.content else {
+            continue;
+        };
+        let ast::DeclarationData::InitDeclaratorList(decl_list) = &decl.content else {
+            continue;
+        };
+        collect_const_ints(decl_list, &mut consts);
+        let head = &decl_list.content.head;
+        let Some(qualifier) = head.ty.content.qualifier.as_ref() else {
+            continue;
+        };
+        let mut kind = None;
+        let mut has_layout = false;
+        for q in &qualifier.qualifiers {
+            match &q.content {
+                ast::TypeQualifierSpecData::Storage(s) => {
+                    kind = match (&s.content, stage) {
+                        (ast::StorageQualifierData::Attribute, _) => Some(InterfaceKind::Attribute),
+                        (ast::StorageQualifierData::Varying, _) => Some(InterfaceKind::Varying),
+                        (ast::StorageQualifierData::Uniform, _) => Some(InterfaceKind::Uniform),
+                        (ast::StorageQualifierData::In, ShaderStage::Vertex) => {
+                            Some(InterfaceKind::Attribute)
+                        }
+                        (ast::StorageQualifierData::In, ShaderStage::Fragment) => {
+                            Some(InterfaceKind::Varying)
+                        }
+                        (ast::StorageQualifierData::Out, ShaderStage::Vertex) => {
+                            Some(InterfaceKind::Varying)
+                        }
+                        (ast::StorageQualifierData::Out, ShaderStage::Fragment) => {
+                            Some(InterfaceKind::Output)
+                        }
+                        _ => kind,
+                    }
+                }
+                ast::TypeQualifierSpecData::Layout(_) => has_layout = true,
+                _ => {}
+            }
+        }
+        let (Some(kind), false, Some(span)) = (kind, has_layout, decl.span) else {
+            continue;
+        };
+
+        let line = shader.line_of(decl.span);
+        let slice = |span: Option<ast::NodeSpan>| {
+            span.map(|s| &shader.source[usize::from(s.start().offset)..usize::from(s.end().offset)])
+                .unwrap_or_default()
+                .to_string()
+        };
+        let subject = |name: &str| {
+            let qualifier = match kind {
+                InterfaceKind::Attribute => "attribute",
+                InterfaceKind::Varying => "varying",
+                InterfaceKind::Output => "out",
+                InterfaceKind::Uniform => "uniform",
+            };
+            format!("{} {}", qualifier, name)
+        };
+        let head_name = head.name.as_ref().map_or("", |n| n.content.as_str());
+        let type_length = array_length(
+            head.ty.ty.array_specifier.as_ref(),
+            &consts,
+            &subject(head_name),
+        )
+        .map_err(|e| Diagnostic::error(line, e))?;
+        let mut declarators = vec![];
+        let head_declarator = head
+            .name
+            .as_ref()
+            .map(|n| (n.content.as_str(), head.array_specifier.as_ref()));
+        let tail = decl_list
+            .content
+            .tail
+            .iter()
+            .map(|d| (d.ident.ident.content.as_str(), d.ident.array_spec.as_ref()));
+        for (name, array_specifier) in head_declarator.into_iter().chain(tail) {
+            let length = array_length(array_specifier, &consts, &subject(name))
+                .map_err(|e| Diagnostic::error(line, e))?
+                .or(type_length);
+            declarators.push((
+                name.to_string(),
+                slice(array_specifier.and_then(|a| a.span)),
+                location_count(&head.ty.ty.ty.content, length),
+            ));
+        }
+        declarations.push(InterfaceDeclaration {
+            kind,
+            range: usize::from(span.start().offset)..usize::from(span.end().offset),
+            line,
+            type_text: slice(head.ty.ty.span),
+            declarators,
+        });
+    }
+    Ok(declarations)
+}
+
+// 变量占用的 location 数量, 矩阵每列占用一个
+fn location_count(r#type: &ast::TypeSpecifierNonArrayData, array_length: Option<u32>) -> u32 {
+    let columns = match r#type {
+        ast::TypeSpecifierNonArrayData::Mat2 => 2,
+        ast::TypeSpecifierNonArrayData::Mat3 => 3,
+        ast::TypeSpecifierNonArrayData::Mat4 => 4,
+        _ => 1,
+    };
+    columns * array_length.unwrap_or(1)
+}
+
+// 把代码替换为空格, 保留换行
+fn blank(code: &mut [u8]) {
+    for b in code.iter_mut().filter(|b| **b != b'\n') {
+        *b = b' ';
+    }
+}
+
+// 去掉 GLSL 450 中无效的 `#version`, `#extension` 和 `#pragma`, 替换为空格以保持字节位置不变
+fn blank_directives(source: &str) -> String {
+    source
+        .split('\n')
+        .map(|line| {
+            let directive = line.trim_start().strip_prefix('#').map(str::trim_start);
+            match directive {
+                Some(d)
+                    if d.starts_with("version")
+                        || d.starts_with("extension")
+                        || d.starts_with("pragma") =>
+                {
+                    " ".repeat(line.len())
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// 采样器数组下标的计算结果, 键为数组名在预处理后代码中的字节位置, 无法计算的下标为 None
+fn collect_sampler_indices(
+    shader: &ParsedShader,
+    samplers: &[&SamplerBinding],
+) -> HashMap<usize, Option<i64>> {
+    let mut collector = SamplerIndexCollector {
+        names: samplers
+            .iter()
+            .filter(|s| s.index.is_some())
+            .map(|s| s.name.as_str())
+            .collect(),
+        consts: HashMap::new(),
+        indices: HashMap::new(),
+    };
+    for declaration in &shader.declarations {
+        declaration.visit(&mut collector);
+    }
+    collector.indices
+}
+
+struct SamplerIndexCollector<'a> {
+    names: HashSet<&'a str>,
+    // 按出现顺序记录的整数常量, 包括函数内的局部常量
+    consts: HashMap<String, i64>,
+    indices: HashMap<usize, Option<i64>>,
+}
+
+impl Visitor for SamplerIndexCollector<'_> {
+    fn visit_init_declarator_list(&mut self, list: &ast::InitDeclaratorList) -> Visit {
+        collect_const_ints(list, &mut self.consts);
+        Visit::Children
+    }
+
+    fn visit_expr(&mut self, expr: &ast::Expr) -> Visit {
+        if let ast::ExprData::Bracket(array, index) = &expr.content {
+            if let (ast::ExprData::Variable(name), Some(span)) = (&array.content, array.span) {
+                if self.names.contains(name.content.as_str()) {
+                    self.indices.insert(
+                        usize::from(span.start().offset),
+                        eval_const_int(index, &self.consts),
+                    );
+                }
+            }
+        }
+        Visit::Children
+    }
+}
+
+// 替换 GLSL 450 中不存在的标识符, 返回替换后的代码和发生替换的行号.
+// 采样器数组按下标替换为对应的元素, 下标越界时报错; 下标不是整数常量表达式 (如循环变量) 时无法转换
+fn rewrite_identifiers(
+    source: &str,
+    samplers: &[&SamplerBinding],
+    sampler_indices: &HashMap<usize, Option<i64>>,
+    stage: ShaderStage,
+) -> Result<(String, HashSet<usize>), TranslateError> {
+    let bytes = source.as_bytes();
+    let mut output = String::with_capacity(source.len());
+    let mut rewritten_lines = HashSet::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if !(c.is_ascii_alphanumeric() || c == b'_') {
+            // 标识符之外的部分按切片原样复制, 注释和字符串中可能有多字节字符
+            let start = i;
+            while i < bytes.len() && !(bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let text = &source[start..i];
+            line += text.matches('\n').count();
+            output.push_str(text);
+            continue;
+        }
+        let start = i;
+        while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+            i += 1;
+        }
+        let word = &source[start..i];
+        // 数字字面量 (如 `1e5`) 原样输出
+        if c.is_ascii_digit() {
+            output.push_str(word);
+            continue;
+        }
+
+        let replacement = match word {
+            "texture2D" | "textureCube" => Some("texture".to_string()),
+            "texture2DProj" => Some("textureProj".to_string()),
+            "texture2DLod" | "textureCubeLod" => Some("textureLod".to_string()),
+            "texture2DProjLod" => Some("textureProjLod".to_string()),
+            "gl_FragColor" if stage == ShaderStage::Fragment => Some(FRAG_COLOR_NAME.to_string()),
+            "gl_FragData" if stage == ShaderStage::Fragment => match subscript(source, i) {
+                Some((index, end)) if index.trim() == "0" => {
+                    i = end;
+                    Some(FRAG_COLOR_NAME.to_string())
+                }
+                _ => None,
+            },
+            _ => match samplers.iter().find(|s| s.name == word) {
+                Some(sampler) if sampler.index.is_none() => Some(combined_sampler(sampler)),
+                Some(_) => {
+                    let Some((index_text, end)) = subscript(source, i) else {
+                        return Err(TranslateError::Unsupported(Diagnostic::error(
+                            line,
+                            format!("采样器数组 {} 只能按下标使用, 如 {}[0]", word, word),
+                        )));
+                    };
+                    let index = sampler_indices.get(&start).copied().flatten();
+                    let element = index.and_then(|index| {
+                        samplers
+                            .iter()
+                            .find(|s| s.name == word && s.index.map(i64::from) == Some(index))
+                    });
+                    let Some(element) = element else {
+                        return Err(match index {
+                            Some(index) => TranslateError::Invalid(Diagnostic::error(
+                                line,
+                                format!("采样器数组 {} 的下标 {} 越界", word, index),
+                            )),
+                            None => TranslateError::Unsupported(Diagnostic::error(
+                                line,
+                                format!(
+                                    "不支持动态的采样器数组下标 {}[{}]",
+                                    word,
+                                    index_text.trim()
+                                ),
+                            )),
+                        });
+                    };
+                    line += source[i..end].matches('\n').count();
+                    i = end;
+                    Some(combined_sampler(element))
+                }
+                None => None,
+            },
+        };
+        match replacement {
+            Some(replacement) => {
+                output.push_str(&replacement);
+                rewritten_lines.insert(line);
+            }
+            None => output.push_str(word),
+        }
+    }
+    Ok((output, rewritten_lines))
+}
+
+// 读取 `start` 之后的 `[...]`, 返回括号内的代码和右括号之后的位置
+fn subscript(source: &str, start: usize) -> Option<(&str, usize)> {
+    let rest = &source[start..];
+    let open = start + rest.len() - rest.trim_start().len();
+    if source.as_bytes().get(open) != Some(&b'[') {
+        return None;
+    }
+    let mut depth = 0;
+    for (offset, c) in source[open..].char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    let close = open + offset;
+                    return Some((&source[open + 1..close], close + 1));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn combined_sampler(sampler: &SamplerBinding) -> String {
+    format!(
+        "sampler2D({}, {})",
+        sampler.texture_name(),
+        sampler.sampler_name()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_identifiers_keeps_utf8() {
+        let sampler = SamplerBinding {
+            name: "SAMPLER".to_string(),
+            index: Some(0),
+            texture_binding: 0,
+            sampler_binding: 1,
+        };
+        let source = "// 采样纹理\nvoid main() {\n    /* 颜色 */ gl_FragColor = texture2D(SAMPLER[0], vec2(0.5));\n}\n";
+        let start = source.find("SAMPLER[").unwrap();
+        let indices = HashMap::from([(start, Some(0))]);
+        let (output, lines) =
+            rewrite_identifiers(source, &[&sampler], &indices, ShaderStage::Fragment).unwrap();
+        assert_eq!(
+            output,
+            "// 采样纹理\nvoid main() {\n    /* 颜色 */ _ksh_FragColor = texture(sampler2D(SAMPLER_0_texture, SAMPLER_0_sampler), vec2(0.5));\n}\n"
+        );
+        assert_eq!(lines, HashSet::from([3]));
+    }
+}
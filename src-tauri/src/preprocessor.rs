@@ -0,0 +1,1069 @@
+use crate::diagnostic::Diagnostic;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 预处理选项
+#[derive(Debug, Clone, Default)]
+pub struct PreprocessOptions {
+    /// 预定义宏, 例如 `GL_ES`
+    pub defines: Vec<(String, String)>,
+    /// `#include` 的搜索路径
+    pub include_paths: Vec<PathBuf>,
+}
+
+impl PreprocessOptions {
+    /// 解析 `NAME` 或 `NAME=VALUE` 形式的宏定义, 未指定值时为 `1`
+    pub fn parse_define(define: &str) -> Result<(String, String), String> {
+        let (name, value) = match define.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => (define.trim(), "1"),
+        };
+        if !is_identifier(name) {
+            return Err(format!("无效的宏名称: {}", name));
+        }
+        Ok((name.to_string(), value.to_string()))
+    }
+}
+
+/// 展开 `#include` 之后的代码
+#[derive(Debug)]
+pub struct IncludedSource {
+    pub code: String,
+    /// 每一行对应的原始文件和行号
+    pub line_map: Vec<(PathBuf, usize)>,
+}
+
+impl IncludedSource {
+    /// 将展开后代码中的行号映射回原始文件
+    pub fn locate(&self, line: usize) -> Option<(&Path, usize)> {
+        let (path, line) = self.line_map.get(line.checked_sub(1)?)?;
+        Some((path, *line))
+    }
+
+    /// 将展开后代码中的诊断改写为指向原始文件和行号, 并附上出错的代码行, 其它错误原样返回
+    pub fn map_error(&self, error: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+        match error.downcast::<Diagnostic>() {
+            Ok(diagnostic) => Box::new(self.map_diagnostic(*diagnostic)),
+            Err(error) => error,
+        }
+    }
+
+    /// 将展开后代码中的诊断改写为指向原始文件和行号, 已经带有文件的诊断保持不变
+    pub fn map_diagnostic(&self, diagnostic: Diagnostic) -> Diagnostic {
+        if diagnostic.file.is_some() {
+            return diagnostic;
+        }
+        let Some((path, line)) = self.locate(diagnostic.line) else {
+            return diagnostic;
+        };
+        let source_line = self
+            .code
+            .lines()
+            .nth(diagnostic.line - 1)
+            .map(str::to_string);
+        Diagnostic {
+            file: Some(path.display().to_string()),
+            line,
+            source_line,
+            ..diagnostic
+        }
+    }
+}
+
+/// 递归展开 `#include "..."`, 其余代码保持不变
+///
+/// `path` 只用于诊断和检测循环包含, 不会据此查找文件: 着色器来自磁盘时, 调用方需要把它所在的目录放在
+/// `include_paths` 的最前面. 被包含的文件中的 `#include` 先在该文件所在目录查找, 然后依次为 `include_paths`.
+///
+/// 展开后的代码会写入 KSH, 而 `defines` 不会, 所以游戏中生效的分支只由代码中的宏决定.
+/// 只有不论是否加上 `defines` 都不会启用的分支 (如 `#if 0`) 中的 `#include` 才原样保留, 由 [`preprocess`] 去掉,
+/// 其余的都会展开, 找不到文件时报错.
+pub fn resolve_includes(
+    source: &str,
+    path: &Path,
+    options: &PreprocessOptions,
+) -> Result<IncludedSource, Box<dyn std::error::Error>> {
+    let mut included = IncludedSource {
+        code: String::with_capacity(source.len()),
+        line_map: vec![],
+    };
+    let mut state = IncludeState {
+        include_paths: &options.include_paths,
+        files: vec![],
+        runtime: ConditionTracker::new(&[]),
+        analysis: ConditionTracker::new(&options.defines),
+    };
+    inline_includes(source, path, None, &mut state, &mut included)?;
+    Ok(included)
+}
+
+// 展开 #include 时的状态
+struct IncludeState<'a> {
+    include_paths: &'a [PathBuf],
+    // 正在展开的文件, 用于检测循环包含
+    files: Vec<PathBuf>,
+    // 游戏中 (只有代码中的宏) 和解析时 (加上预定义宏) 的条件编译状态
+    runtime: ConditionTracker,
+    analysis: ConditionTracker,
+}
+
+impl IncludeState<'_> {
+    // 任一种情况下启用的分支中的 #include 都需要展开
+    fn active(&self) -> bool {
+        self.runtime.active() || self.analysis.active()
+    }
+
+    fn track_directive(&mut self, line: &str, line_number: usize) {
+        let line = strip_comments(line);
+        if let Some((name, rest)) = split_directive(&line) {
+            self.runtime.track_directive(name, rest, line_number);
+            self.analysis.track_directive(name, rest, line_number);
+        }
+    }
+}
+
+// 宏和条件编译块, 只用于判断 #include 是否在启用的分支中
+struct ConditionTracker {
+    macros: HashMap<String, Macro>,
+    conditionals: Vec<Conditional>,
+}
+
+impl ConditionTracker {
+    fn new(defines: &[(String, String)]) -> Self {
+        ConditionTracker {
+            macros: predefined_macros(defines),
+            conditionals: vec![],
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.conditionals.last().map(|c| c.active).unwrap_or(true)
+    }
+
+    // 跟踪宏定义和条件指令, 指令中的错误由 preprocess 报告
+    fn track_directive(&mut self, name: &str, rest: &str, line_number: usize) {
+        let active = self.active();
+        match apply_conditional(
+            &mut self.conditionals,
+            name,
+            rest,
+            &self.macros,
+            line_number,
+        ) {
+            Ok(true) => {}
+            // 无法计算的条件按启用处理
+            Err(_) if name == "if" => self.conditionals.push(Conditional {
+                parent_active: active,
+                active,
+                taken: active,
+                seen_else: false,
+                line: line_number,
+            }),
+            Err(_) => {}
+            Ok(false) if !active => {}
+            Ok(false) => match name {
+                "define" => {
+                    if let Ok((name, mac)) = parse_define(rest) {
+                        self.macros.insert(name, mac);
+                    }
+                }
+                "undef" => {
+                    self.macros.remove(rest);
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+fn inline_includes(
+    source: &str,
+    path: &Path,
+    base_dir: Option<&Path>,
+    state: &mut IncludeState,
+    included: &mut IncludedSource,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let identity = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if state.files.contains(&identity) {
+        let chain: Vec<String> = state
+            .files
+            .iter()
+            .chain(std::iter::once(&identity))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(format!("检测到循环包含: {}", chain.join(" -> ")).into());
+    }
+    state.files.push(identity);
+
+    // 行号会被 `#line N` 重置, 与编译器的行为保持一致
+    let mut line_number = 1;
+    for line in source.split_inclusive('\n') {
+        let current = line_number;
+        line_number = parse_line_directive(line).unwrap_or(line_number + 1);
+        let target = match parse_include(line) {
+            Some(target) if state.active() => target,
+            _ => {
+                state.track_directive(line, current);
+                included.code.push_str(line);
+                included.line_map.push((path.to_path_buf(), current));
+                continue;
+            }
+        };
+        let include_error = |message: String| {
+            Diagnostic::error(current, message)
+                .with_file(path.display().to_string())
+                .with_source_line(line)
+        };
+        let target = target.map_err(include_error)?;
+        let resolved = base_dir
+            .into_iter()
+            .chain(state.include_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(target))
+            .find(|p| p.is_file())
+            .ok_or_else(|| include_error(format!("找不到包含文件 \"{}\"", target)))?;
+        let content = fs::read_to_string(&resolved)
+            .map_err(|e| format!("读取包含文件 {} 失败: {}", resolved.display(), e))?;
+        inline_includes(&content, &resolved, resolved.parent(), state, included)?;
+        if !included.code.ends_with('\n') {
+            included.code.push('\n');
+        }
+    }
+
+    state.files.pop();
+    Ok(())
+}
+
+// 解析 `#line N`, 返回下一行的行号. 与 GLSL ES 1.00 一致, 下一行为 N + 1
+fn parse_line_directive(line: &str) -> Option<usize> {
+    let rest = line.trim_start().strip_prefix('#')?.trim_start();
+    let line: usize = rest
+        .strip_prefix("line")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some(line + 1)
+}
+
+/// 生成变体代码: 在 `#version` 之后 (没有时在开头) 插入宏定义, 并用 `#line` 保持原始行号
+pub fn specialize_source(source: &str, defines: &[(String, String)]) -> String {
+    if defines.is_empty() {
+        return source.to_string();
+    }
+    let lines: Vec<&str> = source.split_inclusive('\n').collect();
+    let split = lines
+        .iter()
+        .position(|l| l.trim_start().starts_with("#version"))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+
+    let mut result = String::with_capacity(source.len());
+    for line in &lines[..split] {
+        result.push_str(line);
+    }
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    for (name, value) in defines {
+        result.push_str(&format!("#define {} {}\n", name, value));
+    }
+    result.push_str(&format!("#line {}\n", split));
+    for line in &lines[split..] {
+        result.push_str(line);
+    }
+    result
+}
+
+// 解析 `#include "file"` 或 `#include <file>`, 不是 include 指令时返回 None
+fn parse_include(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim_start().strip_prefix('#')?.trim_start();
+    let rest = rest.strip_prefix("include")?.trim();
+    let target = rest
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .or_else(|| rest.strip_prefix('<').and_then(|r| r.strip_suffix('>')));
+    Some(match target {
+        Some(target) if !target.is_empty() => Ok(target),
+        _ => Err(format!("无效的 #include 指令: {}", line.trim())),
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+// 条件编译块的状态
+struct Conditional {
+    parent_active: bool,
+    active: bool,
+    taken: bool,
+    seen_else: bool,
+    /// 开始这个条件块的 #if 所在行
+    line: usize,
+}
+
+/// 展开预处理指令, 返回可直接交给 glsl_lang 解析的代码
+///
+/// 输出与输入行数一致: 指令行和未启用的分支会被替换为空行, 以便错误行号仍然对应原始代码.
+/// `#version` `#extension` `#pragma` 会原样保留.
+pub fn preprocess(
+    source: &str,
+    options: &PreprocessOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut macros = predefined_macros(&options.defines);
+    let mut stack: Vec<Conditional> = vec![];
+    let mut output = vec![];
+
+    let source = strip_comments(source);
+    let lines = join_continued_lines(&source);
+    for (index, line) in lines.iter().enumerate() {
+        let line_number = index + 1;
+        let active = stack.last().map(|c| c.active).unwrap_or(true);
+        let error = |message: String| Diagnostic::error(line_number, message);
+
+        let Some((name, rest)) = split_directive(line) else {
+            if active {
+                output.push(expand(line, &macros, &mut vec![]).map_err(error)?);
+            } else {
+                output.push(String::new());
+            }
+            continue;
+        };
+        if apply_conditional(&mut stack, name, rest, &macros, line_number).map_err(error)? {
+            output.push(String::new());
+            continue;
+        }
+
+        match name {
+            _ if !active => {}
+            "define" => {
+                let (name, mac) = parse_define(rest).map_err(error)?;
+                macros.insert(name, mac);
+            }
+            "undef" => {
+                macros.remove(rest);
+            }
+            "error" => {
+                return Err(error(format!("#error {}", rest)).into());
+            }
+            "version" | "extension" | "pragma" => {
+                output.push(line.clone());
+                continue;
+            }
+            "line" | "" => {}
+            "include" => {
+                return Err(
+                    error("#include 需要在构建时通过 resolve_includes 展开".to_string()).into(),
+                );
+            }
+            _ => {
+                return Err(error(format!("未知的预处理指令: #{}", name)).into());
+            }
+        }
+        output.push(String::new());
+    }
+
+    if let Some(unclosed) = stack.last() {
+        return Err(Diagnostic::error(unclosed.line, "#if 缺少对应的 #endif").into());
+    }
+    Ok(output.join("\n"))
+}
+
+fn predefined_macros(defines: &[(String, String)]) -> HashMap<String, Macro> {
+    defines
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.clone(),
+                Macro {
+                    params: None,
+                    body: value.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+// 拆分预处理指令的名称和其余部分, 不是指令时返回 None
+fn split_directive(line: &str) -> Option<(&str, &str)> {
+    let directive = line.trim_start().strip_prefix('#')?.trim_start();
+    let name_end = directive
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(directive.len());
+    let (name, rest) = directive.split_at(name_end);
+    Some((name, rest.trim()))
+}
+
+// 处理条件编译指令, 不是条件指令时返回 false
+fn apply_conditional(
+    stack: &mut Vec<Conditional>,
+    name: &str,
+    rest: &str,
+    macros: &HashMap<String, Macro>,
+    line: usize,
+) -> Result<bool, String> {
+    let active = stack.last().map(|c| c.active).unwrap_or(true);
+    match name {
+        "ifdef" | "ifndef" => {
+            let defined = macros.contains_key(rest);
+            let cond = if name == "ifdef" { defined } else { !defined };
+            stack.push(Conditional {
+                parent_active: active,
+                active: active && cond,
+                taken: cond,
+                seen_else: false,
+                line,
+            });
+        }
+        "if" => {
+            let cond = active && eval_condition(rest, macros)? != 0;
+            stack.push(Conditional {
+                parent_active: active,
+                active: cond,
+                taken: cond,
+                seen_else: false,
+                line,
+            });
+        }
+        "elif" => {
+            let top = stack.last_mut().ok_or("#elif 缺少对应的 #if")?;
+            if top.seen_else {
+                return Err("#elif 出现在 #else 之后".to_string());
+            }
+            if top.taken || !top.parent_active {
+                top.active = false;
+            } else {
+                let cond = eval_condition(rest, macros)? != 0;
+                top.active = cond;
+                top.taken = cond;
+            }
+        }
+        "else" => {
+            let top = stack.last_mut().ok_or("#else 缺少对应的 #if")?;
+            if top.seen_else {
+                return Err("重复的 #else".to_string());
+            }
+            top.seen_else = true;
+            top.active = top.parent_active && !top.taken;
+            top.taken = true;
+        }
+        "endif" => {
+            stack.pop().ok_or("#endif 缺少对应的 #if")?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// 将注释替换为空格, 保留换行
+fn strip_comments(source: &str) -> String {
+    let mut result = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+                result.push(' ');
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = '\0';
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        result.push('\n');
+                    }
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    last = next;
+                }
+                result.push(' ');
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+// 合并以 `\` 结尾的续行, 被合并的行以空行占位
+fn join_continued_lines(source: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut pending = String::new();
+    let mut joined = 0;
+    for line in source.lines() {
+        match line.strip_suffix('\\') {
+            Some(line) => {
+                pending.push_str(line);
+                joined += 1;
+            }
+            None => {
+                pending.push_str(line);
+                lines.push(std::mem::take(&mut pending));
+                for _ in 0..joined {
+                    lines.push(String::new());
+                }
+                joined = 0;
+            }
+        }
+    }
+    if joined > 0 {
+        lines.push(pending);
+        for _ in 1..joined {
+            lines.push(String::new());
+        }
+    }
+    lines
+}
+
+fn parse_define(rest: &str) -> Result<(String, Macro), String> {
+    let name_end = rest
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(name_end);
+    if !is_identifier(name) {
+        return Err(format!("无效的宏名称: {}", name));
+    }
+    // 宏名后紧跟 `(` 才是函数宏
+    if let Some(rest) = rest.strip_prefix('(') {
+        let close = rest.find(')').ok_or("函数宏缺少 `)`")?;
+        let params: Vec<String> = rest[..close]
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if let Some(param) = params.iter().find(|p| !is_identifier(p)) {
+            return Err(format!("无效的宏参数: {}", param));
+        }
+        return Ok((
+            name.to_string(),
+            Macro {
+                params: Some(params),
+                body: rest[close + 1..].trim().to_string(),
+            },
+        ));
+    }
+    Ok((
+        name.to_string(),
+        Macro {
+            params: None,
+            body: rest.trim().to_string(),
+        },
+    ))
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Other(&'a str),
+}
+
+// 拆分为标识符和其它片段, 数字字面量整体作为一个片段, 避免把 `1e5` 中的 `e5` 当成标识符
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let bytes = text.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(&text[start..i]));
+        } else if c.is_ascii_digit()
+            || (c == b'.' && bytes.get(i + 1).is_some_and(|b| b.is_ascii_digit()))
+        {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                i += 1;
+            }
+            tokens.push(Token::Other(&text[start..i]));
+        } else {
+            i += text[i..].chars().next().map(char::len_utf8).unwrap_or(1);
+            tokens.push(Token::Other(&text[start..i]));
+        }
+    }
+    tokens
+}
+
+// 展开一行中的宏, `disabled` 记录正在展开的宏以防止递归
+fn expand(
+    text: &str,
+    macros: &HashMap<String, Macro>,
+    disabled: &mut Vec<String>,
+) -> Result<String, String> {
+    let tokens = tokenize(text);
+    let mut result = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        i += 1;
+        let name = match token {
+            Token::Ident(name) => *name,
+            Token::Other(other) => {
+                result.push_str(other);
+                continue;
+            }
+        };
+        let Some(mac) = macros
+            .get(name)
+            .filter(|_| !disabled.iter().any(|d| d == name))
+        else {
+            result.push_str(name);
+            continue;
+        };
+        let body = match &mac.params {
+            None => mac.body.clone(),
+            Some(params) => {
+                // 函数宏后面没有参数列表时不展开
+                let mut j = i;
+                while j < tokens.len() && tokens[j] == Token::Other(" ") {
+                    j += 1;
+                }
+                if tokens.get(j) != Some(&Token::Other("(")) {
+                    result.push_str(name);
+                    continue;
+                }
+                let (args, next) = collect_macro_args(&tokens, j + 1)
+                    .ok_or_else(|| format!("宏 {} 的参数列表缺少 `)`", name))?;
+                i = next;
+                let no_args = params.is_empty() && args.len() == 1 && args[0].trim().is_empty();
+                if args.len() != params.len() && !no_args {
+                    return Err(format!(
+                        "宏 {} 需要 {} 个参数, 实际为 {} 个",
+                        name,
+                        params.len(),
+                        args.len()
+                    ));
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| expand(arg, macros, disabled))
+                    .collect::<Result<Vec<_>, _>>()?;
+                tokenize(&mac.body)
+                    .iter()
+                    .map(|t| match t {
+                        Token::Ident(id) => params
+                            .iter()
+                            .position(|p| p == id)
+                            .map(|p| args[p].trim())
+                            .unwrap_or(*id),
+                        Token::Other(other) => *other,
+                    })
+                    .collect()
+            }
+        };
+        disabled.push(name.to_string());
+        let expanded = expand(&body, macros, disabled);
+        disabled.pop();
+        result.push_str(&expanded?);
+    }
+    Ok(result)
+}
+
+// 从 `(` 之后开始收集参数, 返回参数列表和 `)` 之后的位置
+fn collect_macro_args(tokens: &[Token], start: usize) -> Option<(Vec<String>, usize)> {
+    let mut args = vec![String::new()];
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        let text = match token {
+            Token::Ident(t) | Token::Other(t) => *t,
+        };
+        match text {
+            "(" => depth += 1,
+            ")" if depth == 0 => return Some((args, i + 1)),
+            ")" => depth -= 1,
+            "," if depth == 0 => {
+                args.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        args.last_mut()?.push_str(text);
+    }
+    None
+}
+
+// 计算 #if / #elif 的条件表达式
+fn eval_condition(expr: &str, macros: &HashMap<String, Macro>) -> Result<i64, String> {
+    // 先处理 defined, 避免其参数被宏展开
+    let tokens = tokenize(expr);
+    let mut replaced = String::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i] != Token::Ident("defined") {
+            match &tokens[i] {
+                Token::Ident(t) | Token::Other(t) => replaced.push_str(t),
+            }
+            i += 1;
+            continue;
+        }
+        let rest: Vec<&Token> = tokens[i + 1..]
+            .iter()
+            .filter(|t| **t != Token::Other(" ") && **t != Token::Other("\t"))
+            .take(3)
+            .collect();
+        let (name, consumed) = match rest.as_slice() {
+            [Token::Other("("), Token::Ident(name), Token::Other(")")] => (*name, 3),
+            [Token::Ident(name), ..] => (*name, 1),
+            _ => return Err("defined 后缺少宏名称".to_string()),
+        };
+        // 跳过 defined 之后被消耗的非空白片段
+        let mut skipped = 0;
+        i += 1;
+        while skipped < consumed {
+            if tokens[i] != Token::Other(" ") && tokens[i] != Token::Other("\t") {
+                skipped += 1;
+            }
+            i += 1;
+        }
+        replaced.push_str(if macros.contains_key(name) {
+            " 1 "
+        } else {
+            " 0 "
+        });
+    }
+
+    let expanded = expand(&replaced, macros, &mut vec![])?;
+    let tokens: Vec<&str> = tokenize_expr(&expanded)?;
+    let mut parser = ExprParser { tokens, pos: 0 };
+    let value = parser.parse_binary(0)?;
+    if parser.pos != parser.tokens.len() {
+        return Err(format!("无法解析的条件表达式: {}", expr));
+    }
+    Ok(value)
+}
+
+fn tokenize_expr(expr: &str) -> Result<Vec<&str>, String> {
+    const OPERATORS: &[&str] = &[
+        "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "(", ")", "+", "-", "*", "/", "%", "<",
+        ">", "!", "~", "&", "|", "^",
+    ];
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let len = if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len())
+        } else {
+            OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .map(|op| op.len())
+                .ok_or_else(|| format!("条件表达式中的非法字符: {}", rest))?
+        };
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    // 二元运算符优先级, 数值越大优先级越高
+    const BINARY_OPERATORS: &'static [(&'static str, u8)] = &[
+        ("||", 1),
+        ("&&", 2),
+        ("|", 3),
+        ("^", 4),
+        ("&", 5),
+        ("==", 6),
+        ("!=", 6),
+        ("<", 7),
+        (">", 7),
+        ("<=", 7),
+        (">=", 7),
+        ("<<", 8),
+        (">>", 8),
+        ("+", 9),
+        ("-", 9),
+        ("*", 10),
+        ("/", 10),
+        ("%", 10),
+    ];
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<i64, String> {
+        let mut left = self.parse_unary()?;
+        while let Some(&(op, precedence)) = self.tokens.get(self.pos).and_then(|t| {
+            Self::BINARY_OPERATORS
+                .iter()
+                .find(|(op, p)| op == t && *p > min_precedence)
+        }) {
+            self.pos += 1;
+            let right = self.parse_binary(precedence)?;
+            left = match op {
+                "||" => (left != 0 || right != 0) as i64,
+                "&&" => (left != 0 && right != 0) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "/" | "%" if right == 0 => return Err("条件表达式中除数为 0".to_string()),
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<i64, String> {
+        let token = *self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| "条件表达式不完整".to_string())?;
+        self.pos += 1;
+        match token {
+            "+" => self.parse_unary(),
+            "-" => Ok(self.parse_unary()?.wrapping_neg()),
+            "!" => Ok((self.parse_unary()? == 0) as i64),
+            "~" => Ok(!self.parse_unary()?),
+            "(" => {
+                let value = self.parse_binary(0)?;
+                if self.tokens.get(self.pos) != Some(&")") {
+                    return Err("条件表达式缺少 `)`".to_string());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => parse_int(token),
+        }
+    }
+}
+
+fn parse_int(token: &str) -> Result<i64, String> {
+    // 未定义的标识符按 0 处理
+    if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return Ok(0);
+    }
+    let digits = token.trim_end_matches(['u', 'U']);
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    };
+    value.map_err(|_| format!("无效的整数: {}", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::TestContext;
+
+    fn run(source: &str, defines: &[&str]) -> Vec<String> {
+        let options = PreprocessOptions {
+            defines: defines
+                .iter()
+                .map(|d| PreprocessOptions::parse_define(d).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        preprocess(source, &options)
+            .unwrap()
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_elif_chain() {
+        let source = "#if MODE == 1\none\n#elif MODE == 2\ntwo\n#elif MODE == 3\nthree\n#else\nother\n#endif\n";
+        assert_eq!(run(source, &["MODE=1"]), ["one"]);
+        assert_eq!(run(source, &["MODE=2"]), ["two"]);
+        assert_eq!(run(source, &["MODE=3"]), ["three"]);
+        assert_eq!(run(source, &["MODE=4"]), ["other"]);
+        // 已经命中的分支之后, 即使条件成立也不再启用
+        let source = "#if 1\na\n#elif 1\nb\n#else\nc\n#endif\n";
+        assert_eq!(run(source, &[]), ["a"]);
+    }
+
+    #[test]
+    fn test_nested_if_zero() {
+        let source = "#if 0\n#if 1\na\n#else\nb\n#endif\n#define HIDDEN 1\n#error 不应报错\n#else\nc\n#endif\n#ifdef HIDDEN\nd\n#endif\n";
+        assert_eq!(run(source, &[]), ["c"]);
+        // 外层未启用时内层的 #elif 和 #else 也不生效
+        let source = "#if 0\n#if 0\na\n#elif 1\nb\n#else\nc\n#endif\n#endif\nend\n";
+        assert_eq!(run(source, &[]), ["end"]);
+    }
+
+    #[test]
+    fn test_defined_forms() {
+        let source = "#if defined(A) && defined B\nboth\n#elif defined A || !defined(B)\nonly_a\n#else\nnone\n#endif\n";
+        assert_eq!(run(source, &["A", "B"]), ["both"]);
+        assert_eq!(run(source, &["A"]), ["only_a"]);
+        assert_eq!(run(source, &["B"]), ["none"]);
+    }
+
+    #[test]
+    fn test_function_like_macro() {
+        let source = "#define SCALE(v, s) ((v) * (s))\n#define PI 3.14\nfloat x = SCALE(a + 1.0, PI);\nfloat y = SCALE (vec2(1.0, 2.0), 2.0);\nfloat SCALE;\n";
+        assert_eq!(
+            run(source, &[]),
+            [
+                "float x = ((a + 1.0) * (3.14));",
+                "float y = ((vec2(1.0, 2.0)) * (2.0));",
+                // 没有参数列表时不展开函数宏
+                "float SCALE;",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preprocess_keeps_line_numbers() {
+        let source = "#define A 1\n#if A\nfoo\n#endif\nbar\n";
+        let output = preprocess(source, &PreprocessOptions::default()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2].trim(), "foo");
+        assert_eq!(lines[4].trim(), "bar");
+
+        let error = preprocess("a\n#if 1\nb\n", &PreprocessOptions::default()).unwrap_err();
+        let diagnostic = error.downcast::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 2);
+    }
+
+    #[test]
+    fn test_line_directive_mapping() {
+        let path = Path::new("shader.ps");
+        let source = "a\n#line 100\nb\nc\n";
+        let included = resolve_includes(source, path, &PreprocessOptions::default()).unwrap();
+        assert_eq!(included.locate(1), Some((path, 1)));
+        assert_eq!(included.locate(3), Some((path, 101)));
+        assert_eq!(included.locate(4), Some((path, 102)));
+
+        let diagnostic = included.map_diagnostic(Diagnostic::error(4, "错误"));
+        assert_eq!(diagnostic.file.as_deref(), Some("shader.ps"));
+        assert_eq!(diagnostic.line, 102);
+        assert_eq!(diagnostic.source_line.as_deref(), Some("c"));
+
+        // 已经带有文件的诊断保持不变, 超出范围的行号也不改写
+        let diagnostic = included.map_diagnostic(Diagnostic::error(3, "错误").with_file("other"));
+        assert_eq!(
+            (diagnostic.file.as_deref(), diagnostic.line),
+            (Some("other"), 3)
+        );
+        let diagnostic = included.map_diagnostic(Diagnostic::error(10, "错误"));
+        assert_eq!((diagnostic.file, diagnostic.line), (None, 10));
+    }
+
+    #[test]
+    fn test_specialize_source_line_mapping() {
+        let source = "#version 100\nuniform float A;\n";
+        let specialized = specialize_source(source, &[("X".to_string(), "1".to_string())]);
+        let included = resolve_includes(
+            &specialized,
+            Path::new("v.vs"),
+            &PreprocessOptions::default(),
+        )
+        .unwrap();
+        let line = specialized
+            .lines()
+            .position(|l| l.starts_with("uniform"))
+            .unwrap();
+        assert_eq!(included.locate(line + 1).map(|(_, l)| l), Some(2));
+    }
+
+    #[test]
+    fn test_include_ignores_working_directory() {
+        // 测试在 crate 目录下运行, 名称相同的 Cargo.toml 不能被当作着色器所在目录中的文件
+        let err = resolve_includes(
+            "#include \"Cargo.toml\"\n",
+            Path::new("Cargo.toml"),
+            &PreprocessOptions::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("找不到包含文件"), "{}", err);
+
+        let ctx = TestContext::new("test_include_ignores_working_directory");
+        fs::write(ctx.temp_dir.join("Cargo.toml"), "float x;\n").unwrap();
+        let options = PreprocessOptions {
+            include_paths: vec![ctx.temp_dir.clone()],
+            ..Default::default()
+        };
+        let included = resolve_includes(
+            "#include \"Cargo.toml\"\n",
+            Path::new("Cargo.toml"),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(included.code, "float x;\n");
+        ctx.cleanup();
+    }
+
+    #[test]
+    fn test_include_in_inactive_branch() {
+        let ctx = TestContext::new("test_include_in_inactive_branch");
+        fs::write(ctx.temp_dir.join("bloom.h"), "float bloom;\n").unwrap();
+        let mut options = PreprocessOptions {
+            include_paths: vec![ctx.temp_dir.clone()],
+            ..Default::default()
+        };
+        let resolve = |source: &str, options: &PreprocessOptions| {
+            resolve_includes(source, Path::new("test.ps"), options)
+        };
+
+        // 始终不会启用的分支中的 #include 不展开, 由 preprocess 去掉
+        let source = "#if 0 // 关闭\n#include \"missing.h\"\n#endif\n";
+        let included = resolve(source, &options).unwrap();
+        assert!(preprocess(&included.code, &options)
+            .unwrap()
+            .trim()
+            .is_empty());
+
+        // 预定义宏不会写入 KSH, 游戏中会启用 #else 分支, 其中的 #include 仍然需要展开
+        let source = "#ifdef BLOOM\n\
+            #include \"bloom.h\"\n\
+            #else\n\
+            #include \"missing.h\"\n\
+            #endif\n";
+        options.defines.push(("BLOOM".to_string(), "1".to_string()));
+        let err = resolve(source, &options).unwrap_err();
+        assert_eq!(err.downcast::<Diagnostic>().unwrap().line, 4);
+
+        // 代码中定义的宏在游戏中同样生效
+        let included = resolve(&format!("#define BLOOM\n{}", source), &options).unwrap();
+        assert!(included.code.contains("float bloom;"));
+
+        // 只在加上预定义宏时启用的分支也会展开
+        let source = "#ifdef BLOOM\n#include \"bloom.h\"\n#endif\n";
+        let included = resolve(source, &options).unwrap();
+        let output = preprocess(&included.code, &options).unwrap();
+        assert_eq!(
+            output.split_whitespace().collect::<Vec<_>>(),
+            ["float", "bloom;"]
+        );
+        ctx.cleanup();
+    }
+}
@@ -0,0 +1,243 @@
+use crate::core::analyze_ksh;
+use crate::glsl_parser::{collect_uniforms, parse_glsl};
+use crate::image::{read_png, write_png, RgbaImage};
+use crate::interpreter::{render, RenderInputs, Texture};
+use crate::ktex::read_tex;
+use crate::preprocessor::{resolve_includes, PreprocessOptions};
+use crate::types::{Variable, VariableType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 预览时 uniform 的值, 例如:
+///
+/// ```json
+/// {
+///     "uniforms": { "TINT": [1, 0.5, 0.5, 1], "LIGHTS": [[1, 0, 0], [0, 1, 0]] },
+///     "varyings": { "PS_POS": [0, 0, 0] },
+///     "textures": { "SAMPLER[1]": "colour_cube.png" }
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct PreviewSettings {
+    #[serde(default)]
+    pub uniforms: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub varyings: HashMap<String, serde_json::Value>,
+    /// 纹理路径 (.png 或 .tex), 相对于设置文件所在目录
+    #[serde(default)]
+    pub textures: HashMap<String, PathBuf>,
+}
+
+impl PreviewSettings {
+    /// 读取设置文件
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("读取文件 {} 失败: {}", path.display(), e))?;
+        let mut settings: PreviewSettings = serde_json::from_str(&content)
+            .map_err(|e| format!("解析 {} 失败: {}", path.display(), e))?;
+        if let Some(parent) = path.parent() {
+            for texture in settings.textures.values_mut() {
+                *texture = parent.join(&*texture);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// 转换为解释器的输入, 并读取纹理
+    pub fn into_inputs(self) -> Result<RenderInputs, Box<dyn std::error::Error>> {
+        let numbers = |values: HashMap<String, serde_json::Value>| {
+            values
+                .into_iter()
+                .map(|(name, value)| {
+                    let mut floats = vec![];
+                    flatten_numbers(&value, &mut floats)
+                        .map_err(|e| format!("{} 的值无效: {}", name, e))?;
+                    Ok((name, floats))
+                })
+                .collect::<Result<HashMap<_, _>, String>>()
+        };
+        let mut textures = HashMap::new();
+        for (name, path) in self.textures {
+            let image = read_texture(&path)?;
+            textures.insert(
+                name,
+                Texture::from_rgba8(image.width, image.height, &image.data),
+            );
+        }
+        Ok(RenderInputs {
+            uniforms: numbers(self.uniforms)?,
+            varyings: numbers(self.varyings)?,
+            textures,
+        })
+    }
+}
+
+/// 读取 PNG 或 KTEX 纹理 (.tex)
+pub fn read_texture(path: &Path) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("tex") => read_tex(path),
+        _ => read_png(path),
+    }
+}
+
+// 嵌套数组按顺序展开为数字, bool 转为 0 或 1
+fn flatten_numbers(value: &serde_json::Value, floats: &mut Vec<f32>) -> Result<(), String> {
+    match value {
+        serde_json::Value::Number(n) => floats.push(n.as_f64().unwrap_or_default() as f32),
+        serde_json::Value::Bool(b) => floats.push(if *b { 1.0 } else { 0.0 }),
+        serde_json::Value::Array(items) => {
+            for item in items {
+                flatten_numbers(item, floats)?;
+            }
+        }
+        other => return Err(format!("需要数字或数组, 实际为 {}", other)),
+    }
+    Ok(())
+}
+
+/// 采样器 uniform 可以绑定纹理的位置, 如 `SAMPLER[0]`, `SAMPLER[1]`, 不是数组时为 uniform 名称
+pub fn sampler_slots(uniforms: &[Variable]) -> Vec<String> {
+    uniforms
+        .iter()
+        .filter(|u| u.variable_type() == Some(VariableType::Sampler2D))
+        .flat_map(|u| match u.array_length {
+            Some(length) => (0..length).map(|i| format!("{}[{}]", u.name, i)).collect(),
+            None => vec![u.name.clone()],
+        })
+        .collect()
+}
+
+/// 像素着色器中使用的采样器可以绑定纹理的位置
+pub fn find_sampler_slots(
+    ps_path: &Path,
+    ps_content: &str,
+    options: &PreprocessOptions,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let ps = resolve_includes(ps_content, ps_path, options)?;
+    let shader = parse_glsl(&ps.code, options).map_err(|e| ps.map_error(e))?;
+    let uniforms = collect_uniforms(&shader).map_err(|e| ps.map_error(e))?;
+    Ok(sampler_slots(&uniforms))
+}
+
+/// 在输入图像上执行像素着色器, 输出与输入图像大小相同.
+///
+/// 输入图像绑定到第一个使用的采样器 (数组为第一个元素), 除非 `inputs` 中已经为它指定了纹理.
+pub fn render_preview(
+    ps_path: &Path,
+    ps_content: &str,
+    image: &RgbaImage,
+    mut inputs: RenderInputs,
+    options: &PreprocessOptions,
+) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+    let ps = resolve_includes(ps_content, ps_path, options)?;
+    let shader = parse_glsl(&ps.code, options).map_err(|e| ps.map_error(e))?;
+    let uniforms = collect_uniforms(&shader).map_err(|e| ps.map_error(e))?;
+
+    let slots = sampler_slots(&uniforms);
+    let first_sampler = slots.first().cloned();
+    for slot in slots.iter().skip(1) {
+        if !inputs.textures.contains_key(slot) {
+            log::warn!("采样器 {} 没有指定纹理, 采样结果为 0", slot);
+        }
+    }
+    if let Some(slot) = first_sampler {
+        inputs
+            .textures
+            .entry(slot)
+            .or_insert_with(|| Texture::from_rgba8(image.width, image.height, &image.data));
+    }
+
+    let data =
+        render(&shader, image.width, image.height, &inputs).map_err(|d| ps.map_diagnostic(d))?;
+    Ok(RgbaImage {
+        width: image.width,
+        height: image.height,
+        data,
+    })
+}
+
+/// 读取像素着色器 (.ps 或 .ksh 文件) 和输入图像 (.png 或 .tex), 渲染后写入 PNG 文件
+pub fn render_preview_file(
+    shader_path: &Path,
+    image_path: &Path,
+    settings_path: Option<&Path>,
+    out_path: &Path,
+    force: bool,
+    options: &PreprocessOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !force && out_path.exists() {
+        return Err(format!("输出文件已存在: {}", out_path.display()).into());
+    }
+    let mut options = options.clone();
+    let (ps_path, ps_content) = if shader_path.extension().and_then(|s| s.to_str()) == Some("ksh") {
+        let (_, _, ps_name, ps_content) = analyze_ksh(&fs::read(shader_path)?)?;
+        (PathBuf::from(ps_name), ps_content)
+    } else {
+        let content = fs::read_to_string(shader_path)
+            .map_err(|e| format!("读取文件 {} 失败: {}", shader_path.display(), e))?;
+        // 着色器所在目录也作为 #include 的搜索路径
+        if let Some(parent) = shader_path.parent() {
+            options.include_paths.insert(0, parent.to_path_buf());
+        }
+        (shader_path.to_path_buf(), content)
+    };
+    let inputs = match settings_path {
+        Some(path) => PreviewSettings::load(path)?.into_inputs()?,
+        None => RenderInputs::default(),
+    };
+    let image = read_texture(image_path)?;
+    let output = render_preview(&ps_path, &ps_content, &image, inputs, &options)?;
+    write_png(out_path, &output)?;
+    log::info!("已写入: {}", out_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Diagnostic;
+
+    #[test]
+    fn test_render_preview() {
+        let ps = "uniform sampler2D SAMPLER[2];\n\
+            uniform vec4 TINT;\n\
+            varying vec2 PS_TEXCOORD;\n\
+            vec3 scale(vec3 c, out float a) { a = 0.5; return c * 2.0; }\n\
+            void main() {\n\
+            vec4 c = texture2D(SAMPLER[0], PS_TEXCOORD);\n\
+            if (c.a < 0.1) discard;\n\
+            float a;\n\
+            c.rgb = scale(c.rgb, a) * TINT.rgb;\n\
+            for (int i = 0; i < 3; i++) { c.g += 0.1; }\n\
+            gl_FragColor = vec4(c.rgb, a);\n\
+            }\n";
+        // 左边为不透明的灰色, 右边全透明
+        let image = RgbaImage {
+            width: 2,
+            height: 1,
+            data: vec![100, 100, 100, 255, 0, 0, 0, 0],
+        };
+        let mut inputs = RenderInputs::default();
+        inputs
+            .uniforms
+            .insert("TINT".to_string(), vec![1.0, 0.5, 0.25, 1.0]);
+        let options = PreprocessOptions::default();
+        let output = render_preview(Path::new("test.ps"), ps, &image, inputs, &options).unwrap();
+        assert_eq!(output.data, [200, 177, 50, 128, 0, 0, 0, 0]);
+
+        // 运行时错误指向出错的行
+        let ps = "uniform vec4 TINT;\nvoid main() {\ngl_FragColor = TINT.xyz;\n}\n";
+        let err = render_preview(
+            Path::new("test.ps"),
+            ps,
+            &image,
+            RenderInputs::default(),
+            &options,
+        )
+        .unwrap_err();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.line, 3);
+    }
+}
@@ -0,0 +1,165 @@
+//! 着色器中的全局声明 (函数, uniform, attribute, varying, 常量, 结构体), 供语言服务器跳转和显示类型
+
+use crate::glsl_parser::ParsedShader;
+use glsl_lang::ast;
+use std::collections::HashSet;
+
+/// 声明的种类
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Uniform,
+    Attribute,
+    Varying,
+    Constant,
+    Variable,
+    Struct,
+}
+
+/// 一个全局声明
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// 声明的代码, 函数为函数原型, 如 `vec4 blend(vec4 a, float t)`
+    pub declaration: String,
+    /// 声明所在行, 属于预处理后的代码
+    pub line: usize,
+}
+
+// 按存储限定符区分声明的种类
+fn declaration_kind(qualifier: Option<&ast::TypeQualifier>) -> SymbolKind {
+    let Some(qualifier) = qualifier else {
+        return SymbolKind::Variable;
+    };
+    for q in &qualifier.qualifiers {
+        let ast::TypeQualifierSpecData::Storage(storage) = &q.content else {
+            continue;
+        };
+        match storage.content {
+            ast::StorageQualifierData::Uniform => return SymbolKind::Uniform,
+            ast::StorageQualifierData::Attribute => return SymbolKind::Attribute,
+            ast::StorageQualifierData::Varying
+            | ast::StorageQualifierData::In
+            | ast::StorageQualifierData::Out => return SymbolKind::Varying,
+            ast::StorageQualifierData::Const => return SymbolKind::Constant,
+            _ => {}
+        }
+    }
+    SymbolKind::Variable
+}
+
+// 合并多行声明中的空白, 便于显示
+fn normalize(code: &str) -> String {
+    code.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// 按源码顺序收集全局声明. 有定义的函数不再收集其前置声明
+pub fn collect_symbols(shader: &ParsedShader) -> Vec<Symbol> {
+    let defined: HashSet<&str> = shader
+        .declarations
+        .iter()
+        .filter_map(|decl| match &decl.content {
+            ast::ExternalDeclarationData::FunctionDefinition(def) => {
+                Some(def.prototype.name.content.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut symbols = vec![];
+    for decl in &shader.declarations {
+        match &decl.content {
+            ast::ExternalDeclarationData::FunctionDefinition(def) => symbols.push(Symbol {
+                name: def.prototype.name.content.as_str().to_string(),
+                kind: SymbolKind::Function,
+                declaration: normalize(shader.text_of(def.prototype.span)),
+                line: shader.line_of(def.span),
+            }),
+            ast::ExternalDeclarationData::Declaration(decl) => match &decl.content {
+                ast::DeclarationData::FunctionPrototype(prototype)
+                    if !defined.contains(prototype.name.content.as_str()) =>
+                {
+                    symbols.push(Symbol {
+                        name: prototype.name.content.as_str().to_string(),
+                        kind: SymbolKind::Function,
+                        declaration: normalize(shader.text_of(prototype.span)),
+                        line: shader.line_of(decl.span),
+                    })
+                }
+                ast::DeclarationData::InitDeclaratorList(decl_list) => {
+                    let head = &decl_list.content.head;
+                    let declaration = normalize(shader.text_of(decl.span));
+                    let line = shader.line_of(decl.span);
+                    if let ast::TypeSpecifierNonArrayData::Struct(s) = &head.ty.ty.ty.content {
+                        if let Some(name) = &s.name {
+                            symbols.push(Symbol {
+                                name: name.content.as_str().to_string(),
+                                kind: SymbolKind::Struct,
+                                declaration: declaration.clone(),
+                                line,
+                            });
+                        }
+                    }
+                    let kind = declaration_kind(head.ty.content.qualifier.as_ref());
+                    let names = head
+                        .name
+                        .iter()
+                        .chain(decl_list.content.tail.iter().map(|d| &d.ident.ident));
+                    for name in names {
+                        symbols.push(Symbol {
+                            name: name.content.as_str().to_string(),
+                            kind,
+                            declaration: declaration.clone(),
+                            line,
+                        });
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_parser::parse_glsl;
+    use crate::preprocessor::PreprocessOptions;
+
+    #[test]
+    fn test_collect_symbols() {
+        let content = "struct Light { vec3 pos; };\n\
+            uniform vec4 TINT, COLOUR;\n\
+            varying vec2 PS_TEXCOORD;\n\
+            const float SCALE = 2.0;\n\
+            vec4 blend(vec4 a,\n    float t);\n\
+            vec4 blend(vec4 a, float t) { return a * t; }\n\
+            void main() { gl_FragColor = blend(TINT, SCALE); }\n";
+        let shader = parse_glsl(content, &PreprocessOptions::default()).unwrap();
+        let symbols: Vec<_> = collect_symbols(&shader)
+            .into_iter()
+            .map(|s| (s.name, s.kind, s.line))
+            .collect();
+        let expected = [
+            ("Light", SymbolKind::Struct, 1),
+            ("TINT", SymbolKind::Uniform, 2),
+            ("COLOUR", SymbolKind::Uniform, 2),
+            ("PS_TEXCOORD", SymbolKind::Varying, 3),
+            ("SCALE", SymbolKind::Constant, 4),
+            ("blend", SymbolKind::Function, 7),
+            ("main", SymbolKind::Function, 8),
+        ];
+        assert_eq!(symbols.len(), expected.len(), "{:?}", symbols);
+        for (symbol, (name, kind, line)) in symbols.iter().zip(expected) {
+            assert_eq!((symbol.0.as_str(), symbol.1, symbol.2), (name, kind, line));
+        }
+        let blend = collect_symbols(&shader)
+            .into_iter()
+            .find(|s| s.name == "blend")
+            .unwrap();
+        assert_eq!(blend.declaration, "vec4 blend(vec4 a, float t)");
+    }
+}
@@ -1,6 +1,8 @@
 use clap::error::ErrorKind;
 use clap::Error;
-use glsl_lang::ast::TypeSpecifierNonArrayData;
+use glsl_lang::ast::{PrecisionQualifierData, TypeSpecifierNonArray, TypeSpecifierNonArrayData};
+use glsl_lang::transpiler::glsl::{show_type_specifier_non_array, FormattingState};
+use serde::Deserialize;
 
 #[derive(Debug)]
 pub enum VariableScope {
@@ -16,6 +18,16 @@ impl VariableScope {
     }
 }
 
+/// 着色器阶段, 图形界面中传入 `"vertex"` 或 `"fragment"`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShaderStage {
+    /// 顶点着色器 (.vs)
+    Vertex,
+    /// 像素着色器 (.ps)
+    Fragment,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum VariableType {
     Float,
@@ -109,6 +121,35 @@ impl From<&VariableType> for String {
     }
 }
 
+/// GLSL 中的类型写法, 如 `sampler2D`, `mat4`
+pub fn glsl_type_name(ty: &TypeSpecifierNonArrayData) -> String {
+    let mut name = String::new();
+    if let TypeSpecifierNonArrayData::Struct(s) = ty {
+        name.push_str("struct");
+        if let Some(struct_name) = &s.name {
+            name.push(' ');
+            name.push_str(struct_name.as_str());
+        }
+    } else {
+        let ty: TypeSpecifierNonArray = ty.clone().into();
+        // 写入 String 不会失败
+        let _ = show_type_specifier_non_array(&mut name, &ty, &mut FormattingState::default());
+    }
+    name
+}
+
+/// GLSL 中的声明写法, 数组长度写在名称之后, 如 `vec3 COLOURS[4]`
+pub fn glsl_declaration(
+    ty: &TypeSpecifierNonArrayData,
+    name: &str,
+    array_length: Option<u32>,
+) -> String {
+    match array_length {
+        Some(length) => format!("{} {}[{}]", glsl_type_name(ty), name, length),
+        None => format!("{} {}", glsl_type_name(ty), name),
+    }
+}
+
 #[derive(Debug)]
 pub struct Variable {
     pub name: String,
@@ -116,6 +157,16 @@ pub struct Variable {
     pub default_data: Vec<u32>,
     pub scope: VariableScope,
     pub array_length: Option<u32>,
+    /// 声明时显式指定的精度, 如 `uniform highp vec4 x;`
+    pub precision: Option<PrecisionQualifierData>,
+    /// 声明所在行, 仅在从着色器代码中解析时存在
+    pub line: Option<usize>,
+}
+
+impl Default for Variable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Variable {
@@ -126,6 +177,8 @@ impl Variable {
             default_data: vec![],
             scope: VariableScope::UNIFORM,
             array_length: None,
+            precision: None,
+            line: None,
         }
     }
 
@@ -162,6 +215,35 @@ impl Variable {
         }
     }
 
+    /// KSH 支持的变量类型, 不支持时返回 None
+    pub fn variable_type(&self) -> Option<VariableType> {
+        match self.r#type {
+            TypeSpecifierNonArrayData::Float => Some(VariableType::Float),
+            TypeSpecifierNonArrayData::Vec2 => Some(VariableType::Vec2),
+            TypeSpecifierNonArrayData::Vec3 => Some(VariableType::Vec3),
+            TypeSpecifierNonArrayData::Vec4 => Some(VariableType::Vec4),
+            TypeSpecifierNonArrayData::Mat4 => Some(VariableType::Mat4),
+            TypeSpecifierNonArrayData::Sampler2D => Some(VariableType::Sampler2D),
+            _ => None,
+        }
+    }
+
+    /// 变量的声明, 包括精度和数组长度, 如 `highp vec4 COLOURS[2]`
+    pub fn declaration(&self) -> String {
+        let mut description = match self.precision {
+            Some(PrecisionQualifierData::High) => "highp ".to_string(),
+            Some(PrecisionQualifierData::Medium) => "mediump ".to_string(),
+            Some(PrecisionQualifierData::Low) => "lowp ".to_string(),
+            None => String::new(),
+        };
+        description.push_str(&glsl_declaration(
+            &self.r#type,
+            &self.name,
+            self.array_length,
+        ));
+        description
+    }
+
     pub fn default_data_length(&self) -> usize {
         match self.r#type {
             TypeSpecifierNonArrayData::Float => 1,
@@ -174,3 +256,28 @@ impl Variable {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glsl_parser::parse_glsl_uniforms;
+    use crate::preprocessor::PreprocessOptions;
+
+    #[test]
+    fn test_glsl_type_name() {
+        use glsl_lang::ast::TypeSpecifierNonArrayData as T;
+
+        assert_eq!(glsl_type_name(&T::Sampler2D), "sampler2D");
+        assert_eq!(glsl_type_name(&T::SamplerCube), "samplerCube");
+        assert_eq!(glsl_type_name(&T::Mat4), "mat4");
+        assert_eq!(
+            glsl_declaration(&T::Vec3, "COLOURS", Some(4)),
+            "vec3 COLOURS[4]"
+        );
+
+        let content = "uniform highp sampler2D SAMPLER[2];\n\
+            void main() { gl_FragColor = texture2D(SAMPLER[0], vec2(0.0)); }\n";
+        let uniforms = parse_glsl_uniforms(content, &PreprocessOptions::default()).unwrap();
+        assert_eq!(uniforms[0].declaration(), "highp sampler2D SAMPLER[2]");
+    }
+}
@@ -19,13 +19,114 @@ export async function analyzeKsh(filePath) {
  * @param {string} params.vs_content - 顶点着色器内容
  * @param {string} params.ps_name - 像素着色器名称
  * @param {string} params.ps_content - 像素着色器内容
+ * @param {string[]} [params.defines] - 预定义宏, 格式为 NAME 或 NAME=VALUE
+ * @param {string[]} [params.include_paths] - #include 的搜索路径
+ * @param {boolean} [params.es100] - 是否按 GLSL ES 1.00 规范检查
+ * @param {boolean} [params.skip_validation] - 是否跳过 naga 语义验证
+ * @param {boolean} [params.lua] - 是否在 KSH 旁生成同名的 Lua 模块
+ * @param {Object<string, number[]>} [params.uniform_defaults] - 写入 KSH 的 uniform 默认值, 未指定的为 0
  * @returns {Promise<void>}
+ * @throws {{message: string, diagnostics: Array<{file: ?string, line: number, column: ?number, severity: 'error'|'warning', message: string}>}}
+ *   构建失败时的错误, diagnostics 中的行号和列号从 1 开始
  */
 export async function buildKsh(params) {
   console.log('buildKsh', params);
   return await invoke('build_ksh', { params });
 }
 
+/**
+ * 编辑时检查着色器, 返回所有诊断: 语法错误, KSH 不支持的 uniform 类型, 没有使用的 uniform,
+ * 提供另一个阶段的着色器时还包括 varying 和 uniform 不一致
+ * @param {Object} params - 检查参数
+ * @param {'vertex'|'fragment'} params.stage - 着色器阶段
+ * @param {string} params.name - 着色器名称
+ * @param {string} params.content - 着色器内容
+ * @param {string} [params.other_name] - 另一个阶段的着色器名称
+ * @param {string} [params.other_content] - 另一个阶段的着色器内容
+ * @param {string[]} [params.defines] - 预定义宏, 格式为 NAME 或 NAME=VALUE
+ * @param {string[]} [params.include_paths] - #include 的搜索路径
+ * @param {boolean} [params.es100] - 是否按 GLSL ES 1.00 规范检查
+ * @param {boolean} [params.skip_validation] - 是否跳过 naga 语义验证
+ * @returns {Promise<Array<{file: ?string, line: number, column: ?number, severity: 'error'|'warning', message: string}>>}
+ */
+export async function validateShader(params) {
+  return await invoke('validate_shader', { params });
+}
+
+/**
+ * 获取按当前着色器构建出的 KSH 中的 uniform 表, 顺序与写入 KSH 的顺序一致
+ * @param {Object} params - 参数
+ * @param {string} params.vs_name - 顶点着色器名称
+ * @param {string} params.vs_content - 顶点着色器内容
+ * @param {string} params.ps_name - 像素着色器名称
+ * @param {string} params.ps_content - 像素着色器内容
+ * @param {string[]} [params.defines] - 预定义宏, 格式为 NAME 或 NAME=VALUE
+ * @param {string[]} [params.include_paths] - #include 的搜索路径
+ * @returns {Promise<Array<{index: number, name: string, type: string, array_length: ?number, vertex: boolean, fragment: boolean, default_value: number[], engine: boolean}>>}
+ *   default_value 均为 0, 数组和采样器为空
+ * @throws {{message: string, diagnostics: Array}} 与 buildKsh 相同
+ */
+export async function getUniforms(params) {
+  return await invoke('get_uniforms', { params });
+}
+
+/**
+ * 在 CPU 上对输入图片的每个像素执行像素着色器
+ * @param {Object} params - 渲染参数
+ * @param {string} params.ps_name - 像素着色器名称
+ * @param {string} params.ps_content - 像素着色器内容
+ * @param {string} params.image_path - 输入图片 (.png 或 .tex) 路径, 绑定到第一个采样器
+ * @param {string} [params.settings_path] - uniform 设置文件 (JSON) 路径
+ * @param {Object<string, string>} [params.textures] - 采样器绑定的纹理路径, 键如 SAMPLER[1]
+ * @param {string[]} [params.include_paths] - #include 的搜索路径
+ * @returns {Promise<{width: number, height: number, data: number[]}>} RGBA8 像素, 第一行为图像顶部
+ * @throws {{message: string, diagnostics: Array}} 与 buildKsh 相同
+ */
+export async function renderPreview(params) {
+  return await invoke('render_preview', { params });
+}
+
+/**
+ * 获取像素着色器中可以绑定纹理的采样器
+ * @param {string} psName - 像素着色器名称
+ * @param {string} psContent - 像素着色器内容
+ * @param {string[]} includePaths - #include 的搜索路径
+ * @returns {Promise<string[]>} 如 ['SAMPLER[0]', 'SAMPLER[1]'], 第一个绑定输入图片
+ */
+export async function getSamplerSlots(psName, psContent, includePaths) {
+  return await invoke('get_sampler_slots', { psName, psContent, includePaths });
+}
+
+/**
+ * 把 PNG 图片转换为 KTEX 纹理 (.tex), 覆盖已存在的文件
+ * @param {Object} params - 转换参数
+ * @param {string} params.image_path - 输入的 PNG 图片路径
+ * @param {string} params.output_path - 输出的 .tex 文件路径
+ * @param {string} [params.format] - 像素格式: dxt1, dxt3, dxt5, rgba 或 rgb, 默认为 dxt5
+ * @param {boolean} [params.mipmaps] - 是否生成 mipmap, 默认生成
+ * @param {boolean} [params.premultiply] - 是否预乘 alpha, 默认预乘
+ * @returns {Promise<void>}
+ */
+export async function encodeTex({ format = 'dxt5', mipmaps = true, premultiply = true, ...params }) {
+  return await invoke('encode_tex', { params: { ...params, format, mipmaps, premultiply } });
+}
+
+/**
+ * 获取引擎会自动赋值的 uniform 目录
+ * @returns {Promise<Array<{name: string, type: string, array: boolean, declaration: string, description: string}>>}
+ */
+export async function getEngineUniforms() {
+  return await invoke('get_engine_uniforms');
+}
+
+/**
+ * 获取启动时要打开的 .ksh 文件, 由 `--gui <文件>` 或文件关联传入
+ * @returns {Promise<?string>} 文件的绝对路径, 没有时为 null
+ */
+export async function getStartupFile() {
+  return await invoke('get_startup_file');
+}
+
 /**
  * 打开文件对话框
  * @param {Object} options - 对话框选项